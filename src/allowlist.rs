@@ -0,0 +1,87 @@
+//! `BUCKLE_ALLOWED_VERSIONS`: restrict which buck2 releases buckle will run.
+//!
+//! The allowlist is either a comma separated list of tags or the path to a file of them, one
+//! per line or comma separated, with `#` starting a comment. Moving versions such as `latest`
+//! only ever resolve to an allowed release.
+
+use crate::{find_tag, session::is_moving, Release};
+use anyhow::{anyhow, Error};
+use std::{env, fs, path::Path};
+
+pub struct Allowlist {
+    tags: Vec<String>,
+    /// Where the allowlist came from, for errors.
+    source: String,
+}
+
+fn parse_tags(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The allowlist from `BUCKLE_ALLOWED_VERSIONS`, if one is set.
+pub fn get_allowlist() -> Result<Option<Allowlist>, Error> {
+    let Ok(value) = env::var("BUCKLE_ALLOWED_VERSIONS") else {
+        return Ok(None);
+    };
+    let path = Path::new(&value);
+    if path.is_file() {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            anyhow!(
+                "Could not read the BUCKLE_ALLOWED_VERSIONS file {}: {err}",
+                path.display()
+            )
+        })?;
+        return Ok(Some(Allowlist {
+            tags: parse_tags(&contents),
+            source: format!("the BUCKLE_ALLOWED_VERSIONS file {}", path.display()),
+        }));
+    }
+    Ok(Some(Allowlist {
+        tags: parse_tags(&value),
+        source: "BUCKLE_ALLOWED_VERSIONS".to_string(),
+    }))
+}
+
+impl Allowlist {
+    pub fn allows(&self, tag: &str) -> bool {
+        self.tags.iter().any(|allowed| allowed == tag)
+    }
+
+    /// Fail unless the release tagged `tag` may be run.
+    pub fn check(&self, tag: &str) -> Result<(), Error> {
+        if self.allows(tag) {
+            return Ok(());
+        }
+        let allowed = if self.tags.is_empty() {
+            "no versions".to_string()
+        } else {
+            self.tags.join(", ")
+        };
+        Err(anyhow!(
+            "buck2 {tag} is not allowed by {}, which permits {allowed}. \
+            Ask whoever manages that policy to allow it.",
+            self.source
+        ))
+    }
+
+    /// The releases `version` may resolve to, failing if it names a release that isn't
+    /// allowed rather than reporting it as missing.
+    pub fn restrict(&self, version: &str, releases: Vec<Release>) -> Result<Vec<Release>, Error> {
+        if !is_moving(version) {
+            if let Some(release) = find_tag(&releases, version) {
+                self.check(&release.tag_name)?;
+            }
+        }
+        Ok(releases
+            .into_iter()
+            .filter(|release| self.allows(&release.tag_name))
+            .collect())
+    }
+}
@@ -0,0 +1,218 @@
+//! HTTP requests, with basic auth for mirrors that require it.
+//!
+//! Every request goes through one client sending a `buckle/<version>` user-agent, prefixed with
+//! `BUCKLE_USER_AGENT` if set so proxies can tell buckle traffic apart.
+//!
+//! Credentials come from `BUCKLE_AUTH=user:pass`, which applies to the `BUCKLE_BASE_URL` host,
+//! or from a `machine` entry in the netrc file (`$NETRC`, otherwise `~/.netrc`). They are never
+//! sent to GitHub, which instead gets `BUCKLE_GITHUB_TOKEN` over https, for private repositories.
+
+use crate::{error::BuckleError, get_base_url};
+use anyhow::{anyhow, Error};
+use once_cell::sync::OnceCell;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use std::{env, fs, path::PathBuf};
+use url::Url;
+
+struct Credentials {
+    login: String,
+    password: String,
+}
+
+fn is_github(host: &str) -> bool {
+    ["github.com", "githubusercontent.com"]
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+}
+
+fn get_netrc_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("NETRC") {
+        return Some(PathBuf::from(path));
+    }
+    let (home, name) = match env::consts::OS {
+        "windows" => (env::var_os("USERPROFILE")?, "_netrc"),
+        _ => (env::var_os("HOME")?, ".netrc"),
+    };
+    Some(PathBuf::from(home).join(name))
+}
+
+/// The login and password for `host` in a netrc file. `default` entries are ignored so that
+/// credentials only go to hosts they were explicitly written down for.
+fn parse_netrc(contents: &str, host: &str) -> Option<Credentials> {
+    let mut tokens = contents.split_whitespace();
+    let mut matched = false;
+    let mut login = None;
+    let mut password = None;
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                if matched {
+                    break;
+                }
+                matched = token == "machine" && tokens.next() == Some(host);
+            }
+            "login" if matched => login = tokens.next(),
+            "password" if matched => password = tokens.next(),
+            "account" | "login" | "password" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+    Some(Credentials {
+        login: login?.to_string(),
+        password: password.unwrap_or_default().to_string(),
+    })
+}
+
+fn get_credentials(url: &Url) -> Result<Option<Credentials>, Error> {
+    let host = match url.host_str() {
+        Some(host) if !is_github(host) => host,
+        _ => return Ok(None),
+    };
+    if let Ok(auth) = env::var("BUCKLE_AUTH") {
+        let mirror = Url::parse(&get_base_url()?)?;
+        if mirror.host_str() == Some(host) {
+            let (login, password) = auth
+                .split_once(':')
+                .ok_or(anyhow!("BUCKLE_AUTH must be of the form user:pass"))?;
+            return Ok(Some(Credentials {
+                login: login.to_string(),
+                password: password.to_string(),
+            }));
+        }
+    }
+    match get_netrc_path() {
+        Some(path) if path.exists() => {
+            let contents = fs::read_to_string(&path)
+                .map_err(|err| anyhow!("Could not read {}: {err}", path.display()))?;
+            Ok(parse_netrc(&contents, host))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// `url` with any credentials embedded in it masked, for logging.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("********");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+fn user_agent() -> String {
+    let version = concat!("buckle/", env!("CARGO_PKG_VERSION"));
+    match env::var("BUCKLE_USER_AGENT") {
+        Ok(agent) if !agent.is_empty() => format!("{agent} {version}"),
+        _ => version.to_string(),
+    }
+}
+
+/// The client shared by every request buckle makes.
+pub fn client() -> Result<&'static Client, Error> {
+    static INSTANCE: OnceCell<Client> = OnceCell::new();
+    INSTANCE.get_or_try_init(|| Ok(Client::builder().user_agent(user_agent()).build()?))
+}
+
+/// The token from `BUCKLE_GITHUB_TOKEN`, if set.
+pub fn github_token() -> Option<String> {
+    env::var("BUCKLE_GITHUB_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+/// Whether `url` is GitHub over https, the only place the GitHub token may be sent.
+pub fn is_github_https(url: &str) -> bool {
+    match Url::parse(url) {
+        Ok(parsed) => {
+            parsed.scheme() == "https" && matches!(parsed.host_str(), Some(host) if is_github(host))
+        }
+        Err(_) => false,
+    }
+}
+
+/// A GET request for `url`, carrying the GitHub token if `url` is GitHub over https.
+pub fn github_request(url: &str) -> Result<RequestBuilder, Error> {
+    let request = client()?.get(url);
+    match github_token() {
+        Some(token) if is_github_https(url) => Ok(request.bearer_auth(token)),
+        _ => Ok(request),
+    }
+}
+
+/// A GET request for `url`, with basic auth if there are credentials for its host, or the
+/// GitHub token if it is GitHub.
+pub fn request(url: &str) -> Result<RequestBuilder, Error> {
+    let parsed =
+        Url::parse(url).map_err(|err| anyhow!("{} is not a valid URL: {err}", redact_url(url)))?;
+    let mut request = github_request(url)?;
+    if let Some(credentials) = get_credentials(&parsed)? {
+        request = request.basic_auth(credentials.login, Some(credentials.password));
+    }
+    Ok(request)
+}
+
+/// Send a [`request`] for `url`.
+pub fn get(url: &str) -> Result<Response, Error> {
+    send(request(url)?, url)
+}
+
+/// Send `request` for `url`, describing a failure to connect in terms of `url`.
+pub fn send(request: RequestBuilder, url: &str) -> Result<Response, Error> {
+    request
+        .send()
+        .map_err(|err| anyhow!("Could not fetch {}: {}", redact_url(url), err.without_url()))
+}
+
+/// Download a release asset from its GitHub API `url` with `token`. Unlike its
+/// `browser_download_url`, the API serves private repositories' assets to a token.
+pub fn get_asset(url: &str, token: &str) -> Result<Response, Error> {
+    if !is_github_https(url) {
+        return Err(anyhow!(
+            "Not sending the GitHub token to {}, which is not GitHub over https",
+            redact_url(url)
+        ));
+    }
+    let resp = client()?
+        .get(url)
+        .bearer_auth(token)
+        .header(reqwest::header::ACCEPT, "application/octet-stream")
+        .send()
+        .map_err(|err| anyhow!("Could not fetch {}: {}", redact_url(url), err.without_url()))?;
+    if !resp.status().is_success() {
+        let message = format!("Could not fetch {}: {}", redact_url(url), resp.status());
+        if is_rate_limited(&resp) {
+            return Err(BuckleError::RateLimited(message).into());
+        }
+        return Err(BuckleError::Network(message).into());
+    }
+    Ok(resp)
+}
+
+/// Whether GitHub turned `resp` away because of its rate limit.
+pub fn is_rate_limited(resp: &Response) -> bool {
+    let remaining = resp.headers().get("x-ratelimit-remaining");
+    resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || remaining.and_then(|value| value.to_str().ok()) == Some("0")
+}
+
+/// Like [`get`], but an unsuccessful status is an error.
+pub fn get_ok(url: &str) -> Result<Response, Error> {
+    ensure_ok(get(url)?, url)
+}
+
+/// `resp` for `url`, or a typed error if it is not a success.
+pub fn ensure_ok(resp: Response, url: &str) -> Result<Response, Error> {
+    if !resp.status().is_success() {
+        let message = format!("Could not fetch {}: {}", redact_url(url), resp.status());
+        if is_rate_limited(&resp) {
+            return Err(BuckleError::RateLimited(message).into());
+        }
+        return Err(BuckleError::Network(message).into());
+    }
+    Ok(resp)
+}
@@ -0,0 +1,36 @@
+//! `BUCKLE_PRELUDE_AUTOFIX=1`: move a mismatched cell submodule to the hash buck2 expects,
+//! doing what the suggested `git fetch && git checkout` would.
+
+use anyhow::{anyhow, Error};
+use git2::{build::CheckoutBuilder, Oid, Repository, StatusOptions};
+use std::path::Path;
+
+/// Check out `expected_hash` in the submodule at `submodule_path`, fetching from its remote
+/// first if the commit isn't there yet. A submodule with local changes is left alone.
+pub fn checkout_expected(submodule_path: &Path, expected_hash: &str) -> Result<(), Error> {
+    let repo = Repository::open(submodule_path)?;
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    if !repo.statuses(Some(&mut options))?.is_empty() {
+        return Err(anyhow!(
+            "{} has local changes, so it was left alone",
+            submodule_path.display()
+        ));
+    }
+
+    let oid = Oid::from_str(expected_hash)?;
+    if repo.find_commit(oid).is_err() {
+        let mut remote = repo.find_remote("origin")?;
+        remote.fetch(&[] as &[&str], None, None)?;
+        if repo.find_commit(oid).is_err() {
+            // Not on any branch the remote advertises, but it may still serve the commit.
+            remote.fetch(&[expected_hash], None, None)?;
+        }
+    }
+    let commit = repo
+        .find_commit(oid)
+        .map_err(|_| anyhow!("{expected_hash} could not be fetched from origin"))?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    repo.set_head_detached(oid)?;
+    Ok(())
+}
@@ -0,0 +1,336 @@
+//! Housekeeping for the versions installed in the buckle cache.
+//!
+//! Each version lives in `<buckle dir>/<commitish>/<triple>`, with its `buck2` hard linked to
+//! the content addressed store in `<buckle dir>/buck2/objects`. With `BUCKLE_COMPRESS_CACHE=1`
+//! the `buck2` of a version that has gone unused is kept as `buck2.zst` instead. A buckle about
+//! to run a version holds its `buck2` locked, shared, so that another can't compress it away
+//! in the meantime.
+
+use crate::{
+    debug_log, env_flag, error::BuckleError, get_cached_buck2_dir, install_binary, HashingWriter,
+};
+use anyhow::{anyhow, Error};
+use std::{
+    env,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tempfile::NamedTempFile;
+
+/// What a version's `buck2` is compressed into.
+pub const COMPRESSED_NAME: &str = "buck2.zst";
+
+/// How long a version goes unused before `BUCKLE_COMPRESS_CACHE` compresses it, so that moving
+/// between a few projects doesn't decompress on every run.
+const COMPRESS_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A version directory in the cache, holding a `buck2` or its compressed `buck2.zst`.
+pub struct InstalledVersion {
+    pub dir: PathBuf,
+    /// When the binary was last used, or modified where access times are unavailable.
+    pub last_used: SystemTime,
+    pub compressed: bool,
+}
+
+/// Whether `path` is a directory another buckle is still staging an install in.
+fn is_staging(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    name.starts_with('.') && name.contains(".tmp-")
+}
+
+/// Every installed version, least recently used first.
+pub fn installed_versions(buckle_dir: &Path) -> Result<Vec<InstalledVersion>, Error> {
+    let mut versions = vec![];
+    for commitish in fs::read_dir(buckle_dir)? {
+        let commitish = commitish?.path();
+        // `buck2` holds the object store rather than a version.
+        if !commitish.is_dir()
+            || commitish.file_name() == Some("buck2".as_ref())
+            || is_staging(&commitish)
+        {
+            continue;
+        }
+        for triple in fs::read_dir(&commitish)? {
+            let dir = triple?.path();
+            if is_staging(&dir) {
+                continue;
+            }
+            let (metadata, compressed) = match fs::metadata(dir.join("buck2")) {
+                Ok(metadata) => (metadata, false),
+                Err(_) => match fs::metadata(dir.join(COMPRESSED_NAME)) {
+                    Ok(metadata) => (metadata, true),
+                    Err(_) => continue,
+                },
+            };
+            let last_used = metadata.accessed().or_else(|_| metadata.modified())?;
+            versions.push(InstalledVersion {
+                dir,
+                last_used,
+                compressed,
+            });
+        }
+    }
+    versions.sort_by_key(|version| version.last_used);
+    Ok(versions)
+}
+
+/// Bytes taken up by the files in `dirs`, counting hard links to the same file once.
+fn disk_usage<'a>(dirs: impl Iterator<Item = &'a Path>) -> Result<u64, Error> {
+    #[cfg(unix)]
+    let mut seen = std::collections::HashSet::new();
+    let mut total = 0;
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            let metadata = entry?.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                if !seen.insert((metadata.dev(), metadata.ino())) {
+                    continue;
+                }
+            }
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Delete an installed version, and its object once nothing else links to it.
+pub fn remove_version(buckle_dir: &Path, dir: &Path) -> Result<(), Error> {
+    fs::remove_dir_all(dir)?;
+    if let Some(commitish) = dir.parent() {
+        // Only succeeds once no other triple is installed for the commitish.
+        let _ = fs::remove_dir(commitish);
+    }
+    prune_objects(buckle_dir)
+}
+
+/// Delete the objects no installed version links to any more.
+fn prune_objects(buckle_dir: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let objects = buckle_dir.join("buck2").join("objects");
+        if !objects.exists() {
+            return Ok(());
+        }
+        for object in fs::read_dir(objects)? {
+            let object = object?;
+            if object.metadata()?.nlink() == 1 {
+                fs::remove_file(object.path())?;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = buckle_dir;
+    Ok(())
+}
+
+fn get_cache_max_bytes() -> Option<u64> {
+    let max_bytes = env::var("BUCKLE_CACHE_MAX_BYTES").ok()?;
+    match max_bytes.trim().parse() {
+        Ok(max_bytes) => Some(max_bytes),
+        Err(_) => {
+            eprintln!("buckle: ignoring invalid BUCKLE_CACHE_MAX_BYTES '{max_bytes}'");
+            None
+        }
+    }
+}
+
+/// Evict the least recently used versions until the cache fits in `BUCKLE_CACHE_MAX_BYTES`.
+///
+/// `keep` are the versions that were just installed, which are never evicted even if they
+/// alone are over budget, and neither is the version the project resolves to.
+pub fn enforce_size_cap(buckle_dir: &Path, keep: &[&Path]) -> Result<(), Error> {
+    let Some(max_bytes) = get_cache_max_bytes() else {
+        return Ok(());
+    };
+    let project_dir = get_cached_buck2_dir().ok();
+    let mut keep = keep.to_vec();
+    keep.extend(project_dir.as_deref());
+    let mut versions = installed_versions(buckle_dir)?;
+    loop {
+        let total = disk_usage(versions.iter().map(|version| version.dir.as_path()))?;
+        if total <= max_bytes {
+            return Ok(());
+        }
+        let Some(index) = versions
+            .iter()
+            .position(|version| !keep.contains(&version.dir.as_path()))
+        else {
+            return Ok(());
+        };
+        let evicted = versions.remove(index);
+        eprintln!(
+            "buckle: cache is {total} bytes, over BUCKLE_CACHE_MAX_BYTES={max_bytes}, evicting {}",
+            evicted.dir.display()
+        );
+        remove_version(buckle_dir, &evicted.dir)?;
+    }
+}
+
+/// With `BUCKLE_COMPRESS_CACHE=1`, compress the binary of every version but `keep` that hasn't
+/// been used for a day. [`decompress`] restores it when it is next run.
+pub fn compress_idle(buckle_dir: &Path, keep: &Path) -> Result<(), Error> {
+    if !env_flag("BUCKLE_COMPRESS_CACHE") {
+        return Ok(());
+    }
+    let now = SystemTime::now();
+    let mut compressed_any = false;
+    for version in installed_versions(buckle_dir)? {
+        let idle = now.duration_since(version.last_used).unwrap_or_default();
+        if version.compressed || version.dir == keep || idle < COMPRESS_AFTER {
+            continue;
+        }
+        let buck2 = version.dir.join("buck2");
+        let binary = match File::open(&buck2) {
+            Ok(binary) => binary,
+            // Another buckle has just compressed it.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        #[cfg(unix)]
+        if !try_lock_exclusive(&binary)? || !is_same_file(&binary, &buck2)? {
+            debug_log(&format!(
+                "not compressing {}, it is in use",
+                buck2.display()
+            ));
+            continue;
+        }
+        let mut tmp = NamedTempFile::new_in(&version.dir)?;
+        zstd::stream::copy_encode(&binary, &mut tmp, 0)?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(version.dir.join(COMPRESSED_NAME))?;
+        fs::remove_file(&buck2)?;
+        debug_log(&format!("compressed the unused {}", buck2.display()));
+        compressed_any = true;
+    }
+    if compressed_any {
+        prune_objects(buckle_dir)?;
+    }
+    Ok(())
+}
+
+/// Lock `file` exclusively if nothing else holds it locked.
+#[cfg(unix)]
+fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: flock only locks the descriptor, which `file` keeps open.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+        io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// Lock `file` shared, waiting for an exclusive lock to be released.
+#[cfg(unix)]
+fn lock_shared(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    loop {
+        // SAFETY: flock only locks the descriptor, which `file` keeps open.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Whether `file` is still what `path` names, rather than a binary since removed.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let opened = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(named) => Ok(opened.dev() == named.dev() && opened.ino() == named.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Keep the `buck2` in `dir` from being compressed by [`compress_idle`] until the returned file
+/// is dropped, first restoring it if another buckle already has. Nothing is held if neither is
+/// there.
+pub fn hold_version(buckle_dir: &Path, dir: &Path) -> Result<Option<File>, Error> {
+    let buck2 = dir.join("buck2");
+    loop {
+        if !buck2.exists() && !dir.join(COMPRESSED_NAME).exists() {
+            return Ok(None);
+        }
+        decompress(buckle_dir, dir)?;
+        let binary = match File::open(&buck2) {
+            Ok(binary) => binary,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(anyhow!("Could not open {}: {err}", buck2.display())),
+        };
+        // It may have been compressed between opening and locking it.
+        #[cfg(unix)]
+        {
+            lock_shared(&binary)?;
+            if !is_same_file(&binary, &buck2)? {
+                continue;
+            }
+        }
+        return Ok(Some(binary));
+    }
+}
+
+/// Restore the `buck2` of a version in `dir` that [`compress_idle`] compressed, checking it
+/// against the SHA256 recorded when it was downloaded. A version that isn't compressed is left
+/// alone.
+pub fn decompress(buckle_dir: &Path, dir: &Path) -> Result<(), Error> {
+    let buck2 = dir.join("buck2");
+    let compressed = dir.join(COMPRESSED_NAME);
+    if buck2.exists() {
+        return Ok(());
+    }
+    let archive = match File::open(&compressed) {
+        Ok(archive) => archive,
+        // Another buckle has just decompressed it.
+        Err(err) if err.kind() == io::ErrorKind::NotFound && buck2.exists() => return Ok(()),
+        Err(err) => return Err(anyhow!("Could not open {}: {err}", compressed.display())),
+    };
+    debug_log(&format!("decompressing {}", compressed.display()));
+    let tmp = NamedTempFile::new_in(dir)?;
+    let mut writer = HashingWriter::new(&tmp);
+    zstd::stream::copy_decode(archive, &mut writer)
+        .map_err(|err| anyhow!("Could not decompress {}: {err}", compressed.display()))?;
+    let digest = writer.finish();
+    if let Ok(expected) = fs::read_to_string(dir.join("buck2.sha256")) {
+        if digest != expected.trim() {
+            return Err(BuckleError::CacheCorrupt(format!(
+                "The buckle cache is corrupted: {} decompresses to SHA256 {digest} but {} was \
+                downloaded. Suggested fix is to remove {} to download it again",
+                compressed.display(),
+                expected.trim(),
+                dir.display()
+            ))
+            .into());
+        }
+    }
+    tmp.as_file().sync_all()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o755))?;
+    }
+    install_binary(tmp, &digest, buckle_dir, &buck2)?;
+    match fs::remove_file(&compressed) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,185 @@
+//! `BUCKLE_PARALLEL_DOWNLOAD`: fetch a buck2 archive as several byte ranges at once, for links
+//! where one stream can't use the available bandwidth.
+
+use crate::{auth, content_type, debug_log};
+use anyhow::{anyhow, Error};
+use reqwest::{blocking::Response, header, StatusCode};
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    thread,
+    time::Instant,
+};
+use tempfile::NamedTempFile;
+
+/// The most ranges an archive is fetched in at once.
+const MAX_PARTS: usize = 16;
+/// The largest archive a server may claim to have. buck2 archives are tens of megabytes, so
+/// anything near this is a broken or hostile server rather than a real release.
+const MAX_ARCHIVE_BYTES: u64 = 4 << 30;
+
+/// A buck2 archive to be decoded, however it was fetched.
+pub struct Archive {
+    pub body: Box<dyn Read + Send>,
+    /// The length the server promised, to catch a truncated download.
+    pub len: Option<u64>,
+    pub content_type: Option<String>,
+    pub url: String,
+    /// How many ranges it was fetched in, 1 for a single stream.
+    pub parts: usize,
+    /// When the download began, for reporting its throughput.
+    pub started: Instant,
+}
+
+impl From<Response> for Archive {
+    fn from(resp: Response) -> Self {
+        Archive {
+            len: resp.content_length(),
+            content_type: content_type(&resp),
+            url: resp.url().to_string(),
+            parts: 1,
+            started: Instant::now(),
+            body: Box::new(resp),
+        }
+    }
+}
+
+/// How many ranges `BUCKLE_PARALLEL_DOWNLOAD` asks for, if more than one.
+fn get_parallelism() -> Option<usize> {
+    let parts = env::var("BUCKLE_PARALLEL_DOWNLOAD").ok()?;
+    match parts.trim().parse::<usize>() {
+        Ok(0 | 1) => None,
+        Ok(parsed) if parsed > MAX_PARTS => {
+            eprintln!("buckle: BUCKLE_PARALLEL_DOWNLOAD is capped at {MAX_PARTS} parts");
+            Some(MAX_PARTS)
+        }
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            eprintln!(
+                "buckle: ignoring invalid BUCKLE_PARALLEL_DOWNLOAD '{parts}', expected a number \
+                of parts up to {MAX_PARTS}"
+            );
+            None
+        }
+    }
+}
+
+/// Fetch the archive at `url`, in parallel ranges if `BUCKLE_PARALLEL_DOWNLOAD` asks for it and
+/// the server supports them. Anything else, including a range that fails, falls back to a
+/// single stream. The ranges are put back together in a temporary file in `tmpdir`, and the
+/// archive is checked afterwards exactly as a streamed one would be.
+pub fn fetch(url: &str, tmpdir: &Path) -> Result<Archive, Error> {
+    let Some(parts) = get_parallelism() else {
+        return Ok(auth::get_ok(url)?.into());
+    };
+    let started = Instant::now();
+    let probe = auth::send(auth::request(url)?.header(header::RANGE, "bytes=0-0"), url)?;
+    let total = match total_len(&probe) {
+        Some(total) => total,
+        // A server that ignores the range has sent the whole archive anyway.
+        None => {
+            debug_log(&format!(
+                "{} did not serve a range ({}), downloading it in one piece",
+                auth::redact_url(url),
+                probe.status()
+            ));
+            return Ok(auth::ensure_ok(probe, url)?.into());
+        }
+    };
+    if total > MAX_ARCHIVE_BYTES {
+        return Err(anyhow!(
+            "{} claims to be {total} bytes, more than any buck2 archive could be",
+            auth::redact_url(url)
+        ));
+    }
+    let content_type = content_type(&probe);
+    let final_url = probe.url().to_string();
+    drop(probe);
+
+    let parts = parts
+        .min(usize::try_from(total).unwrap_or(usize::MAX))
+        .max(1);
+    match fetch_ranges(url, total, parts, tmpdir) {
+        Ok(body) => Ok(Archive {
+            body: Box::new(body),
+            len: Some(total),
+            content_type,
+            url: final_url,
+            parts,
+            started,
+        }),
+        Err(err) => {
+            eprintln!(
+                "buckle: the parallel download of {} failed, downloading it in one piece: {err}",
+                auth::redact_url(url)
+            );
+            Ok(auth::get_ok(url)?.into())
+        }
+    }
+}
+
+/// The full length from the `Content-Range` of a 206 response.
+fn total_len(resp: &Response) -> Option<u64> {
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    let range = resp.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (_, total) = range.strip_prefix("bytes ")?.split_once('/')?;
+    total.parse().ok().filter(|&total| total > 0)
+}
+
+/// Fetch the `total` bytes at `url` as `parts` ranges, each on its own thread writing at its
+/// offset in a temporary file in `tmpdir`. Returns the file, rewound to its start.
+fn fetch_ranges(
+    url: &str,
+    total: u64,
+    parts: usize,
+    tmpdir: &Path,
+) -> Result<NamedTempFile, Error> {
+    let mut body = NamedTempFile::new_in(tmpdir)?;
+    body.as_file().set_len(total)?;
+    let (whole, rest) = (total / parts as u64, total % parts as u64);
+    let part_len = whole + u64::from(rest > 0);
+    // Rounding the parts up can leave fewer of them than asked for, never a part past the end.
+    let ranges = (0..total)
+        .step_by(usize::try_from(part_len)?)
+        .map(|start| Ok((start, part_len.min(total - start), body.reopen()?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    thread::scope(|scope| {
+        let fetches: Vec<_> = ranges
+            .into_iter()
+            .map(|(start, len, file)| scope.spawn(move || fetch_range(url, start, len, file)))
+            .collect();
+        fetches.into_iter().try_for_each(|fetch| {
+            fetch
+                .join()
+                .map_err(|_| anyhow!("a download thread panicked"))?
+        })
+    })?;
+    body.seek(SeekFrom::Start(0))?;
+    Ok(body)
+}
+
+/// Write the `len` bytes at `url` from `start` into `file` at the same offset.
+fn fetch_range(url: &str, start: u64, len: u64, mut file: File) -> Result<(), Error> {
+    let end = start + len - 1;
+    let request = auth::request(url)?.header(header::RANGE, format!("bytes={start}-{end}"));
+    let mut resp = auth::send(request, url)?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(anyhow!("bytes {start}-{end} came back {}", resp.status()));
+    }
+    file.seek(SeekFrom::Start(start))?;
+    let copied = io::copy(&mut (&mut resp).take(len), &mut file)
+        .map_err(|err| anyhow!("bytes {start}-{end} could not be read: {err}"))?;
+    if copied != len {
+        return Err(anyhow!("bytes {start}-{end} came back {copied} bytes long"));
+    }
+    if resp.read(&mut [0])? != 0 {
+        return Err(anyhow!(
+            "bytes {start}-{end} came back longer than asked for"
+        ));
+    }
+    Ok(())
+}
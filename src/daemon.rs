@@ -0,0 +1,80 @@
+//! `BUCKLE_KILL_STALE_DAEMON`: stop the buck2 daemon a project was last run with when the
+//! project moves to another buck2, so the old daemon can't linger and be mistaken for the new
+//! one.
+
+use crate::{
+    debug_log, env_flag, get_buck2_project_root, get_default_buck2_args, is_buckle_var, session,
+    write_file_atomically,
+};
+use anyhow::Error;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Where the buck2 last run for the current project is recorded, as its tag and path on two
+/// lines.
+fn get_last_run_path(buckle_dir: &Path) -> PathBuf {
+    buckle_dir.join("last-run").join(session::project_key())
+}
+
+/// With `BUCKLE_KILL_STALE_DAEMON=1`, run `buck2 kill` with the buck2 last run in this project
+/// if it was a different one from `buck2`, buck2 `tag`, which is about to run. Then record
+/// `buck2` as the last one run.
+pub fn kill_stale_daemon(buckle_dir: &Path, tag: &str, buck2: &Path) -> Result<(), Error> {
+    if !env_flag("BUCKLE_KILL_STALE_DAEMON") {
+        return Ok(());
+    }
+    let path = get_last_run_path(buckle_dir);
+    let current = format!("{tag}\n{}\n", buck2.display());
+    let previous = fs::read_to_string(&path).ok();
+    if previous.as_deref() == Some(current.as_str()) {
+        return Ok(());
+    }
+    let last_run = previous.as_deref().and_then(|previous| {
+        let mut lines = previous.lines();
+        Some((lines.next()?, PathBuf::from(lines.next()?)))
+    });
+    if let Some((last_tag, last_buck2)) = last_run {
+        kill_daemon(last_tag, &last_buck2, tag, &get_default_buck2_args()?);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_file_atomically(&path, current.as_bytes())?;
+    Ok(())
+}
+
+/// Stop the daemon of buck2 `last_tag` at `last_buck2`, passing the `default_args` buck2 is
+/// run with so that the kill reaches the same `--isolation-dir`. Failing to is only a warning,
+/// as the new buck2 runs either way.
+fn kill_daemon(last_tag: &str, last_buck2: &Path, tag: &str, default_args: &[String]) {
+    if !last_buck2.is_file() {
+        debug_log(&format!(
+            "not stopping the buck2 {last_tag} daemon, {} is gone",
+            last_buck2.display()
+        ));
+        return;
+    }
+    eprintln!("buckle: this project last ran buck2 {last_tag}, stopping its daemon for {tag}");
+    let mut command = Command::new(last_buck2);
+    if let Some(root) = get_buck2_project_root() {
+        command.current_dir(root);
+    }
+    let status = command
+        .args(default_args)
+        .arg("kill")
+        .env_clear()
+        .envs(env::vars_os().filter(|(key, _)| !is_buckle_var(key)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            eprintln!("buckle: could not stop the buck2 {last_tag} daemon: buck2 kill {status}")
+        }
+        Err(err) => eprintln!("buckle: could not stop the buck2 {last_tag} daemon: {err}"),
+    }
+}
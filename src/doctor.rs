@@ -0,0 +1,343 @@
+//! `buckle doctor`: check the setup for common problems without running buck2.
+
+use crate::{
+    auth, cache, check_cell, ensure_buckle_dir, error::BuckleError, get_cells,
+    get_expected_cell_hash, get_releases, get_releases_url, get_triple, get_version_dir,
+    is_offline, prelude_check_enabled, read_buck2_version, resolve_release,
+    verify_installed_binary, CellCheck,
+};
+use anyhow::{anyhow, Error};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    thread,
+};
+use tempfile::NamedTempFile;
+
+enum Status {
+    Ok,
+    Skip,
+    Warn,
+    /// A problem that stops buckle from working.
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+fn check_cache(buckle_dir: &Result<PathBuf, Error>) -> Check {
+    let writable = buckle_dir
+        .as_ref()
+        .map_err(|err| anyhow!("{err}"))
+        .and_then(|dir| {
+            NamedTempFile::new_in(dir)?;
+            Ok(dir)
+        });
+    match writable {
+        Ok(dir) => Check::new(
+            "cache",
+            Status::Ok,
+            format!("{} is writable", dir.display()),
+        ),
+        Err(err) => Check::new("cache", Status::Fail, err.to_string())
+            .hint("Set BUCKLE_CACHE to a writable directory"),
+    }
+}
+
+fn check_network() -> Check {
+    match is_offline() {
+        Ok(true) => return Check::new("network", Status::Skip, "buckle is offline"),
+        Ok(false) => {}
+        Err(err) => return Check::new("network", Status::Fail, err.to_string()),
+    }
+    let resp = get_releases_url().and_then(|url| Ok((auth::github_request(&url)?.send()?, url)));
+    match resp {
+        Ok((resp, url)) if resp.status().is_success() => {
+            Check::new("network", Status::Ok, format!("{url} is reachable"))
+        }
+        Ok((resp, url)) => {
+            if auth::is_rate_limited(&resp) {
+                Check::new("network", Status::Fail, format!("{url} is rate limited"))
+                    .hint("Wait for the GitHub rate limit to reset, or use a mirror")
+            } else {
+                Check::new(
+                    "network",
+                    Status::Fail,
+                    format!("{url} returned {}", resp.status()),
+                )
+            }
+        }
+        Err(err) => Check::new("network", Status::Fail, err.to_string())
+            .hint("Check your connection, or set BUCKLE_OFFLINE=1 to use only the cache"),
+    }
+}
+
+fn check_arch() -> Check {
+    match get_triple() {
+        Ok(triple) => Check::new("platform", Status::Ok, triple),
+        Err(err) => match err.downcast_ref::<BuckleError>() {
+            Some(BuckleError::UnsupportedPlatform(platform)) => Check::new(
+                "platform",
+                Status::Fail,
+                format!("{}/{} is not supported", platform.arch, platform.os),
+            )
+            .hint(err.to_string()),
+            _ => Check::new("platform", Status::Fail, err.to_string())
+                .hint("Set BUCKLE_TRIPLE to use the binary for another platform"),
+        },
+    }
+}
+
+/// Check the version resolves, returning where it is installed.
+fn check_version(buckle_dir: &Result<PathBuf, Error>) -> (Check, Option<PathBuf>) {
+    let resolved = buckle_dir
+        .as_ref()
+        .map_err(|err| anyhow!("{err}"))
+        .and_then(|dir| {
+            let version = read_buck2_version()?;
+            let releases = get_releases(dir)?;
+            let release = resolve_release(&version, &releases)?;
+            Ok((
+                version,
+                release.tag_name.clone(),
+                get_version_dir(dir, release)?,
+            ))
+        });
+    match resolved {
+        Ok((version, tag, dir)) if version == tag => {
+            (Check::new("version", Status::Ok, tag), Some(dir))
+        }
+        Ok((version, tag, dir)) => (
+            Check::new(
+                "version",
+                Status::Ok,
+                format!("{version} resolves to {tag}"),
+            ),
+            Some(dir),
+        ),
+        Err(err) => (
+            Check::new("version", Status::Fail, err.to_string())
+                .hint("Check the version in .buckversion or USE_BUCK2_VERSION"),
+            None,
+        ),
+    }
+}
+
+fn check_binary(version_dir: Option<&Path>) -> Check {
+    let Some(version_dir) = version_dir else {
+        return Check::new("binary", Status::Skip, "the version did not resolve");
+    };
+    let buck2 = version_dir.join("buck2");
+    let Ok(metadata) = buck2.metadata() else {
+        return Check::new(
+            "binary",
+            Status::Warn,
+            format!("{} is not downloaded yet", buck2.display()),
+        )
+        .hint("It will be downloaded on the next run");
+    };
+    #[cfg(unix)]
+    let is_exec = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let is_exec = metadata.is_file();
+    if is_exec {
+        Check::new("binary", Status::Ok, buck2.display().to_string())
+    } else {
+        Check::new(
+            "binary",
+            Status::Fail,
+            format!("{} is not executable", buck2.display()),
+        )
+        .hint(format!(
+            "Remove {} to download it again",
+            version_dir.display()
+        ))
+    }
+}
+
+fn check_prelude(version_dir: Option<&Path>) -> Check {
+    match prelude_check_enabled() {
+        Ok(true) => {}
+        Ok(false) => return Check::new("prelude", Status::Skip, "the prelude check is disabled"),
+        Err(err) => return Check::new("prelude", Status::Fail, err.to_string()),
+    }
+    let downloaded = version_dir.map(|dir| dir.join("prelude_hash").exists());
+    if downloaded != Some(true) {
+        return Check::new("prelude", Status::Skip, "buck2 is not downloaded yet");
+    }
+    let mut checked = vec![];
+    for (cell, path) in get_cells() {
+        let expected_hash = match get_expected_cell_hash(&cell) {
+            Some(Ok(expected_hash)) => expected_hash,
+            Some(Err(err)) => return Check::new("prelude", Status::Warn, err.to_string()),
+            None => continue,
+        };
+        match check_cell(&cell, &path, expected_hash) {
+            CellCheck::Matches => checked.push(cell),
+            CellCheck::Unchecked => {}
+            CellCheck::Skipped(reason) => return Check::new("prelude", Status::Warn, reason),
+            CellCheck::Mismatch {
+                absolute_path,
+                hash,
+            } => {
+                return Check::new(
+                    "prelude",
+                    Status::Warn,
+                    format!("{cell} is at {hash}, not the expected {expected_hash}"),
+                )
+                .hint(format!(
+                    "cd {} && git fetch && git checkout {expected_hash}",
+                    absolute_path.display()
+                ))
+            }
+        }
+    }
+    if checked.is_empty() {
+        Check::new("prelude", Status::Skip, "no prelude submodule to check")
+    } else {
+        Check::new(
+            "prelude",
+            Status::Ok,
+            format!("{} matches", checked.join(", ")),
+        )
+    }
+}
+
+/// Check one cached binary against the SHA256 recorded when it was downloaded.
+fn check_integrity(version_dir: &Path) -> Check {
+    let buck2 = version_dir.join("buck2");
+    if !buck2.with_extension("sha256").exists() {
+        return Check::new(
+            "integrity",
+            Status::Skip,
+            format!("{} has no recorded SHA256", buck2.display()),
+        );
+    }
+    match verify_installed_binary(&buck2) {
+        Ok(()) => Check::new(
+            "integrity",
+            Status::Ok,
+            format!("{} matches its SHA256", buck2.display()),
+        ),
+        Err(err) => Check::new("integrity", Status::Fail, err.to_string()).hint(format!(
+            "Remove {} to download it again",
+            version_dir.display()
+        )),
+    }
+}
+
+/// `--verify-cache`: re-hash every cached buck2, spread over a thread per CPU as each one is
+/// hundreds of megabytes. The checks come back in the order of their directories.
+fn check_cache_integrity(buckle_dir: &Result<PathBuf, Error>) -> Vec<Check> {
+    let versions = match buckle_dir
+        .as_ref()
+        .map(|dir| cache::installed_versions(dir))
+    {
+        Ok(Ok(versions)) => versions,
+        Ok(Err(err)) => return vec![Check::new("integrity", Status::Fail, err.to_string())],
+        Err(_) => {
+            return vec![Check::new(
+                "integrity",
+                Status::Skip,
+                "there is no cache to verify",
+            )]
+        }
+    };
+    let mut dirs: Vec<PathBuf> = versions.into_iter().map(|version| version.dir).collect();
+    dirs.sort();
+    if dirs.is_empty() {
+        return vec![Check::new(
+            "integrity",
+            Status::Skip,
+            "no versions are cached",
+        )];
+    }
+    let workers = thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1)
+        .min(dirs.len());
+    let mut checks: Vec<(usize, Check)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|worker| {
+                let dirs = &dirs;
+                scope.spawn(move || {
+                    (worker..dirs.len())
+                        .step_by(workers)
+                        .map(|index| (index, check_integrity(&dirs[index])))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("verifying the cache panicked"))
+            .collect()
+    });
+    checks.sort_by_key(|(index, _)| *index);
+    checks.into_iter().map(|(_, check)| check).collect()
+}
+
+pub fn doctor(args: &[OsString]) -> Result<(), Error> {
+    let verify_cache = match args {
+        [] => false,
+        [arg] if arg == "--verify-cache" => true,
+        _ => return Err(anyhow!("Usage: buckle doctor [--verify-cache]")),
+    };
+    let buckle_dir = ensure_buckle_dir();
+    let (version, version_dir) = check_version(&buckle_dir);
+    let mut checks = vec![
+        check_cache(&buckle_dir),
+        check_network(),
+        check_arch(),
+        version,
+        check_binary(version_dir.as_deref()),
+        check_prelude(version_dir.as_deref()),
+    ];
+    if verify_cache {
+        checks.extend(check_cache_integrity(&buckle_dir));
+    }
+
+    let mut failures = 0;
+    for check in &checks {
+        let label = match check.status {
+            Status::Ok => " ok ",
+            Status::Skip => "skip",
+            Status::Warn => "warn",
+            Status::Fail => {
+                failures += 1;
+                "FAIL"
+            }
+        };
+        println!("[{label}] {}: {}", check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            println!("       {hint}");
+        }
+    }
+    if failures > 0 {
+        return Err(anyhow!("{failures} of the checks failed"));
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! `buckle --buckle-env`: print the effective configuration for debugging.
+
+use crate::{
+    arch_fallback, get_buck2_project_root, get_buckle_dir, get_config_path, get_direct_dir,
+    get_releases_for, get_version_dir, is_installed, read_buck2_version,
+    session::peek_session_release,
+};
+use anyhow::{anyhow, Error};
+use std::env;
+
+/// Every environment variable buckle reads.
+const BUCKLE_VARS: &[&str] = &[
+    "BUCKLE_ALLOWED_VERSIONS",
+    "BUCKLE_ARCH_FALLBACK",
+    "BUCKLE_ASSERT_VERSION",
+    "BUCKLE_AUTH",
+    "BUCKLE_BASE_URL",
+    "BUCKLE_BINARY",
+    "BUCKLE_BUCK2_ARGS",
+    "BUCKLE_BUCK2_BIN",
+    "BUCKLE_CACHE",
+    "BUCKLE_CACHE_MAX_BYTES",
+    "BUCKLE_COMPRESS_CACHE",
+    "BUCKLE_CONFIG",
+    "BUCKLE_DEBUG",
+    "BUCKLE_DIRECT_DOWNLOAD",
+    "BUCKLE_DRY_RUN",
+    "BUCKLE_EXEC_WRAPPER",
+    "BUCKLE_EXPECTED_PRELUDE_HASH",
+    "BUCKLE_GITHUB_TOKEN",
+    "BUCKLE_KEEP_ENV",
+    "BUCKLE_KILL_STALE_DAEMON",
+    "BUCKLE_LOG_APPEND",
+    "BUCKLE_LOG_STDERR",
+    "BUCKLE_LOG_STDOUT",
+    "BUCKLE_MANIFEST_URL",
+    "BUCKLE_NO_PROGRESS",
+    "BUCKLE_NO_STALE_WARN",
+    "BUCKLE_OFFLINE",
+    "BUCKLE_PARALLEL_DOWNLOAD",
+    "BUCKLE_PASSTHROUGH",
+    "BUCKLE_POST_DOWNLOAD_HOOK",
+    "BUCKLE_POST_DOWNLOAD_STRICT",
+    "BUCKLE_PREFER_NATIVE_ARCH",
+    "BUCKLE_PRELUDE_AUTOFIX",
+    "BUCKLE_PRELUDE_CHECK",
+    "BUCKLE_PROJECT_CACHE",
+    "BUCKLE_QUIET",
+    "BUCKLE_RELEASES_MAX_BYTES",
+    "BUCKLE_RELEASES_PER_PAGE",
+    "BUCKLE_RELEASES_TTL_SECS",
+    "BUCKLE_RELEASES_URL",
+    "BUCKLE_REPO",
+    "BUCKLE_ROOT",
+    "BUCKLE_STALE_WARN_DAYS",
+    "BUCKLE_STRICT_CONFIG",
+    "BUCKLE_TIMING",
+    "BUCKLE_TMPDIR",
+    "BUCKLE_TRIPLE",
+    "BUCKLE_USER_AGENT",
+    "BUCKLE_VERIFY_KEY",
+    "BUCKLE_VERIFY_ON_RUN",
+    "BUCKLE_WRITE_LOCK",
+    "NETRC",
+    "USE_BUCK2_VERSION",
+];
+
+/// Credentials are shown as set but never printed.
+fn is_secret(name: &str) -> bool {
+    ["TOKEN", "AUTH", "PASSWORD"]
+        .iter()
+        .any(|secret| name.contains(secret))
+}
+
+fn describe_var(name: &str) -> String {
+    match env::var_os(name) {
+        Some(_) if is_secret(name) => format!("{name}=********"),
+        Some(value) => format!("{name}={}", value.to_string_lossy()),
+        None => format!("{name} (unset)"),
+    }
+}
+
+/// Print what buckle would use, without downloading or running buck2. Nothing is changed
+/// other than refreshing a stale releases list, so the dump can't alter what the next run
+/// resolves.
+///
+/// Anything that cannot be worked out is reported in place, so the rest is still shown.
+pub fn print_buckle_env() -> Result<(), Error> {
+    let buckle_dir = get_buckle_dir();
+    match &buckle_dir {
+        Ok(dir) if dir.is_dir() => println!("cache dir: {}", dir.display()),
+        Ok(dir) => println!("cache dir: {} (not created yet)", dir.display()),
+        Err(err) => println!("cache dir: unknown ({err})"),
+    }
+    match get_config_path() {
+        Some(path) if path.exists() => println!("config file: {}", path.display()),
+        Some(path) => println!("config file: {} (not found)", path.display()),
+        None => println!("config file: none"),
+    }
+    match get_buck2_project_root() {
+        Some(root) => println!("project root: {}", root.display()),
+        None => println!("project root: none"),
+    }
+
+    match read_buck2_version() {
+        Ok(version) => {
+            println!("version: {version}");
+            let release = buckle_dir.and_then(|dir| {
+                if let Some(direct_dir) = get_direct_dir(&dir, &version)? {
+                    return Ok((version.clone(), direct_dir));
+                }
+                if !dir.is_dir() {
+                    return Err(anyhow!("nothing is cached yet"));
+                }
+                let releases = get_releases_for(&dir, Some(&version))?;
+                let resolved = peek_session_release(&version, &releases, &dir)?;
+                // Show the release a fallback would substitute, as that is what runs.
+                let release = arch_fallback(&resolved, &releases, &dir)?;
+                Ok((release.tag_name.clone(), get_version_dir(&dir, release)?))
+            });
+            match release {
+                Ok((tag, dir)) => {
+                    let buck2 = dir.join("buck2");
+                    let installed = if is_installed(&dir) {
+                        "installed"
+                    } else {
+                        "not installed"
+                    };
+                    println!("tag: {tag}");
+                    match env::var_os("BUCKLE_BUCK2_BIN") {
+                        Some(buck2_bin) => {
+                            println!("buck2: {} (BUCKLE_BUCK2_BIN)", buck2_bin.to_string_lossy())
+                        }
+                        None => println!("buck2: {} ({installed})", buck2.display()),
+                    }
+                }
+                Err(err) => println!("tag: unknown ({err})"),
+            }
+        }
+        Err(err) => println!("version: unknown ({err})"),
+    }
+
+    println!("environment:");
+    for name in BUCKLE_VARS {
+        println!("  {}", describe_var(name));
+    }
+    // Unrecognised variables are usually typos, which is worth pointing out.
+    let mut unknown: Vec<String> = env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| name.starts_with("BUCKLE_") && !BUCKLE_VARS.contains(&name.as_str()))
+        .collect();
+    unknown.sort();
+    for name in unknown {
+        println!("  {} (not recognised)", describe_var(&name));
+    }
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Failures that scripts may want to tell apart, each with its own exit code.
+//!
+//! Anything else, including buckle's plain `anyhow` errors, exits with 1. buck2's own exit code
+//! is passed through unchanged once it runs.
+
+use crate::UnsupportedPlatform;
+use anyhow::Error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum BuckleError {
+    /// A download or the releases list could not be fetched.
+    Network(String),
+    /// GitHub refused a request until its rate limit resets.
+    RateLimited(String),
+    UnsupportedPlatform(UnsupportedPlatform),
+    /// The requested version is not a buck2 release, or not one in the cache.
+    VersionNotFound(String),
+    CacheCorrupt(String),
+    /// The prelude does not match, with `BUCKLE_PRELUDE_CHECK=ERROR`.
+    PreludeMismatch(String),
+    /// A config file, `.buckversion` or setting could not be used.
+    Config(String),
+}
+
+impl BuckleError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BuckleError::Network(_) => 20,
+            BuckleError::RateLimited(_) => 21,
+            BuckleError::UnsupportedPlatform(_) => 22,
+            BuckleError::VersionNotFound(_) => 23,
+            BuckleError::CacheCorrupt(_) => 24,
+            BuckleError::PreludeMismatch(_) => 25,
+            BuckleError::Config(_) => 26,
+        }
+    }
+}
+
+impl fmt::Display for BuckleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuckleError::UnsupportedPlatform(platform) => platform.fmt(f),
+            BuckleError::Network(message)
+            | BuckleError::RateLimited(message)
+            | BuckleError::VersionNotFound(message)
+            | BuckleError::CacheCorrupt(message)
+            | BuckleError::PreludeMismatch(message)
+            | BuckleError::Config(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for BuckleError {}
+
+/// The exit code for `err`: that of the first [`BuckleError`] in its chain, with any other
+/// failure to reach the network counting as a network error.
+pub fn exit_code(err: &Error) -> i32 {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<BuckleError>() {
+            return err.exit_code();
+        }
+        if cause.is::<reqwest::Error>() {
+            return BuckleError::Network(String::new()).exit_code();
+        }
+    }
+    1
+}
@@ -0,0 +1,57 @@
+//! Pass the file descriptors buckle was started with on to buck2, such as a pipe an
+//! integration hands it on FD 3.
+//!
+//! Only the standard streams are passed to a child explicitly. Anything else buckle inherited
+//! is recorded before buckle opens files of its own, and kept open across the spawn of buck2.
+
+#[cfg(unix)]
+use once_cell::sync::OnceCell;
+#[cfg(unix)]
+use std::{fs, os::unix::io::RawFd};
+
+/// The descriptors above stderr that were open when buckle started.
+#[cfg(unix)]
+static INHERITED: OnceCell<Vec<RawFd>> = OnceCell::new();
+
+/// The descriptors above stderr that are open now.
+#[cfg(unix)]
+fn open_fds() -> Vec<RawFd> {
+    let dir = if cfg!(target_os = "linux") {
+        "/proc/self/fd"
+    } else {
+        "/dev/fd"
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let listed: Vec<RawFd> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|&fd| fd > libc::STDERR_FILENO)
+        .collect();
+    // The listing itself was one of them, and is closed again by now.
+    listed
+        .into_iter()
+        // SAFETY: F_GETFD only reads the flags of `fd`, failing if it is not open.
+        .filter(|&fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1)
+        .collect()
+}
+
+/// Remember what buckle inherited. Call before opening anything else that stays open.
+pub fn record_inherited() {
+    #[cfg(unix)]
+    INHERITED.get_or_init(open_fds);
+}
+
+/// Clear close-on-exec on the inherited descriptors, so that buck2 gets them too.
+pub fn keep_inherited_open() {
+    #[cfg(unix)]
+    for &fd in INHERITED.get().into_iter().flatten() {
+        // SAFETY: fcntl on a descriptor that has since been closed just fails.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags != -1 && flags & libc::FD_CLOEXEC != 0 {
+            // SAFETY: F_SETFD only changes the close-on-exec flag of `fd`.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) };
+        }
+    }
+}
@@ -0,0 +1,3292 @@
+//! buckle, a launcher for buck2 that downloads and runs the version a project asks for.
+//!
+//! Besides the `buckle` binary, the crate offers [`list_releases`] for tools such as editor
+//! integrations that want the releases buckle knows about as data.
+//!
+//! ```no_run
+//! for release in buckle::list_releases()? {
+//!     println!("{}", release.tag_name);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, FixedOffset};
+use error::BuckleError;
+use ini::Ini;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use signature::{get_signature_verifier, SignatureVerifier};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+};
+use tempfile::NamedTempFile;
+use url::Url;
+use vcs::SubmoduleCheck;
+
+mod allowlist;
+mod auth;
+mod autofix;
+mod cache;
+mod chunked;
+mod daemon;
+mod doctor;
+mod env_dump;
+mod error;
+mod fds;
+mod lock;
+mod manifest;
+mod not_found;
+mod session;
+mod signature;
+mod tee;
+mod timing;
+mod upgrade;
+pub mod vcs;
+mod warm;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::time::SystemTime;
+
+const DEFAULT_REPO: &str = "facebook/buck2";
+const BUCK_RELEASE_URL: &str = "https://github.com/facebook/buck2/tags";
+
+/// Whether a boolean environment variable such as `BUCKLE_DRY_RUN=1` is switched on.
+fn env_flag(name: &str) -> bool {
+    env::var(name).map(|var| var == "1").unwrap_or(false)
+}
+
+/// Explain a decision that is normally silent, with `BUCKLE_DEBUG=1`.
+fn debug_log(message: &str) {
+    if env_flag("BUCKLE_DEBUG") {
+        eprintln!("buckle: debug: {message}");
+    }
+}
+
+/// Per-user preferences from `<config dir>/buckle/config.toml`.
+///
+/// Each key mirrors an environment variable, which takes precedence when set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// `BUCKLE_CACHE`
+    cache: Option<PathBuf>,
+    /// `BUCKLE_REPO`
+    repo: Option<String>,
+    /// `BUCKLE_BASE_URL`
+    base_url: Option<String>,
+    /// `BUCKLE_PRELUDE_CHECK`
+    prelude_check: Option<bool>,
+    /// `BUCKLE_OFFLINE`
+    offline: Option<bool>,
+    /// `BUCKLE_DIRECT_DOWNLOAD`
+    direct_download: Option<bool>,
+    /// `BUCKLE_PROJECT_CACHE`
+    project_cache: Option<bool>,
+}
+
+fn get_config_path() -> Option<PathBuf> {
+    // Paths are read with `var_os` throughout, as they need not be UTF-8.
+    if let Some(path) = env::var_os("BUCKLE_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let home = || env::var_os("HOME").map(PathBuf::from);
+    let mut dir = match env::consts::OS {
+        "linux" => env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".config"))),
+        "macos" => home().map(|home| home.join("Library").join("Application Support")),
+        "windows" => env::var_os("AppData").map(PathBuf::from),
+        _ => return None,
+    }?;
+    dir.push("buckle");
+    dir.push("config.toml");
+    Some(dir)
+}
+
+fn get_config() -> Result<&'static Config, Error> {
+    static INSTANCE: OnceCell<Config> = OnceCell::new();
+    INSTANCE.get_or_try_init(|| match get_config_path() {
+        Some(path) if path.exists() => {
+            let buf = fs::read_to_string(&path).map_err(|err| {
+                BuckleError::Config(format!("Could not read {}: {err}", path.display()))
+            })?;
+            toml::from_str(&buf).map_err(|err| {
+                BuckleError::Config(format!("Could not parse {}: {err}", path.display())).into()
+            })
+        }
+        _ => Ok(Config::default()),
+    })
+}
+
+/// The GitHub repository buck2 is released from, `owner/name`.
+fn get_repo() -> Result<String, Error> {
+    if let Ok(repo) = env::var("BUCKLE_REPO") {
+        return Ok(repo);
+    }
+    let config = get_config()?;
+    Ok(config
+        .repo
+        .clone()
+        .unwrap_or_else(|| DEFAULT_REPO.to_string()))
+}
+
+/// Where release assets are downloaded from, honoring a `BUCKLE_BASE_URL` mirror.
+fn get_base_url() -> Result<String, Error> {
+    let base_url = match env::var("BUCKLE_BASE_URL") {
+        Ok(base_url) => base_url,
+        Err(_) => match &get_config()?.base_url {
+            Some(base_url) => base_url.clone(),
+            None => format!("https://github.com/{}/releases/download", get_repo()?),
+        },
+    };
+    Ok(base_url.trim_end_matches('/').to_string())
+}
+
+/// Where the list of releases is fetched from, honoring a `BUCKLE_RELEASES_URL` mirror.
+fn get_releases_url() -> Result<String, Error> {
+    match env::var("BUCKLE_RELEASES_URL") {
+        Ok(releases_url) => Ok(releases_url),
+        Err(_) => Ok(format!(
+            "https://api.github.com/repos/{}/releases",
+            get_repo()?
+        )),
+    }
+}
+
+/// Whether buckle must only use what is already cached.
+fn is_offline() -> Result<bool, Error> {
+    if env::var("BUCKLE_OFFLINE").is_ok() {
+        return Ok(env_flag("BUCKLE_OFFLINE"));
+    }
+    Ok(get_config()?.offline.unwrap_or(false))
+}
+
+/// Whether exact tags are downloaded straight from the base URL, skipping the releases list.
+fn is_direct_download() -> Result<bool, Error> {
+    if env::var("BUCKLE_DIRECT_DOWNLOAD").is_ok() {
+        return Ok(env_flag("BUCKLE_DIRECT_DOWNLOAD"));
+    }
+    Ok(get_config()?.direct_download.unwrap_or(false))
+}
+
+/// Whether buck2 is cached in `.buckle` under the project root rather than the user cache.
+fn is_project_cache() -> Result<bool, Error> {
+    if env::var("BUCKLE_PROJECT_CACHE").is_ok() {
+        return Ok(env_flag("BUCKLE_PROJECT_CACHE"));
+    }
+    Ok(get_config()?.project_cache.unwrap_or(false))
+}
+
+fn get_buckle_dir() -> Result<PathBuf, Error> {
+    // A project cache keeps each checkout isolated, so it wins over a shared cache dir.
+    if is_project_cache()? {
+        if let Some(root) = get_buck2_project_root() {
+            return Ok(root.join(".buckle"));
+        }
+    }
+    let configured_cache = match env::var_os("BUCKLE_CACHE") {
+        Some(home) => Some(PathBuf::from(home)),
+        None => get_config()?.cache.clone(),
+    };
+    let mut dir = match configured_cache {
+        Some(home) => Ok(home),
+        None => match env::consts::OS {
+            "linux" => {
+                if let Some(base_dir) = env::var_os("XDG_CACHE_HOME") {
+                    Ok(PathBuf::from(base_dir))
+                } else if let Some(base_dir) = env::var_os("HOME") {
+                    let mut path = PathBuf::from(base_dir);
+                    path.push(".cache");
+                    Ok(path)
+                } else {
+                    Err(anyhow!("neither $XDG_CACHE_HOME nor $HOME are defined. Either define them or specify a $BUCKLE_CACHE"))
+                }
+            }
+            "macos" => {
+                // Plenty of macOS users follow the XDG conventions, so honour them first.
+                if let Some(base_dir) = env::var_os("XDG_CACHE_HOME") {
+                    Ok(PathBuf::from(base_dir))
+                } else {
+                    let mut base_dir = env::var_os("HOME")
+                        .map(PathBuf::from)
+                        .ok_or(anyhow!("$HOME is not defined"))?;
+                    base_dir.push("Library");
+                    base_dir.push("Caches");
+                    Ok(base_dir)
+                }
+            }
+            "windows" => Ok(env::var_os("LocalAppData")
+                .map(PathBuf::from)
+                .ok_or(anyhow!("%LocalAppData% is not defined"))?),
+            os => Err(anyhow!(
+                "'{os}' is currently an unsupported OS. Feel free to contribute a patch."
+            )),
+        },
+    }?;
+    dir.push("buckle");
+    Ok(dir)
+}
+
+/// A project root set explicitly with `--buckle-root` or `BUCKLE_ROOT`.
+static PROJECT_ROOT_OVERRIDE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Use `root` as the project root instead of searching for one.
+fn set_project_root_override(root: &Path) -> Result<(), Error> {
+    let root = fs::canonicalize(root)
+        .map_err(|err| anyhow!("The project root {} is not usable: {err}", root.display()))?;
+    if !root.join(".buckconfig").exists() && !root.join(".buckroot").exists() {
+        return Err(anyhow!(
+            "The project root {} contains neither a .buckconfig nor a .buckroot",
+            root.display()
+        ));
+    }
+    PROJECT_ROOT_OVERRIDE
+        .set(root)
+        .map_err(|_| anyhow!("The project root was already set"))
+}
+
+/// Find the furthest .buckconfig except if a .buckroot is found.
+fn get_buck2_project_root() -> Option<&'static Path> {
+    static INSTANCE: OnceCell<Option<PathBuf>> = OnceCell::new();
+    let path = INSTANCE.get_or_init(|| {
+        if let Some(root) = PROJECT_ROOT_OVERRIDE.get() {
+            return Some(root.clone());
+        }
+        let path = match env::current_dir() {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!(
+                    "buckle: could not determine the current directory ({err}), \
+                    continuing without a project root"
+                );
+                return None;
+            }
+        };
+        // Resolve symlinks so the walk, and later comparisons against the git workdir, all
+        // see the same real path.
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        let mut current_root = None;
+        for ancestor in path.ancestors() {
+            let mut br = ancestor.to_path_buf();
+            br.push(".buckroot");
+            if br.exists() {
+                // A buckroot means you should not check any higher in the file tree.
+                return Some(ancestor.to_path_buf());
+            }
+
+            let mut bc = ancestor.to_path_buf();
+            bc.push(".buckconfig");
+            if bc.exists() {
+                // This is the highest buckconfig we know about
+                current_root = Some(ancestor.to_path_buf());
+            }
+        }
+        current_root
+    });
+    path.as_deref()
+}
+
+/// A buck2 release, as the GitHub releases API describes it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Release {
+    pub url: Url,
+    pub html_url: Url,
+    pub assets_url: Url,
+    pub upload_url: String,
+    pub tarball_url: Option<Url>,
+    pub zipball_url: Option<Url>,
+    pub id: usize,
+    pub node_id: String,
+    pub tag_name: String,
+    pub target_commitish: String,
+    pub name: Option<String>,
+    pub body: Option<String>,
+    pub draft: bool,
+    pub prerelease: bool,
+    pub created_at: Option<String>,
+    pub published_at: Option<String>,
+    pub author: serde_json::Value,
+    pub assets: Vec<serde_json::Value>,
+}
+
+impl Release {
+    /// When the release was published. Drafts have no `published_at`, so fall back to when they
+    /// were created. `None` if neither is present or parses as RFC 3339.
+    pub fn released_at(&self) -> Option<DateTime<FixedOffset>> {
+        self.published_at
+            .as_deref()
+            .or(self.created_at.as_deref())
+            .and_then(parse_timestamp)
+    }
+}
+
+/// How long a cached releases.json is trusted before it is refetched.
+const DEFAULT_RELEASES_TTL_SECS: u64 = 60 * 60;
+
+/// How far in the future a cached releases.json may be dated before the clock is distrusted,
+/// allowing for small differences between machines sharing a cache.
+const CLOCK_SKEW_TOLERANCE_SECS: i64 = 60;
+
+/// The releases cache freshness window, overridable with `BUCKLE_RELEASES_TTL_SECS`.
+fn get_releases_ttl_secs() -> u64 {
+    match env::var("BUCKLE_RELEASES_TTL_SECS") {
+        Ok(ttl) => ttl.trim().parse().unwrap_or_else(|_| {
+            eprintln!(
+                "buckle: ignoring invalid BUCKLE_RELEASES_TTL_SECS '{ttl}', \
+                using {DEFAULT_RELEASES_TTL_SECS}"
+            );
+            DEFAULT_RELEASES_TTL_SECS
+        }),
+        Err(_) => DEFAULT_RELEASES_TTL_SECS,
+    }
+}
+
+/// The largest releases list buckle will read. GitHub's is well under a megabyte, so anything
+/// near this is a mirror or proxy serving something else.
+const DEFAULT_RELEASES_MAX_BYTES: u64 = 8 * 1024 * 1024;
+
+/// The releases list size limit, overridable with `BUCKLE_RELEASES_MAX_BYTES`.
+fn get_releases_max_bytes() -> u64 {
+    match env::var("BUCKLE_RELEASES_MAX_BYTES") {
+        Ok(max_bytes) => max_bytes.trim().parse().unwrap_or_else(|_| {
+            eprintln!(
+                "buckle: ignoring invalid BUCKLE_RELEASES_MAX_BYTES '{max_bytes}', \
+                using {DEFAULT_RELEASES_MAX_BYTES}"
+            );
+            DEFAULT_RELEASES_MAX_BYTES
+        }),
+        Err(_) => DEFAULT_RELEASES_MAX_BYTES,
+    }
+}
+
+/// Read a releases list response, refusing one that is implausibly large or plainly isn't
+/// JSON, such as a proxy's login page. Returns the releases along with the body to cache.
+fn read_releases_response(
+    resp: reqwest::blocking::Response,
+    url: &str,
+) -> Result<(Vec<Release>, String), Error> {
+    let url = auth::redact_url(url);
+    let max_bytes = get_releases_max_bytes();
+    let too_large = || {
+        anyhow!(
+            "The releases list from {url} is larger than {} (BUCKLE_RELEASES_MAX_BYTES), \
+            check that BUCKLE_RELEASES_URL points at a releases list",
+            human_bytes(max_bytes)
+        )
+    };
+    if matches!(resp.content_length(), Some(len) if len > max_bytes) {
+        return Err(too_large());
+    }
+    let content_type = content_type(&resp);
+    let mut body = Vec::new();
+    resp.take(max_bytes + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > max_bytes {
+        return Err(too_large());
+    }
+    let text = String::from_utf8_lossy(&body).into_owned();
+    let is_html = matches!(&content_type, Some(content_type) if content_type.contains("html"));
+    if is_html || !text.trim_start().starts_with('[') {
+        return Err(anyhow!(
+            "The releases list from {url} is not JSON ({}), check that BUCKLE_RELEASES_URL \
+            points at a releases list rather than a web page",
+            content_type.as_deref().unwrap_or("no content type")
+        ));
+    }
+    let releases = serde_json::from_str(&text)
+        .map_err(|err| anyhow!("The releases list from {url} could not be parsed: {err}"))?;
+    Ok((releases, text))
+}
+
+/// GitHub's largest page of releases.
+const MAX_RELEASES_PER_PAGE: u32 = 100;
+
+/// The page size to ask for with `BUCKLE_RELEASES_PER_PAGE`. Without it the server's default
+/// applies, which is 30 for GitHub.
+fn get_releases_per_page() -> Option<u32> {
+    let per_page = env::var("BUCKLE_RELEASES_PER_PAGE").ok()?;
+    match per_page.trim().parse() {
+        Ok(parsed @ 1..=MAX_RELEASES_PER_PAGE) => Some(parsed),
+        _ => {
+            eprintln!(
+                "buckle: ignoring invalid BUCKLE_RELEASES_PER_PAGE '{per_page}', expected 1 to \
+                {MAX_RELEASES_PER_PAGE}"
+            );
+            None
+        }
+    }
+}
+
+/// How many pages of releases an exact tag is looked for in before giving up.
+const MAX_RELEASE_PAGES: usize = 20;
+
+/// The URL of the newest page of releases.
+fn get_first_releases_url() -> Result<String, Error> {
+    let releases_url = get_releases_url()?;
+    let Some(per_page) = get_releases_per_page() else {
+        return Ok(releases_url);
+    };
+    let mut url = Url::parse(&releases_url)?;
+    url.query_pairs_mut()
+        .append_pair("per_page", &per_page.to_string());
+    Ok(url.to_string())
+}
+
+/// The next page of a paged response, from the `rel="next"` entry of its `Link` header.
+fn next_page_url(resp: &reqwest::blocking::Response) -> Option<Url> {
+    let link = resp.headers().get(reqwest::header::LINK)?.to_str().ok()?;
+    link.split(',').find_map(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let target = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+        if !parts.any(|param| param == "rel=\"next\"") {
+            return None;
+        }
+        resp.url().join(target).ok()
+    })
+}
+
+/// Follow `next` back through older pages of releases until one has `tag`.
+fn fetch_older_releases(mut next: Option<Url>, tag: &str) -> Result<Vec<Release>, Error> {
+    let mut older = vec![];
+    for _ in 1..MAX_RELEASE_PAGES {
+        let Some(url) = next else {
+            break;
+        };
+        debug_log(&format!(
+            "looking for {tag} in {}",
+            auth::redact_url(url.as_str())
+        ));
+        let resp = auth::github_request(url.as_str())?.send()?;
+        if !resp.status().is_success() {
+            return Err(BuckleError::Network(format!(
+                "Could not fetch older releases from {}: {}",
+                auth::redact_url(url.as_str()),
+                resp.status()
+            ))
+            .into());
+        }
+        next = next_page_url(&resp);
+        let (page, _) = read_releases_response(resp, url.as_str())?;
+        let found = find_tag(&page, tag).is_some();
+        older.extend(page);
+        if found {
+            break;
+        }
+    }
+    Ok(older)
+}
+
+fn content_type(resp: &reqwest::blocking::Response) -> Option<String> {
+    resp.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// The releases list cached at `releases_json_path`.
+fn read_releases_json(releases_json_path: &Path) -> Result<Vec<Release>, Error> {
+    let buf = fs::read_to_string(releases_json_path)
+        .map_err(|err| anyhow!("Could not read {}: {err}", releases_json_path.display()))?;
+    serde_json::from_str(&buf).map_err(|err| {
+        anyhow!(
+            "{} could not be parsed: {err}",
+            releases_json_path.display()
+        )
+    })
+}
+
+fn get_releases(path: &Path) -> Result<Vec<Release>, Error> {
+    get_releases_for(path, None)
+}
+
+/// The buck2 releases, newest page first, as `buckle` itself sees them. The same settings
+/// apply: the list comes from the cache while it is fresh, from `BUCKLE_RELEASES_URL` or
+/// GitHub otherwise, and never from the network when buckle is offline.
+pub fn list_releases() -> Result<Vec<Release>, Error> {
+    get_config()?;
+    get_releases(&ensure_buckle_dir()?)
+}
+
+/// The releases list, from the cache while it is fresh. Only the newest page is fetched
+/// unless `version` is an exact tag that isn't on it, when older pages are searched too and
+/// cached along with it.
+fn get_releases_for(path: &Path, version: Option<&str>) -> Result<Vec<Release>, Error> {
+    let wanted = version.filter(|version| !session::is_moving(version));
+    let mut releases_json_path = path.to_path_buf();
+    releases_json_path.push("releases.json");
+
+    if is_offline()? {
+        if !releases_json_path.exists() {
+            return Err(anyhow!(
+                "buckle is offline and there is no cached {}",
+                releases_json_path.display()
+            ));
+        }
+        return read_releases_json(&releases_json_path);
+    }
+
+    // TODO support last last_modification_time for windows users
+    #[cfg(unix)]
+    if releases_json_path.exists() {
+        use std::os::unix::fs::MetadataExt;
+        let meta = fs::metadata(&releases_json_path)?;
+        let last_modification_time = meta.mtime();
+        let curr_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let age = curr_time - last_modification_time;
+        // A list from the future can't be dated, and trusting it could serve it for ever.
+        if age < -CLOCK_SKEW_TOLERANCE_SECS {
+            eprintln!(
+                "buckle: {} was modified {}s in the future, check the system clock. \
+                Fetching the releases list again",
+                releases_json_path.display(),
+                -age
+            );
+        } else if age < i64::try_from(get_releases_ttl_secs()).unwrap_or(i64::MAX) {
+            // A corrupt cache, such as from a disk error, is replaced by fetching it again.
+            let releases = match read_releases_json(&releases_json_path) {
+                Ok(releases) => releases,
+                Err(err) => {
+                    debug_log(&format!("{err}, fetching the releases list again"));
+                    return fetch_releases(&releases_json_path, false, wanted);
+                }
+            };
+            let Some(tag) = wanted.filter(|tag| find_tag(&releases, tag).is_none()) else {
+                return Ok(releases);
+            };
+            // The cached list is still fresh, so it stands if older pages can't be had.
+            return match fetch_releases(&releases_json_path, true, wanted) {
+                Ok(fetched) => Ok(fetched),
+                Err(err) => {
+                    debug_log(&format!(
+                        "could not look for {tag} in older releases: {err}"
+                    ));
+                    Ok(releases)
+                }
+            };
+        }
+    }
+
+    fetch_releases(&releases_json_path, true, wanted)
+}
+
+/// Fetch the releases list and cache it at `releases_json_path`, paging back for `wanted` if
+/// it isn't among the newest. If the fetch fails and `fall_back` is set, a cached list is used
+/// instead, however old.
+fn fetch_releases(
+    releases_json_path: &Path,
+    fall_back: bool,
+    wanted: Option<&str>,
+) -> Result<Vec<Release>, Error> {
+    let releases_url = get_first_releases_url()?;
+    // Ask whether the cached list has changed rather than fetching it whole. GitHub answers an
+    // unchanged list with an empty 304, which doesn't count against its rate limit. A cached
+    // list that lacks the wanted tag needs the older pages, so it is fetched in full.
+    let revalidated = read_validators(releases_json_path, &releases_url)
+        .zip(read_releases_json(releases_json_path).ok())
+        .filter(|(_, cached)| !matches!(wanted, Some(tag) if find_tag(cached, tag).is_none()));
+    let mut request = auth::github_request(&releases_url)?;
+    if let Some((validators, _)) = &revalidated {
+        for (name, value) in validators {
+            request = request.header(name, value);
+        }
+    }
+    let releases = request.send()?;
+
+    let not_modified = releases.status() == reqwest::StatusCode::NOT_MODIFIED;
+    if let Some((_, cached)) = revalidated.filter(|_| not_modified) {
+        debug_log("the releases list has not changed since it was cached");
+        if !env_flag("BUCKLE_DRY_RUN") {
+            // Rewrite it, so that it counts as fresh for another TTL.
+            let text = fs::read(releases_json_path)?;
+            write_file_atomically(releases_json_path, &text)
+                .map_err(|err| cache_write_error(releases_json_path, err))?;
+        }
+        Ok(cached)
+    } else if releases.status().is_success() {
+        let headers = releases.headers().clone();
+        let next = next_page_url(&releases);
+        // Only a list that parsed is cached, so a bad response is not trusted on later runs.
+        let (mut parsed, mut text) = read_releases_response(releases, &releases_url)?;
+        if let Some(tag) = wanted.filter(|tag| find_tag(&parsed, tag).is_none()) {
+            let older = fetch_older_releases(next, tag)?;
+            if !older.is_empty() {
+                parsed.extend(older);
+                text = serde_json::to_string(&parsed)?;
+            }
+        }
+        if !env_flag("BUCKLE_DRY_RUN") {
+            write_file_atomically(releases_json_path, text.as_bytes())
+                .map_err(|err| cache_write_error(releases_json_path, err))?;
+            write_validators(releases_json_path, &releases_url, &headers)?;
+        }
+        Ok(parsed)
+    } else if let Some(cached) = fall_back
+        .then(|| read_releases_json(releases_json_path).ok())
+        .flatten()
+    {
+        // maybe out of date, but not that bad. A cached list that doesn't parse is no fallback.
+        Ok(cached)
+    } else if auth::is_rate_limited(&releases) {
+        Err(BuckleError::RateLimited(format!(
+            "{} is rate limited and there is no cached releases list. Wait for the rate limit \
+            to reset, set BUCKLE_AUTH, or use a mirror",
+            auth::redact_url(&releases_url)
+        ))
+        .into())
+    } else {
+        Err(BuckleError::Network(format!(
+            "Could not fetch the releases list from {}: {}",
+            auth::redact_url(&releases_url),
+            releases.status()
+        ))
+        .into())
+    }
+}
+
+/// Where the `ETag` and `Last-Modified` the cached releases list was served with are kept, as
+/// `name: value` lines along with the URL they are for.
+fn validators_path(releases_json_path: &Path) -> PathBuf {
+    releases_json_path.with_file_name("releases.validators")
+}
+
+/// The conditional request headers for refetching `releases_url`, if the cached list was
+/// fetched from it with a validator.
+fn read_validators(
+    releases_json_path: &Path,
+    releases_url: &str,
+) -> Option<Vec<(reqwest::header::HeaderName, String)>> {
+    let contents = fs::read_to_string(validators_path(releases_json_path)).ok()?;
+    let fields: Vec<(&str, &str)> = contents
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .collect();
+    if !fields.contains(&("url", releases_url)) {
+        return None;
+    }
+    let headers: Vec<_> = fields
+        .into_iter()
+        .filter_map(|(name, value)| match name {
+            "etag" => Some((reqwest::header::IF_NONE_MATCH, value.to_string())),
+            "last-modified" => Some((reqwest::header::IF_MODIFIED_SINCE, value.to_string())),
+            _ => None,
+        })
+        .collect();
+    (!headers.is_empty()).then_some(headers)
+}
+
+/// Remember the validators in the `headers` of the releases list from `releases_url`, or forget
+/// the old ones if there are none.
+fn write_validators(
+    releases_json_path: &Path,
+    releases_url: &str,
+    headers: &reqwest::header::HeaderMap,
+) -> Result<(), Error> {
+    let path = validators_path(releases_json_path);
+    let mut contents = format!("url: {releases_url}\n");
+    let mut any = false;
+    for (name, header) in [
+        ("etag", reqwest::header::ETAG),
+        ("last-modified", reqwest::header::LAST_MODIFIED),
+    ] {
+        if let Some(value) = headers.get(header).and_then(|value| value.to_str().ok()) {
+            contents.push_str(&format!("{name}: {value}\n"));
+            any = true;
+        }
+    }
+    let written = if any {
+        write_file_atomically(&path, contents.as_bytes())
+    } else {
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            removed => removed,
+        }
+    };
+    written.map_err(|err| cache_write_error(&path, err))
+}
+
+/// The buck2 target triple for each `(arch, os)` buckle knows how to request.
+const TARGETS: &[(&str, &str, &str)] = &[
+    ("x86_64", "linux", "x86_64-unknown-linux-musl"),
+    ("x86_64", "macos", "x86_64-apple-darwin"),
+    ("x86_64", "windows", "x86_64-pc-windows-msvc"),
+    ("aarch64", "linux", "aarch64-unknown-linux-gnu"),
+    ("aarch64", "macos", "aarch64-apple-darwin"),
+];
+
+/// The host is a platform buckle doesn't know a buck2 binary for.
+#[derive(Debug)]
+pub struct UnsupportedPlatform {
+    pub arch: &'static str,
+    pub os: &'static str,
+}
+
+impl fmt::Display for UnsupportedPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let known: Vec<String> = TARGETS
+            .iter()
+            .map(|(arch, os, _)| format!("{arch}/{os}"))
+            .collect();
+        write!(
+            f,
+            "buckle does not know of a buck2 binary for {}/{}. It can fetch buck2 for {}. \
+            Check {BUCK_RELEASE_URL} for the platforms buck2 publishes, then set BUCKLE_TRIPLE \
+            to use one of them or BUCKLE_BUCK2_BIN to use a local buck2.",
+            self.arch,
+            self.os,
+            known.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedPlatform {}
+
+fn get_arch() -> Result<&'static str, UnsupportedPlatform> {
+    let (mut arch, os) = (env::consts::ARCH, env::consts::OS);
+    if arch == "x86_64" && is_translated() && env_flag("BUCKLE_PREFER_NATIVE_ARCH") {
+        arch = "aarch64";
+    }
+    TARGETS
+        .iter()
+        .find(|(known_arch, known_os, _)| *known_arch == arch && *known_os == os)
+        .map(|(_, _, triple)| *triple)
+        .ok_or(UnsupportedPlatform { arch, os })
+}
+
+/// Whether this is an x86_64 buckle being translated by Rosetta on an Apple Silicon Mac, where
+/// it would pick the x86_64 buck2 and run it translated too. Unless `BUCKLE_PREFER_NATIVE_ARCH=1`
+/// picks the native buck2 instead, this warns, once.
+fn is_translated() -> bool {
+    static INSTANCE: OnceCell<bool> = OnceCell::new();
+    *INSTANCE.get_or_init(|| {
+        if env::consts::OS != "macos" {
+            return false;
+        }
+        let translated = matches!(
+            Command::new("sysctl").args(["-n", "sysctl.proc_translated"]).output(),
+            Ok(output) if String::from_utf8_lossy(&output.stdout).trim() == "1"
+        );
+        if !translated {
+            return false;
+        }
+        if env_flag("BUCKLE_PREFER_NATIVE_ARCH") {
+            debug_log("running under Rosetta, using the aarch64 buck2 as BUCKLE_PREFER_NATIVE_ARCH is set");
+        } else {
+            eprintln!(
+                "buckle: buckle is running under Rosetta, so the slower x86_64 buck2 was \
+                selected. Install an aarch64 buckle to run natively, or set \
+                BUCKLE_PREFER_NATIVE_ARCH=1 to use the aarch64 buck2."
+            );
+        }
+        true
+    })
+}
+
+/// The triple `buckle warm` is fetching, which takes precedence over `BUCKLE_TRIPLE` and the
+/// host's while it is set.
+static TRIPLE_OVERRIDE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Run `f` as though the platform were `triple`.
+fn with_triple<T>(triple: &str, f: impl FnOnce() -> T) -> T {
+    *TRIPLE_OVERRIDE.lock().unwrap() = Some(triple.to_string());
+    let result = f();
+    *TRIPLE_OVERRIDE.lock().unwrap() = None;
+    result
+}
+
+/// Set while `buckle warm` installs several versions, so that installing one can't evict
+/// another warmed in the same run. The cache is capped once they are all in.
+static SIZE_CAP_DEFERRED: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` without capping the cache size after each install.
+fn deferring_size_cap<T>(f: impl FnOnce() -> T) -> T {
+    SIZE_CAP_DEFERRED.store(true, Ordering::SeqCst);
+    let result = f();
+    SIZE_CAP_DEFERRED.store(false, Ordering::SeqCst);
+    result
+}
+
+/// The target triple of the buck2 binary to use, `BUCKLE_TRIPLE` overriding the host's.
+fn get_triple() -> Result<String, Error> {
+    let triple = match TRIPLE_OVERRIDE.lock().unwrap().clone() {
+        Some(triple) => triple,
+        None => match env::var("BUCKLE_TRIPLE") {
+            Ok(triple) => triple,
+            Err(_) => {
+                return Ok(get_arch()
+                    .map_err(BuckleError::UnsupportedPlatform)?
+                    .to_string())
+            }
+        },
+    };
+    // Triples are put in cache paths and download URLs just like versions.
+    if !is_safe_name(&triple) {
+        return Err(BuckleError::Config(format!(
+            "{triple:?} is not a valid target triple, triples may only contain letters, \
+            digits, '.', '-', '_' and '+', and may not start with '.'"
+        ))
+        .into());
+    }
+    Ok(triple)
+}
+
+/// Adopt a binary cached before the cache was keyed by triple, which kept `buck2` and
+/// `prelude_hash` directly under the commitish directory.
+///
+/// Such a binary could only have been installed for the host, so this is only called for the
+/// host's own triple.
+///
+/// Several buckles may migrate at once. Each links the old files into the new layout and only
+/// then removes them, so whichever finds them gone can count on the new layout being there.
+fn migrate_untripled_cache(commitish_dir: &Path, dir_path: &Path) -> Result<(), Error> {
+    let old_files = ["buck2", "prelude_hash"].map(|name| commitish_dir.join(name));
+    if !old_files.iter().all(|old| old.is_file()) {
+        return Ok(());
+    }
+    // Assemble the new directory to one side so it only appears once it is complete.
+    let staging = create_staging_dir(dir_path)?;
+    for old in &old_files {
+        let Some(name) = old.file_name() else {
+            continue;
+        };
+        match fs::hard_link(old, staging.path().join(name)) {
+            // Another buckle has finished migrating it.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            linked => linked?,
+        }
+    }
+    if is_installed(dir_path) {
+        return Ok(());
+    }
+    publish_version_dir(staging, dir_path)?;
+    for old in &old_files {
+        match fs::remove_file(old) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    eprintln!("buckle: moved the cached buck2 into {}", dir_path.display());
+    Ok(())
+}
+
+/// `bytes` in the largest binary unit that keeps it at least 1, e.g. `12.3 MiB`.
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Check that `body` starts like a zstd archive before decoding it, so that an HTML error
+/// page or a gzip body from a misconfigured mirror is reported as such rather than as a
+/// decode error. Returns a reader over the whole body.
+fn check_zstd<'a, R: Read>(
+    body: &'a mut R,
+    content_type: Option<&str>,
+    url: &str,
+) -> Result<io::Chain<io::Cursor<Vec<u8>>, &'a mut R>, Error> {
+    let mut head = Vec::new();
+    (&mut *body).take(16).read_to_end(&mut head)?;
+    if !head.starts_with(&ZSTD_MAGIC) {
+        let hex: Vec<String> = head.iter().map(|byte| format!("{byte:02x}")).collect();
+        return Err(anyhow!(
+            "The download from {} is not a zstd archive ({}, starting {} {:?}), check that \
+            the mirror serves buck2 release assets",
+            auth::redact_url(url),
+            content_type.unwrap_or("no content type"),
+            hex.join(" "),
+            String::from_utf8_lossy(&head)
+        ));
+    }
+    Ok(io::Cursor::new(head).chain(body))
+}
+
+/// Passes reads through from `inner` while counting and hashing the bytes read.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+    hasher: Sha256,
+}
+
+impl<R: Read> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            count: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The hex encoded SHA256 of everything read.
+    fn finish(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Passes writes through to `inner` while computing their SHA256.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The hex encoded SHA256 of everything written.
+    fn finish(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Install a freshly decoded binary at `buck2_path`, sharing storage with any identical binary
+/// already downloaded for another version.
+///
+/// Binaries are stored once in a content addressed store under `<buckle>/buck2/objects` and
+/// hard linked into each version directory, falling back to a copy where that isn't possible.
+fn install_binary(
+    tmp_buck2_bin: NamedTempFile,
+    digest: &str,
+    buckle_dir: &Path,
+    buck2_path: &Path,
+) -> Result<(), Error> {
+    let objects_dir: PathBuf = [buckle_dir, Path::new("buck2"), Path::new("objects")]
+        .iter()
+        .collect();
+    fs::create_dir_all(&objects_dir)?;
+    let object_path = objects_dir.join(digest);
+    if !object_path.exists() {
+        persist_or_copy(tmp_buck2_bin, &object_path)?;
+        sync_dir(&objects_dir)?;
+    }
+
+    let dir_path = buck2_path
+        .parent()
+        .ok_or(anyhow!("{} has no parent", buck2_path.display()))?;
+    if fs::hard_link(&object_path, buck2_path).is_err() {
+        let tmp_copy = NamedTempFile::new_in(dir_path)?;
+        fs::copy(&object_path, tmp_copy.path())?;
+        tmp_copy.as_file().sync_all()?;
+        tmp_copy.persist(buck2_path)?;
+    }
+    sync_dir(dir_path)?;
+    Ok(())
+}
+
+/// The newest published release that is, or isn't, a prerelease.
+fn channel_release(releases: &[Release], prerelease: bool) -> Option<&Release> {
+    // The moving `latest` tag is skipped so that a channel resolves to a concrete release.
+    let candidates = releases.iter().filter(|release| {
+        release.prerelease == prerelease && !release.draft && release.tag_name != "latest"
+    });
+    newest_release(candidates)
+}
+
+/// The release with the latest [`Release::released_at`], as the API's order isn't guaranteed.
+/// On a tie, or for releases without timestamps, the earliest listed wins.
+fn newest_release<'a>(releases: impl Iterator<Item = &'a Release>) -> Option<&'a Release> {
+    releases.reduce(|newest, release| {
+        if release.released_at() > newest.released_at() {
+            release
+        } else {
+            newest
+        }
+    })
+}
+
+/// The release tagged `tag`, preferring a published one over a draft of the same tag. A
+/// release whose display name is `tag` is the fallback, as the name is optional.
+fn find_tag<'a>(releases: &'a [Release], tag: &str) -> Option<&'a Release> {
+    let find = |matches: &dyn Fn(&Release) -> bool| {
+        let mut matching = releases.iter().filter(|release| matches(release));
+        let first = matching.clone().next();
+        matching.find(|release| !release.draft).or(first)
+    };
+    find(&|release| release.tag_name == tag)
+        .or_else(|| find(&|release| release.name.as_deref() == Some(tag)))
+}
+
+/// Find the release a version refers to. Besides literal tags this understands the channel
+/// aliases `stable` (newest non-prerelease) and `nightly`/`prerelease` (newest prerelease).
+fn resolve_release<'a>(version: &str, releases: &'a [Release]) -> Result<&'a Release, Error> {
+    let release = match version {
+        "stable" => channel_release(releases, false).ok_or_else(|| {
+            anyhow!("There are no stable releases of buck2 to resolve '{version}'.")
+        })?,
+        "nightly" | "prerelease" => channel_release(releases, true)
+            .ok_or_else(|| anyhow!("There are no prereleases of buck2 to resolve '{version}'."))?,
+        // Drafts can show up for maintainers with a token, but their assets may be incomplete.
+        "latest" => find_tag(releases, version)
+            .filter(|release| !release.draft)
+            .or_else(|| newest_release(releases.iter().filter(|release| !release.draft)))
+            .ok_or_else(|| {
+                anyhow!("There are no published releases of buck2 to resolve '{version}'.")
+            })?,
+        tag => find_tag(releases, tag).ok_or_else(|| {
+            BuckleError::VersionNotFound(format!(
+                "{version} was not available. \
+                Please check '{BUCK_RELEASE_URL}' for available releases."
+            ))
+        })?,
+    };
+    if release.draft {
+        eprintln!(
+            "buckle: buck2 {} is a draft release, its assets may be incomplete",
+            release.tag_name
+        );
+    }
+    if release.tag_name != version {
+        eprintln!("buckle: {version} resolved to {}", release.tag_name);
+    }
+    Ok(release)
+}
+
+/// How far behind the newest release a pin can fall before buckle suggests upgrading.
+const DEFAULT_STALE_WARN_DAYS: i64 = 90;
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(timestamp).ok()
+}
+
+/// Suggest an upgrade when `pinned` was published long before the newest release. This is
+/// purely advisory and never affects which version is used.
+fn warn_if_stale(pinned: &Release, releases: &[Release]) {
+    if env_flag("BUCKLE_NO_STALE_WARN") {
+        return;
+    }
+    let threshold_days = env::var("BUCKLE_STALE_WARN_DAYS")
+        .ok()
+        .and_then(|days| days.trim().parse().ok())
+        .unwrap_or(DEFAULT_STALE_WARN_DAYS);
+    let Some(pinned_at) = pinned.released_at() else {
+        return;
+    };
+    let newer: Vec<(&Release, DateTime<FixedOffset>)> = releases
+        .iter()
+        .filter(|release| !release.draft && release.tag_name != "latest")
+        .filter_map(|release| Some((release, release.released_at()?)))
+        .filter(|(_, published_at)| *published_at > pinned_at)
+        .collect();
+    let Some((newest, newest_at)) = newer.iter().max_by_key(|(_, published_at)| *published_at)
+    else {
+        return;
+    };
+    let days_behind = (*newest_at - pinned_at).num_days();
+    if days_behind > threshold_days {
+        eprintln!(
+            "buckle: buck2 {} is {days_behind} days and {} releases behind {}, \
+            consider upgrading .buckversion",
+            pinned.tag_name,
+            newer.len(),
+            newest.tag_name,
+        );
+    }
+}
+
+/// Where `release` is installed, keyed by triple so a shared cache can serve several platforms.
+fn get_version_dir(output_dir: &Path, release: &Release) -> Result<PathBuf, Error> {
+    // Both come from the releases list, which a mirror may get wrong.
+    validate_version(&release.tag_name)?;
+    if !is_safe_name(&release.target_commitish) {
+        return Err(anyhow!(
+            "The release {} has a target_commitish of {:?}, which can't be used as a cache \
+            directory",
+            release.tag_name,
+            release.target_commitish
+        ));
+    }
+    Ok(output_dir
+        .join(&release.target_commitish)
+        .join(get_triple()?))
+}
+
+/// Fail, naming what is there instead, if `release` lists its assets but none is a buck2 for
+/// `arch`. Releases without an asset list, as some mirrors serve, are assumed to have one.
+fn check_arch_asset(release: &Release, arch: &str) -> Result<(), Error> {
+    let names = asset_names(release);
+    let wanted = format!("buck2-{arch}.zst");
+    if names.is_empty() || names.contains(&wanted.as_str()) {
+        return Ok(());
+    }
+    // The inline list can be cut short for releases with many assets.
+    if !is_offline()? {
+        match find_paged_asset(release, &wanted) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(err) => debug_log(&format!(
+                "could not page through the assets of buck2 {}: {err}",
+                release.tag_name
+            )),
+        }
+    }
+    Err(anyhow!(
+        "buck2 {} exists but has no binary for {arch}. Its assets are: {}",
+        release.tag_name,
+        names.join(", ")
+    ))
+}
+
+/// With `BUCKLE_GITHUB_TOKEN` set, the API URL of `release`'s asset `name`, which unlike the
+/// base URL accepts the token, as private repositories need. Only an asset on GitHub over
+/// https, and on the host the releases list came from, qualifies, so the token goes nowhere
+/// else.
+fn get_asset_api_url(release: &Release, name: &str) -> Result<Option<String>, Error> {
+    if auth::github_token().is_none() {
+        return Ok(None);
+    }
+    let Some(url) = release
+        .assets
+        .iter()
+        .find(|asset| asset.get("name").and_then(|value| value.as_str()) == Some(name))
+        .and_then(|asset| asset.get("url")?.as_str())
+    else {
+        return Ok(None);
+    };
+    if !auth::is_github_https(url) {
+        debug_log(&format!(
+            "not downloading through the asset API URL {}, it is not GitHub over https",
+            auth::redact_url(url)
+        ));
+        return Ok(None);
+    }
+    let releases_url = Url::parse(&get_releases_url()?)?;
+    match Url::parse(url) {
+        Ok(parsed) if parsed.host_str().is_some() && parsed.host() == releases_url.host() => {
+            Ok(Some(url.to_string()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// GitHub's largest page of release assets.
+const ASSETS_PER_PAGE: usize = 100;
+/// Enough pages for any release GitHub will accept.
+const MAX_ASSET_PAGES: usize = 10;
+
+/// Page through the `assets_url` of `release` looking for the asset `wanted`.
+fn find_paged_asset(release: &Release, wanted: &str) -> Result<bool, Error> {
+    for page in 1..=MAX_ASSET_PAGES {
+        let mut url = release.assets_url.clone();
+        url.query_pairs_mut()
+            .append_pair("per_page", &ASSETS_PER_PAGE.to_string())
+            .append_pair("page", &page.to_string());
+        let assets: Vec<serde_json::Value> = auth::get_ok(url.as_str())?.json()?;
+        let found = assets
+            .iter()
+            .any(|asset| asset.get("name").and_then(|name| name.as_str()) == Some(wanted));
+        if found {
+            return Ok(true);
+        }
+        if assets.len() < ASSETS_PER_PAGE {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+fn asset_names(release: &Release) -> Vec<&str> {
+    release
+        .assets
+        .iter()
+        .filter_map(|asset| asset.get("name")?.as_str())
+        .collect()
+}
+
+/// The release to install for `release`. With `BUCKLE_ARCH_FALLBACK=1` that is the newest older
+/// release with a binary for this platform when `release` has none and isn't already cached.
+/// Releases without dates are taken in list order.
+fn arch_fallback<'a>(
+    release: &'a Release,
+    releases: &'a [Release],
+    output_dir: &Path,
+) -> Result<&'a Release, Error> {
+    if !env_flag("BUCKLE_ARCH_FALLBACK") || is_installed(&get_version_dir(output_dir, release)?) {
+        return Ok(release);
+    }
+    let arch = get_triple()?;
+    let Err(err) = check_arch_asset(release, &arch) else {
+        return Ok(release);
+    };
+    let wanted = format!("buck2-{arch}.zst");
+    let pinned_at = release.released_at();
+    let position = releases
+        .iter()
+        .position(|candidate| candidate.tag_name == release.tag_name);
+    let older = releases
+        .iter()
+        .enumerate()
+        .filter(
+            |(index, candidate)| match (pinned_at, candidate.released_at()) {
+                (Some(pinned_at), Some(released_at)) => released_at < pinned_at,
+                _ => matches!(position, Some(position) if *index > position),
+            },
+        )
+        .map(|(_, candidate)| candidate)
+        .filter(|candidate| {
+            !candidate.draft
+                && candidate.tag_name != "latest"
+                && asset_names(candidate).contains(&wanted.as_str())
+        });
+    let fallback = newest_release(older).ok_or_else(|| {
+        anyhow!("{err}. BUCKLE_ARCH_FALLBACK is set, but no older release has one either")
+    })?;
+    eprintln!(
+        "buckle: WARNING: buck2 {} has no binary for {arch}, so {} is being used instead of the \
+        pinned version because BUCKLE_ARCH_FALLBACK is set",
+        release.tag_name, fallback.tag_name
+    );
+    Ok(fallback)
+}
+
+/// Whether `dir_path` holds a usable version: its binary and the prelude_hash to check
+/// against. Either can be missing after an interrupted download or a partial cleanup.
+fn is_installed(dir_path: &Path) -> bool {
+    let binary = ["buck2", cache::COMPRESSED_NAME];
+    binary.iter().any(|name| dir_path.join(name).is_file())
+        && dir_path.join("prelude_hash").is_file()
+}
+
+/// Write `contents` to `path` so that readers see either the old file or all of the new one.
+fn write_file_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no parent directory", path.display()),
+        )
+    })?;
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(contents)?;
+    tmp.flush()?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path)?;
+    sync_dir(dir)
+}
+
+/// Make renames into `dir` durable, so a crash can't lose a file that was already reported
+/// as written.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Download `version` into the cache unless it is already there. With a `pinned_digest`, the
+/// binary must have that SHA256 or it is neither installed nor run. Returns the tag `version`
+/// resolved to and the directory it is installed in.
+fn download_http(
+    version: String,
+    pinned_digest: Option<&str>,
+    output_dir: &Path,
+) -> Result<(String, PathBuf), Error> {
+    validate_version(&version)?;
+    not_found::check(output_dir, &version)?;
+    let allowlist = allowlist::get_allowlist()?;
+    if let Some(allowlist) = &allowlist {
+        // A direct download skips the releases list, so the tag itself has to be allowed.
+        if get_direct_dir(output_dir, &version)?.is_some() {
+            allowlist.check(&version)?;
+        }
+    }
+    if let Some(dir_path) = download_direct(&version, pinned_digest, output_dir)? {
+        return Ok((version, dir_path));
+    }
+    let mut releases = timing::time("releases list", || {
+        get_releases_for(output_dir, Some(&version))
+    })?;
+    if let Some(allowlist) = &allowlist {
+        releases = allowlist.restrict(&version, releases)?;
+    }
+    let resolved = match session::resolve_session_release(&version, &releases, output_dir) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            not_found::remember(output_dir, &version, &err);
+            return Err(err);
+        }
+    };
+    if let Some(allowlist) = &allowlist {
+        // A session may remember a release from before the allowlist changed.
+        allowlist.check(&resolved.tag_name)?;
+    }
+    // Only an explicit pin can go stale, aliases always resolve to something recent.
+    if resolved.tag_name == version && version != "latest" {
+        warn_if_stale(&resolved, &releases);
+    }
+    let release = arch_fallback(&resolved, &releases, output_dir)?;
+    let version = release.tag_name.clone();
+    let arch = get_triple()?;
+    let commitish_dir = output_dir.join(&release.target_commitish);
+
+    // Path to directory that caches buck
+    let dir_path = get_version_dir(output_dir, release)?;
+    let mut buck2_path = dir_path.clone();
+    let dry_run = env_flag("BUCKLE_DRY_RUN");
+    buck2_path.push("buck2");
+    if !buck2_path.exists() && !dry_run && matches!(get_arch(), Ok(host) if host == arch) {
+        if let Err(err) = migrate_untripled_cache(&commitish_dir, &dir_path) {
+            eprintln!(
+                "buckle: could not move the cached buck2 in {} into {}: {err}",
+                commitish_dir.display(),
+                dir_path.display()
+            );
+        }
+    }
+    if is_installed(&dir_path) {
+        // Already downloaded
+        if !dry_run {
+            cache::decompress(output_dir, &dir_path)?;
+        }
+        if let Some(pinned_digest) = pinned_digest {
+            check_cached_digest(&buck2_path, &version, pinned_digest)?;
+        }
+        if dry_run {
+            eprintln!(
+                "buckle: dry run: buck2 {version} is already cached at {}",
+                dir_path.display()
+            );
+        }
+        return Ok((version, dir_path));
+    }
+
+    check_arch_asset(release, &arch)?;
+    let base_url = get_base_url()?;
+    let asset_api_url = get_asset_api_url(release, &format!("buck2-{arch}.zst"))?;
+    let buck2_url = match &asset_api_url {
+        Some(asset_api_url) => asset_api_url.clone(),
+        None => format!("{base_url}/{version}/buck2-{arch}.zst"),
+    };
+    let prelude_hash_url = format!("{base_url}/{version}/prelude_hash");
+    let verifier = get_signature_verifier()?;
+    if dry_run {
+        eprintln!(
+            "buckle: dry run: would fetch buck2-{arch}.zst from {}",
+            auth::redact_url(&buck2_url)
+        );
+        eprintln!(
+            "buckle: dry run: would fetch prelude_hash from {}",
+            auth::redact_url(&prelude_hash_url)
+        );
+        eprintln!(
+            "buckle: dry run: would install buck2 {version} to {}",
+            buck2_path.display()
+        );
+        return Ok((version, dir_path));
+    }
+    if is_offline()? {
+        return Err(anyhow!(
+            "buck2 {version} is not cached and buckle is offline"
+        ));
+    }
+
+    install_release(
+        |tmpdir| match (&asset_api_url, auth::github_token()) {
+            (Some(asset_api_url), Some(token)) => {
+                Ok(auth::get_asset(asset_api_url, &token)?.into())
+            }
+            _ => chunked::fetch(&buck2_url, tmpdir),
+        },
+        &version,
+        pinned_digest,
+        verifier.as_deref(),
+        output_dir,
+        &dir_path,
+    )?;
+    Ok((version, dir_path))
+}
+
+/// Where an exact tag is installed when it is downloaded directly. Without the releases list
+/// its commitish isn't known, so the tag names the directory instead. `None` unless direct
+/// downloads are enabled and `version` is a plain tag.
+fn get_direct_dir(output_dir: &Path, version: &str) -> Result<Option<PathBuf>, Error> {
+    if !is_safe_name(version) || session::is_moving(version) || !is_direct_download()? {
+        return Ok(None);
+    }
+    Ok(Some(output_dir.join(version).join(get_triple()?)))
+}
+
+/// With `BUCKLE_DIRECT_DOWNLOAD`, fetch an exact tag from `BUCKLE_BASE_URL/<tag>` without
+/// consulting the releases list, saving an API call. `None` to fall back to the list, such as
+/// when the tag isn't found there.
+fn download_direct(
+    version: &str,
+    pinned_digest: Option<&str>,
+    output_dir: &Path,
+) -> Result<Option<PathBuf>, Error> {
+    let Some(dir_path) = get_direct_dir(output_dir, version)? else {
+        return Ok(None);
+    };
+    let buck2_path = dir_path.join("buck2");
+    let dry_run = env_flag("BUCKLE_DRY_RUN");
+    if !dry_run {
+        // An explicit pin ends the session.
+        session::end_session(output_dir);
+    }
+    if is_installed(&dir_path) {
+        if !dry_run {
+            cache::decompress(output_dir, &dir_path)?;
+        }
+        if let Some(pinned_digest) = pinned_digest {
+            check_cached_digest(&buck2_path, version, pinned_digest)?;
+        }
+        if dry_run {
+            eprintln!(
+                "buckle: dry run: buck2 {version} is already cached at {}",
+                dir_path.display()
+            );
+        }
+        return Ok(Some(dir_path));
+    }
+    if is_offline()? {
+        return Ok(None);
+    }
+
+    let arch = get_triple()?;
+    let buck2_url = format!("{}/{version}/buck2-{arch}.zst", get_base_url()?);
+    let verifier = get_signature_verifier()?;
+    if dry_run {
+        eprintln!(
+            "buckle: dry run: would fetch buck2-{arch}.zst from {} without the releases list",
+            auth::redact_url(&buck2_url)
+        );
+        eprintln!(
+            "buckle: dry run: would install buck2 {version} to {}",
+            buck2_path.display()
+        );
+        return Ok(Some(dir_path));
+    }
+    let resp = auth::get(&buck2_url)?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        eprintln!(
+            "buckle: {} was not found, looking buck2 {version} up in the releases list",
+            auth::redact_url(&buck2_url)
+        );
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Could not fetch {}: {}",
+            auth::redact_url(&buck2_url),
+            resp.status()
+        ));
+    }
+    install_release(
+        |_| Ok(resp.into()),
+        version,
+        pinned_digest,
+        verifier.as_deref(),
+        output_dir,
+        &dir_path,
+    )?;
+    Ok(Some(dir_path))
+}
+
+/// Decode the archive `fetch` returns into `dir_path`, fetching its prelude_hash (and
+/// signature, if one is required) alongside it. `fetch` is given the directory downloads are
+/// staged in. The binary is installed last, so an interrupted download leaves nothing that
+/// looks cached.
+fn install_release(
+    fetch: impl FnOnce(&Path) -> Result<chunked::Archive, Error>,
+    version: &str,
+    pinned_digest: Option<&str>,
+    verifier: Option<&dyn SignatureVerifier>,
+    output_dir: &Path,
+    dir_path: &Path,
+) -> Result<(), Error> {
+    let arch = get_triple()?;
+    let base_url = get_base_url()?;
+    // The manifest approves one binary per tag, so what `buckle warm` fetches, possibly for
+    // other platforms, is only checked once it runs.
+    let warming = TRIPLE_OVERRIDE.lock().unwrap().is_some();
+    let manifest = if warming {
+        None
+    } else {
+        manifest::get_manifest(output_dir)?
+    };
+    if let Some(manifest) = &manifest {
+        manifest.check_tag(version)?;
+    }
+    let buck2_path = dir_path.join("buck2");
+    if buck2_path.exists() || dir_path.join("prelude_hash").exists() {
+        eprintln!(
+            "buckle: {} is incomplete, downloading buck2 {version} again",
+            dir_path.display()
+        );
+    }
+    fs::create_dir_all(dir_path).map_err(|err| cache_write_error(dir_path, err))?;
+    let staging = create_staging_dir(dir_path)?;
+    let tmpdir = get_download_tmpdir(staging.path());
+    let archive = match fetch(&tmpdir) {
+        Ok(archive) => archive,
+        Err(err) => {
+            // Leave no empty directory behind for a version that was never fetched.
+            drop(staging);
+            let _ = fs::remove_dir(dir_path);
+            return Err(err);
+        }
+    };
+    let started = archive.started;
+
+    // The prelude hash is tiny and independent of the archive, so fetch it while the
+    // archive streams rather than paying for another round-trip afterwards.
+    let prelude_hash_url = format!("{base_url}/{version}/prelude_hash");
+    let prelude_hash_fetch = thread::spawn(move || -> Result<Vec<u8>, Error> {
+        let resp = auth::get_ok(&prelude_hash_url)?;
+        Ok(resp.bytes()?.to_vec())
+    });
+
+    // Decode the buck2 archive, make it executable
+    let mut tmp_buck2_bin = create_download_tmpfile(&tmpdir, staging.path())?;
+    let progress = !env_flag("BUCKLE_NO_PROGRESS");
+    let declared_len = archive.len;
+    if progress {
+        let parts = match archive.parts {
+            1 => String::new(),
+            parts => format!(" in {parts} parts"),
+        };
+        match declared_len {
+            Some(len) => eprintln!(
+                "buckle: fetching buck2 {version} ({}){parts}",
+                human_bytes(len)
+            ),
+            None => eprintln!("buckle: fetching buck2 {version}{parts}"),
+        }
+    }
+    let mut resp = CountingReader::new(archive.body);
+    let mut writer = HashingWriter::new(&tmp_buck2_bin);
+    let zstd_archive = check_zstd(&mut resp, archive.content_type.as_deref(), &archive.url)?;
+    let decoded = zstd::stream::copy_decode(zstd_archive, &mut writer);
+    // Anything after the last frame is still part of the asset, and of its digest.
+    if decoded.is_ok() {
+        io::copy(&mut resp, &mut io::sink())?;
+    }
+    // A connection dropped part way through shows up as a confusing decode error, or none at
+    // all if it happened to end on a frame boundary, so compare against what was promised.
+    if let Some(declared_len) = declared_len {
+        if resp.count != declared_len {
+            return Err(anyhow!(
+                "The download of buck2 {version} was truncated: received {} of {declared_len} \
+                bytes. Please retry.",
+                resp.count
+            ));
+        }
+    }
+    decoded?;
+    if progress {
+        let elapsed = started.elapsed();
+        let throughput = resp.count as f64 / elapsed.as_secs_f64().max(0.001);
+        eprintln!(
+            "buckle: fetched buck2 {version} in {:.1}s ({}/s)",
+            elapsed.as_secs_f64(),
+            human_bytes(throughput as u64)
+        );
+    }
+    // An archive assembled from ranges is a temporary file in the staging directory, which
+    // must be gone before that directory is published.
+    let asset_digest = resp.finish();
+    let mut digest = writer.finish();
+    tmp_buck2_bin.flush()?;
+    if is_tar(tmp_buck2_bin.as_file_mut())? {
+        let (extracted, extracted_digest) = extract_buck2(tmp_buck2_bin.reopen()?, &tmpdir)?;
+        tmp_buck2_bin = extracted;
+        digest = extracted_digest;
+    }
+    if let Some(pinned_digest) = pinned_digest {
+        if digest != pinned_digest {
+            return Err(anyhow!(
+                "Refusing to install buck2 {version}: its SHA256 is {digest}, \
+                but {pinned_digest} is pinned"
+            ));
+        }
+    }
+    tmp_buck2_bin.as_file().sync_all()?;
+    if let Some(verifier) = verifier {
+        let signature_url = format!(
+            "{base_url}/{version}/buck2-{arch}{}",
+            verifier.signature_suffix()
+        );
+        let resp = auth::get(&signature_url)?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "BUCKLE_VERIFY_KEY is set but no signature could be fetched from {}: {}",
+                auth::redact_url(&signature_url),
+                resp.status()
+            ));
+        }
+        verifier
+            .verify(tmp_buck2_bin.path(), &resp.bytes()?)
+            .map_err(|err| anyhow!("Refusing to install buck2 {version}: {err}"))?;
+    }
+    #[cfg(unix)]
+    {
+        let permissions = fs::Permissions::from_mode(0o755);
+        fs::set_permissions(&tmp_buck2_bin, permissions)?;
+    }
+
+    // Populate the staging directory, then move it into place all at once.
+    let prelude_hash = prelude_hash_fetch
+        .join()
+        .map_err(|_| anyhow!("The prelude_hash download for buck2 {version} panicked"))?
+        .map_err(|err| anyhow!("Could not fetch prelude_hash for buck2 {version}: {err}"))?;
+    if let Some(manifest) = &manifest {
+        let prelude_hash = String::from_utf8_lossy(&prelude_hash);
+        manifest.check(version, &digest, prelude_hash.trim())?;
+    }
+    write_file_atomically(&staging.path().join("prelude_hash"), &prelude_hash)?;
+    // Remember what was verified so later runs can check the binary without a download.
+    write_file_atomically(&staging.path().join("buck2.sha256"), digest.as_bytes())?;
+    // And what the release asset itself hashed to, to compare with published checksums.
+    write_file_atomically(
+        &staging.path().join(ASSET_DIGEST_NAME),
+        asset_digest.as_bytes(),
+    )?;
+    install_binary(
+        tmp_buck2_bin,
+        &digest,
+        output_dir,
+        &staging.path().join("buck2"),
+    )?;
+    publish_version_dir(staging, dir_path)?;
+    #[cfg(unix)]
+    ensure_executable(&buck2_path)?;
+    if !SIZE_CAP_DEFERRED.load(Ordering::SeqCst) {
+        cache::enforce_size_cap(output_dir, &[dir_path])?;
+    }
+    run_post_download_hook(version, &buck2_path)?;
+
+    Ok(())
+}
+
+/// Run `BUCKLE_POST_DOWNLOAD_HOOK`, if set, for the newly installed buck2 `tag`. It is given
+/// the tag and binary path as arguments and as `BUCKLE_DOWNLOADED_VERSION` and
+/// `BUCKLE_DOWNLOADED_PATH`. A failing hook only warns unless `BUCKLE_POST_DOWNLOAD_STRICT`
+/// is set.
+fn run_post_download_hook(tag: &str, buck2_path: &Path) -> Result<(), Error> {
+    let Ok(line) = env::var("BUCKLE_POST_DOWNLOAD_HOOK") else {
+        return Ok(());
+    };
+    let hook = split_command_line(&line)
+        .map_err(|err| anyhow!("BUCKLE_POST_DOWNLOAD_HOOK could not be parsed: {err}"))?;
+    let Some((program, args)) = hook.split_first() else {
+        return Ok(());
+    };
+    // Whatever the hook prints goes to stderr, so it can't be mistaken for buck2's output.
+    let ran = Command::new(program)
+        .args(args)
+        .arg(tag)
+        .arg(buck2_path)
+        .env("BUCKLE_DOWNLOADED_VERSION", tag)
+        .env("BUCKLE_DOWNLOADED_PATH", buck2_path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output();
+    let failure = match ran {
+        Ok(output) => {
+            io::stderr().write_all(&output.stdout)?;
+            if output.status.success() {
+                return Ok(());
+            }
+            format!("{program} exited with {}", output.status)
+        }
+        Err(err) => format!("{program} could not be run: {err}"),
+    };
+    if env_flag("BUCKLE_POST_DOWNLOAD_STRICT") {
+        return Err(anyhow!(
+            "The post-download hook for buck2 {tag} failed: {failure}"
+        ));
+    }
+    eprintln!("buckle: the post-download hook for buck2 {tag} failed: {failure}");
+    Ok(())
+}
+
+/// The files that make up an installed version, in the order they are moved into place when
+/// the whole directory can't be. The binary goes last, so that it never appears without the
+/// rest.
+const VERSION_FILES: [&str; 4] = ["prelude_hash", "buck2.sha256", ASSET_DIGEST_NAME, "buck2"];
+
+/// Where the SHA256 of the downloaded `buck2-<triple>.zst` asset is recorded.
+const ASSET_DIGEST_NAME: &str = "asset.sha256";
+
+/// An empty directory next to `dir_path` to assemble a version in before publishing it.
+fn create_staging_dir(dir_path: &Path) -> Result<tempfile::TempDir, Error> {
+    let parent = dir_path
+        .parent()
+        .ok_or(anyhow!("{} has no parent directory", dir_path.display()))?;
+    let name = dir_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    tempfile::Builder::new()
+        .prefix(&format!(".{name}.tmp-"))
+        .tempdir_in(parent)
+        .map_err(|err| cache_write_error(parent, err))
+}
+
+/// Move the fully populated `staging` directory to `dir_path` with a single rename, so that
+/// readers see either no version or all of it. Where that isn't possible, such as when
+/// `dir_path` already holds files or on Windows, the files are renamed into it one by one.
+fn publish_version_dir(staging: tempfile::TempDir, dir_path: &Path) -> Result<(), Error> {
+    if fs::rename(staging.path(), dir_path).is_ok() {
+        // It is gone from where the TempDir would clean up.
+        let _ = staging.into_path();
+    } else {
+        fs::create_dir_all(dir_path)?;
+        for name in VERSION_FILES {
+            // A version migrated from an older cache has no buck2.sha256 or asset.sha256.
+            let staged = staging.path().join(name);
+            if staged.exists() {
+                fs::rename(staged, dir_path.join(name))?;
+            }
+        }
+    }
+    sync_dir(dir_path)?;
+    if let Some(parent) = dir_path.parent() {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+/// Whether `file` holds a tar archive rather than a bare executable, judged by the `ustar`
+/// magic in its first header.
+fn is_tar(file: &mut File) -> Result<bool, Error> {
+    let mut header = [0; 512];
+    file.seek(SeekFrom::Start(0))?;
+    let read = file.read(&mut header)?;
+    Ok(read == header.len() && header[257..262] == *b"ustar")
+}
+
+/// Pull the buck2 executable out of a decoded tar `archive` into a temporary file in `dir`,
+/// returning it along with its hash.
+fn extract_buck2(archive: File, dir: &Path) -> Result<(NamedTempFile, String), Error> {
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?;
+        let name = path.file_name().and_then(|name| name.to_str());
+        if !entry.header().entry_type().is_file() || !matches!(name, Some("buck2" | "buck2.exe")) {
+            continue;
+        }
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        let mut writer = HashingWriter::new(&mut tmp);
+        io::copy(&mut entry, &mut writer)?;
+        let digest = writer.finish();
+        tmp.flush()?;
+        return Ok((tmp, digest));
+    }
+    Err(anyhow!(
+        "The buck2 archive is a tar file without a buck2 in it"
+    ))
+}
+
+/// Fail unless the cached `buck2_path` is the binary `pinned_digest` names, going by the
+/// `buck2.sha256` recorded when it was downloaded, or by hashing it if there is none.
+fn check_cached_digest(buck2_path: &Path, version: &str, pinned_digest: &str) -> Result<(), Error> {
+    let cached_digest = installed_digest(buck2_path)?;
+    if cached_digest != pinned_digest {
+        return Err(anyhow!(
+            "The cached buck2 {version} at {} has SHA256 {cached_digest}, but {pinned_digest} \
+            is pinned",
+            buck2_path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// The SHA256 of an installed buck2: the `buck2.sha256` recorded when it was downloaded, or
+/// the hash of the file if there is none.
+fn installed_digest(buck2_path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(buck2_path.with_extension("sha256")) {
+        Ok(cached_digest) => Ok(cached_digest.trim().to_string()),
+        Err(_) => hash_binary(buck2_path),
+    }
+}
+
+/// The SHA256 of the buck2 at `buck2_path`, or of what it decompresses to if the cache holds it
+/// compressed.
+fn hash_binary(buck2_path: &Path) -> Result<String, Error> {
+    let mut writer = HashingWriter::new(io::sink());
+    let compressed = buck2_path.with_file_name(cache::COMPRESSED_NAME);
+    if !buck2_path.exists() && compressed.exists() {
+        zstd::stream::copy_decode(File::open(&compressed)?, &mut writer)?;
+    } else {
+        io::copy(&mut File::open(buck2_path)?, &mut writer)?;
+    }
+    Ok(writer.finish())
+}
+
+/// Re-hash an installed buck2 and compare it with the `buck2.sha256` stored when it was
+/// downloaded, to catch corruption on disk.
+fn verify_installed_binary(buck2_path: &Path) -> Result<(), Error> {
+    let sha256_path = buck2_path.with_extension("sha256");
+    let expected = match fs::read_to_string(&sha256_path) {
+        Ok(expected) => expected,
+        Err(_) => {
+            eprintln!(
+                "buckle: no {} to verify buck2 against, skipping",
+                sha256_path.display()
+            );
+            return Ok(());
+        }
+    };
+    let actual = hash_binary(buck2_path)?;
+    if actual != expected.trim() {
+        return Err(BuckleError::CacheCorrupt(format!(
+            "The buckle cache is corrupted: {} has SHA256 {actual} but {expected} was downloaded. \
+            Suggested fix is to remove {} to download it again",
+            buck2_path.display(),
+            buck2_path.parent().unwrap_or(buck2_path).display(),
+            expected = expected.trim(),
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Length of a hex encoded git SHA-1, which is what `prelude_hash` is expected to contain.
+const PRELUDE_HASH_LEN: usize = 40;
+
+fn is_git_hash(hash: &str) -> bool {
+    hash.len() == PRELUDE_HASH_LEN && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn read_prelude_hash(prelude_hash_path: &Path) -> Result<String, Error> {
+    let buf = fs::read(prelude_hash_path).map_err(|err| {
+        anyhow!(
+            "Could not read {}: {err}. Remove it to force a re-download.",
+            prelude_hash_path.display()
+        )
+    })?;
+    let prelude_hash = std::str::from_utf8(&buf)
+        .map_err(|_| {
+            anyhow!(
+                "{} is not valid UTF-8. Remove it to force a re-download.",
+                prelude_hash_path.display()
+            )
+        })?
+        .trim();
+    if prelude_hash.is_empty() {
+        return Err(anyhow!(
+            "{} is empty. Remove it to force a re-download.",
+            prelude_hash_path.display()
+        ));
+    }
+    if !is_git_hash(prelude_hash) {
+        return Err(anyhow!(
+            "{} does not contain a valid git hash. Remove it to force a re-download.",
+            prelude_hash_path.display()
+        ));
+    }
+    Ok(prelude_hash.to_string())
+}
+
+fn get_expected_prelude_hash() -> Result<&'static str, Error> {
+    static INSTANCE: OnceCell<String> = OnceCell::new();
+    let expected_hash = INSTANCE.get_or_try_init(|| {
+        if let Some(prelude_hash) = get_prelude_hash_override()? {
+            return Ok(prelude_hash);
+        }
+        // A locally built buck2 is never downloaded for, so only check against a cached release.
+        let mut prelude_hash_path = match get_buck2_bin_override()? {
+            Some(_) if USED_VERSION.get().is_none() => get_cached_buck2_dir()?,
+            _ => get_buck2_dir()?.1,
+        };
+        prelude_hash_path.push("prelude_hash");
+        read_prelude_hash(&prelude_hash_path)
+    })?;
+    Ok(expected_hash)
+}
+
+/// A prelude hash the project manages itself, such as for a prelude fork, to check against
+/// instead of the release's `prelude_hash`: `BUCKLE_EXPECTED_PRELUDE_HASH`, or else the first
+/// line of `.buckprelude` at the project root.
+fn get_prelude_hash_override() -> Result<Option<String>, Error> {
+    let (prelude_hash, source) = match env::var("BUCKLE_EXPECTED_PRELUDE_HASH") {
+        Ok(prelude_hash) => (prelude_hash, "BUCKLE_EXPECTED_PRELUDE_HASH".to_string()),
+        Err(_) => {
+            let Some(root) = get_buck2_project_root() else {
+                return Ok(None);
+            };
+            let path = root.join(".buckprelude");
+            match fs::read_to_string(&path) {
+                Ok(contents) => (contents, path.display().to_string()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(anyhow!("Could not read {}: {err}", path.display())),
+            }
+        }
+    };
+    let prelude_hash = prelude_hash.lines().next().unwrap_or_default().trim();
+    if !is_git_hash(prelude_hash) {
+        return Err(BuckleError::Config(format!(
+            "{source} does not contain a valid git hash: {prelude_hash:?}"
+        ))
+        .into());
+    }
+    debug_log(&format!(
+        "expecting the prelude at {prelude_hash} from {source}, not the release's prelude_hash"
+    ));
+    Ok(Some(prelude_hash.to_string()))
+}
+
+/// Pull the version out of a `.buckversion` file: the first token that isn't on a blank or
+/// `#` comment line, ignoring a UTF-8 BOM and anything after it on the line.
+fn parse_buckversion(contents: &str) -> Option<&str> {
+    contents
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| line.split_whitespace().next())
+}
+
+/// Whether `name` is safe to use as a single path component and in a URL: letters, digits,
+/// `.`, `-`, `_` and `+`, not starting with a dot.
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'))
+}
+
+/// Fail unless `version` is safe to put in cache paths and download URLs, so that a value such
+/// as `../../etc` can't escape the cache.
+fn validate_version(version: &str) -> Result<(), Error> {
+    if is_safe_name(version) {
+        return Ok(());
+    }
+    Err(BuckleError::Config(format!(
+        "{version:?} is not a valid buck2 version, versions may only contain letters, digits, \
+        '.', '-', '_' and '+', and may not start with '.'"
+    ))
+    .into())
+}
+
+/// Split a `<version>@sha256:<hex>` pin into the version and the digest its binary must have.
+fn split_digest(spec: &str) -> Result<(&str, Option<String>), Error> {
+    let Some((version, digest)) = spec.split_once('@') else {
+        validate_version(spec)?;
+        return Ok((spec, None));
+    };
+    validate_version(version)?;
+    let digest = digest
+        .strip_prefix("sha256:")
+        .filter(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| {
+            BuckleError::Config(format!(
+                "{spec} is not a valid version, expected <version>@sha256:<64 hex digits>"
+            ))
+        })?;
+    Ok((version, Some(digest.to_ascii_lowercase())))
+}
+
+/// The `.buckversion` closest to the current directory, looking no higher than the project
+/// root, so that a nested project can pin its own version.
+fn find_buckversion() -> Option<PathBuf> {
+    let root = get_buck2_project_root()?;
+    let cwd = env::current_dir().ok();
+    let cwd = cwd.map(|cwd| fs::canonicalize(&cwd).unwrap_or(cwd));
+    let start = cwd
+        .as_deref()
+        .filter(|cwd| cwd.starts_with(root))
+        .unwrap_or(root);
+    start
+        .ancestors()
+        .take_while(|dir| dir.starts_with(root))
+        .map(|dir| dir.join(".buckversion"))
+        .find(|path| path.exists())
+}
+
+/// The version to use, without any digest it is pinned to.
+fn read_buck2_version() -> Result<String, Error> {
+    let spec = read_version_spec()?;
+    Ok(split_digest(&spec)?.0.to_string())
+}
+
+/// `USE_BUCK2_VERSION`, trimmed. Set but blank, as a CI variable that is defined without a
+/// value often is, it counts as unset.
+fn get_version_override() -> Option<String> {
+    let version = env::var("USE_BUCK2_VERSION").ok()?;
+    let version = version.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// The version to use as written, possibly pinned to a digest with `@sha256:<hex>`, or as held
+/// by the project's `buckle.lock`.
+fn read_version_spec() -> Result<String, Error> {
+    if let Some(version) = get_version_override() {
+        return Ok(version);
+    }
+    if env::var_os("USE_BUCK2_VERSION").is_some() {
+        debug_log("ignoring USE_BUCK2_VERSION, which is set but empty");
+    }
+
+    let mut spec = String::from("latest");
+    if let Some(path) = find_buckversion() {
+        let contents = fs::read_to_string(&path)?;
+        spec = parse_buckversion(&contents)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                BuckleError::Config(format!("{} does not contain a version", path.display()))
+            })?;
+    }
+
+    // A project's lock holds its moving version to the exact binary it was written for.
+    if session::is_moving(&spec) {
+        if let Some(lock) = lock::read_lock()? {
+            return Ok(format!("{}@sha256:{}", lock.tag_name, lock.binary_sha256));
+        }
+    }
+    Ok(spec)
+}
+
+/// The buckle directory, created if it doesn't exist yet.
+fn ensure_buckle_dir() -> Result<PathBuf, Error> {
+    let buckle_dir = get_buckle_dir()?;
+    if !buckle_dir.exists() && !env_flag("BUCKLE_DRY_RUN") {
+        fs::create_dir_all(&buckle_dir).map_err(|err| cache_write_error(&buckle_dir, err))?;
+    }
+    Ok(buckle_dir)
+}
+
+/// Why writing to a directory failed, in terms of its likely cause.
+fn write_failure_reason(err: &std::io::Error) -> String {
+    // `ErrorKind::ReadOnlyFilesystem` is too new to rely on, EROFS is 30 on Linux and macOS,
+    // and ENOSPC is 28.
+    if cfg!(unix) && err.raw_os_error() == Some(30) {
+        "it is on a read-only file system".to_string()
+    } else if cfg!(unix) && err.raw_os_error() == Some(28) {
+        "its file system is full".to_string()
+    } else if err.kind() == std::io::ErrorKind::PermissionDenied {
+        "permission denied".to_string()
+    } else {
+        err.to_string()
+    }
+}
+
+/// Explain a failure to write to the cache at `path`, and how to use another one.
+fn cache_write_error(path: &Path, err: std::io::Error) -> Error {
+    anyhow!(
+        "buckle could not write to its cache at {}: {}. \
+        Set BUCKLE_CACHE to a writable directory to use a different cache.",
+        path.display(),
+        write_failure_reason(&err)
+    )
+}
+
+/// Where to stage downloads before they are renamed into `dir_path`: `BUCKLE_TMPDIR` if set,
+/// otherwise `dir_path` itself so that the rename is atomic.
+fn get_download_tmpdir(dir_path: &Path) -> PathBuf {
+    let Some(tmpdir) = env::var_os("BUCKLE_TMPDIR").map(PathBuf::from) else {
+        return dir_path.to_path_buf();
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let (Ok(tmp), Ok(cache)) = (tmpdir.metadata(), dir_path.metadata()) {
+            if tmp.dev() != cache.dev() {
+                eprintln!(
+                    "buckle: BUCKLE_TMPDIR {} is on a different file system to the cache, so \
+                    downloads will be copied into place rather than renamed atomically",
+                    tmpdir.display()
+                );
+            }
+        }
+    }
+    tmpdir
+}
+
+/// Create a temporary file in `tmpdir`, explaining which setting to change if that fails.
+fn create_download_tmpfile(tmpdir: &Path, dir_path: &Path) -> Result<NamedTempFile, Error> {
+    NamedTempFile::new_in(tmpdir).map_err(|err| {
+        if tmpdir == dir_path {
+            cache_write_error(dir_path, err)
+        } else {
+            anyhow!(
+                "buckle could not create a temporary file in BUCKLE_TMPDIR {}: {}",
+                tmpdir.display(),
+                write_failure_reason(&err)
+            )
+        }
+    })
+}
+
+/// Rename `tmp` to `path`, copying it across instead if it is on another file system.
+fn persist_or_copy(tmp: NamedTempFile, path: &Path) -> Result<(), Error> {
+    let tmp = match tmp.persist(path) {
+        Ok(_) => return Ok(()),
+        Err(err) => err.file,
+    };
+    let dir = path
+        .parent()
+        .ok_or(anyhow!("{} has no parent directory", path.display()))?;
+    let copy = NamedTempFile::new_in(dir)?;
+    fs::copy(tmp.path(), copy.path())?;
+    copy.as_file().sync_all()?;
+    copy.persist(path)?;
+    Ok(())
+}
+
+/// Whether `path` is a file with an execute bit set.
+#[cfg(unix)]
+fn is_executable(path: &Path) -> Result<bool, Error> {
+    let metadata = path.metadata()?;
+    let permissions = metadata.permissions();
+    Ok(metadata.is_file() && permissions.mode() & 0o111 != 0)
+}
+
+/// Why the cached buck2 at `buck2_path` could not be started. Being refused permission to run
+/// a binary that is executable almost always means its file system is mounted `noexec`, as
+/// `/home` and `/tmp` are on some hardened systems, which says so nowhere else.
+fn launch_error(buck2_path: &Path, err: io::Error) -> Error {
+    #[cfg(unix)]
+    if err.kind() == io::ErrorKind::PermissionDenied
+        && matches!(is_executable(buck2_path), Ok(true))
+    {
+        let mount = match find_noexec_mount(buck2_path) {
+            Some(mount) => format!("{} is mounted noexec", mount.display()),
+            None => "its file system is probably mounted noexec".to_string(),
+        };
+        return anyhow!(
+            "Could not run {}: {err}. It is executable, but {mount}, which forbids running \
+            programs from it. Set BUCKLE_CACHE to a directory on a file system mounted \
+            without noexec.",
+            buck2_path.display()
+        );
+    }
+    anyhow!("Could not run {}: {err}", buck2_path.display())
+}
+
+/// `BUCKLE_ASSERT_VERSION=1`: make sure the cached buck2 at `buck2_path` reports itself as
+/// buck2 `tag`, so that a stale or wrong binary in the cache is never run.
+fn assert_buck2_version(tag: &str, buck2_path: &Path) -> Result<(), Error> {
+    let output = Command::new(buck2_path)
+        .arg("--version")
+        .env_clear()
+        .envs(env::vars_os().filter(|(key, _)| !is_buckle_var(key)))
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| launch_error(buck2_path, err))?;
+    let reported = String::from_utf8_lossy(&output.stdout);
+    let reported = reported.lines().next().unwrap_or_default().trim();
+    // A release's version directory is named after the commit it was built from.
+    let dir = buck2_path.parent().unwrap_or(buck2_path);
+    let commitish = dir
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str);
+    if output.status.success() && reports_version(reported, tag, commitish) {
+        return Ok(());
+    }
+    let expected = match commitish.filter(|commitish| is_git_hash(commitish)) {
+        Some(commitish) => format!("buck2 {tag} (commit {commitish})"),
+        None => format!("buck2 {tag}"),
+    };
+    Err(BuckleError::CacheCorrupt(format!(
+        "BUCKLE_ASSERT_VERSION is set, but {} reports its version as {reported:?} rather than \
+        {expected}. Suggested fix is to remove {} to download it again",
+        buck2_path.display(),
+        dir.display()
+    ))
+    .into())
+}
+
+/// Whether `buck2 --version` output names buck2 `tag`, built from `commitish`. It prints
+/// `buck2 <version> <build id>`, the version being the commit hash, possibly abbreviated, or
+/// the release tag with the commit after it.
+fn reports_version(reported: &str, tag: &str, commitish: Option<&str>) -> bool {
+    let is_commit = |word: &str| {
+        word.len() >= 7
+            && word.chars().all(|c| c.is_ascii_hexdigit())
+            && matches!(commitish, Some(commitish) if commitish.starts_with(word) || word.starts_with(commitish))
+    };
+    reported.split_whitespace().any(|word| {
+        word == tag
+            || word.starts_with(&format!("{tag}-"))
+            || is_commit(word.rsplit('-').next().unwrap_or(word))
+    })
+}
+
+/// The mount point `path` is under, if that is mounted `noexec`. Only known on Linux.
+#[cfg(unix)]
+fn find_noexec_mount(path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    // The mount point is the fifth field and its options the sixth. The last of several
+    // mounts on the same point is the one in effect.
+    let (mount_point, options) = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').skip(4);
+            let mount_point = PathBuf::from(fields.next()?.replace("\\040", " "));
+            Some((mount_point, fields.next()?))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())?;
+    options
+        .split(',')
+        .any(|option| option == "noexec")
+        .then_some(mount_point)
+}
+
+/// Make sure the installed `path` kept its execute bits, which some SMB and NFS mounts drop
+/// over a rename, reapplying them once before giving up.
+#[cfg(unix)]
+fn ensure_executable(path: &Path) -> Result<(), Error> {
+    if is_executable(path)? {
+        return Ok(());
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    if is_executable(path)? {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{} is not executable even after setting its mode to 755. The file system it is on \
+        may not support execute permissions, as with some network mounts, so try a \
+        BUCKLE_CACHE on a local disk",
+        path.display()
+    ))
+}
+
+/// A buck2 to run instead of a release, from `BUCKLE_BUCK2_BIN`, such as one built locally.
+fn get_buck2_bin_override() -> Result<Option<PathBuf>, Error> {
+    let Some(buck2_bin) = env::var_os("BUCKLE_BUCK2_BIN").map(PathBuf::from) else {
+        return Ok(None);
+    };
+    if !buck2_bin.is_file() {
+        return Err(anyhow!(
+            "BUCKLE_BUCK2_BIN is set to {}, which is not a file",
+            buck2_bin.display()
+        ));
+    }
+    #[cfg(unix)]
+    if !is_executable(&buck2_bin)? {
+        return Err(anyhow!(
+            "BUCKLE_BUCK2_BIN is set to {}, which is not executable",
+            buck2_bin.display()
+        ));
+    }
+    Ok(Some(buck2_bin))
+}
+
+/// The tag `version` resolves to and where it is installed, found from the cache alone so that
+/// nothing is fetched.
+fn find_cached_version(version: &str) -> Result<(String, PathBuf), Error> {
+    let buckle_dir = get_buckle_dir()?;
+    if let Some(dir) = get_direct_dir(&buckle_dir, version)? {
+        if is_installed(&dir) {
+            cache::decompress(&buckle_dir, &dir)?;
+            return Ok((version.to_string(), dir));
+        }
+    }
+    let releases = read_cached_releases(&buckle_dir)?;
+    let release = resolve_release(version, &releases)?;
+    let dir = get_version_dir(&buckle_dir, release)?;
+    if !is_installed(&dir) {
+        return Err(BuckleError::VersionNotFound(format!(
+            "buck2 {} is not in the cache",
+            release.tag_name
+        ))
+        .into());
+    }
+    cache::decompress(&buckle_dir, &dir)?;
+    Ok((release.tag_name.clone(), dir))
+}
+
+/// The releases list as last fetched, however old it is.
+fn read_cached_releases(buckle_dir: &Path) -> Result<Vec<Release>, Error> {
+    read_releases_json(&buckle_dir.join("releases.json"))
+}
+
+/// Where the project's version is installed, without fetching anything.
+fn get_cached_buck2_dir() -> Result<PathBuf, Error> {
+    Ok(find_cached_version(&read_buck2_version()?)?.1)
+}
+
+/// The cached version `buckle use` runs, in place of the project's, for this invocation.
+static USED_VERSION: OnceCell<(String, PathBuf)> = OnceCell::new();
+
+/// `buckle use <version> [args]`: pick a cached version to run for this invocation only,
+/// returning the arguments for buck2.
+fn use_cached_version(args: &[OsString]) -> Result<Vec<OsString>, Error> {
+    let (version, args) = args.split_first().ok_or(anyhow!(
+        "buckle use requires a version, such as buckle use 2023-07-15"
+    ))?;
+    let version = version.to_str().ok_or(anyhow!(
+        "The version {} is not valid UTF-8",
+        version.to_string_lossy()
+    ))?;
+    let cached = find_cached_version(version).map_err(|err| {
+        anyhow!(
+            "{err}. buckle use only runs cached versions, download it first with \
+            USE_BUCK2_VERSION={version} buckle --version"
+        )
+    })?;
+    // Being cached is no reason to skip the checks a download would have had to pass.
+    let (tag, dir) = &cached;
+    if let Some(allowlist) = allowlist::get_allowlist()? {
+        allowlist.check(tag)?;
+    }
+    if let Some(manifest) = manifest::get_manifest(&get_buckle_dir()?)? {
+        manifest.verify(tag, dir)?;
+    }
+    USED_VERSION
+        .set(cached)
+        .map_err(|_| anyhow!("The version to use was already set"))?;
+    Ok(args.to_vec())
+}
+
+/// Check the name of a companion binary to run instead of buck2, `None` for buck2 itself.
+fn parse_companion_name(name: &str, source: &str) -> Result<Option<String>, Error> {
+    if name == "buck2" {
+        return Ok(None);
+    }
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(anyhow!(
+            "{source} must name a binary from the buck2 release, such as rust-project, not '{name}'"
+        ));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// The companion binary `BUCKLE_BINARY` selects, if any.
+fn get_companion_name() -> Result<Option<String>, Error> {
+    match env::var("BUCKLE_BINARY") {
+        Ok(name) => parse_companion_name(&name, "BUCKLE_BINARY"),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The companion binary `name`, such as `rust-project`, from the release buck2 `tag` came from.
+/// It is cached next to buck2 in `dir` and downloaded the first time it is needed.
+fn get_companion(tag: &str, dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    let path = dir.join(name);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let arch = get_triple()?;
+    let url = format!("{}/{tag}/{name}-{arch}.zst", get_base_url()?);
+    if env_flag("BUCKLE_DRY_RUN") {
+        eprintln!(
+            "buckle: dry run: would fetch {name}-{arch}.zst from {}",
+            auth::redact_url(&url)
+        );
+        return Ok(path);
+    }
+    if is_offline()? {
+        return Err(anyhow!(
+            "{name} from buck2 {tag} is not cached and buckle is offline"
+        ));
+    }
+    let mut resp = auth::get(&url)?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(anyhow!(
+            "buck2 {tag} has no {name} binary for {arch}: {} was not found",
+            auth::redact_url(&url)
+        ));
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "Could not fetch {}: {}",
+            auth::redact_url(&url),
+            resp.status()
+        ));
+    }
+
+    let tmpdir = get_download_tmpdir(dir);
+    let tmp = create_download_tmpfile(&tmpdir, dir)?;
+    let content_type = content_type(&resp);
+    let archive = check_zstd(&mut resp, content_type.as_deref(), &url)?;
+    zstd::stream::copy_decode(archive, tmp.as_file())
+        .map_err(|err| anyhow!("Could not decode {name} from buck2 {tag}: {err}"))?;
+    tmp.as_file().sync_all()?;
+    if let Some(verifier) = get_signature_verifier()? {
+        let signature_url = format!(
+            "{}/{tag}/{name}-{arch}{}",
+            get_base_url()?,
+            verifier.signature_suffix()
+        );
+        let resp = auth::get_ok(&signature_url)?;
+        verifier
+            .verify(tmp.path(), &resp.bytes()?)
+            .map_err(|err| anyhow!("Refusing to install {name} from buck2 {tag}: {err}"))?;
+    }
+    #[cfg(unix)]
+    fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o755))?;
+    persist_or_copy(tmp, &path)?;
+    #[cfg(unix)]
+    ensure_executable(&path)?;
+    sync_dir(dir)?;
+    Ok(path)
+}
+
+/// The tag the project's version resolves to, and the directory it is installed in.
+fn get_buck2_dir() -> Result<(String, PathBuf), Error> {
+    if let Some(used) = USED_VERSION.get() {
+        return Ok(used.clone());
+    }
+    let buckle_dir = ensure_buckle_dir()?;
+    let spec = read_version_spec()?;
+    let (version, digest) = split_digest(&spec)?;
+    let (tag, dir) = timing::time("download", || {
+        download_http(version.to_string(), digest.as_deref(), &buckle_dir)
+    })?;
+    // Versions are per project, so say which project this one came from.
+    let source = match (get_version_override(), find_buckversion()) {
+        (Some(_), _) => "USE_BUCK2_VERSION".to_string(),
+        (None, Some(path)) => path.display().to_string(),
+        (None, None) => "the default".to_string(),
+    };
+    debug_log(&format!(
+        "project root {}, version {spec} from {source}, running buck2 {tag} from {}",
+        get_buck2_project_root()
+            .map(|root| root.display().to_string())
+            .unwrap_or_else(|| "(none)".to_string()),
+        dir.display()
+    ));
+    if !env_flag("BUCKLE_DRY_RUN") {
+        if let Err(err) = cache::compress_idle(&buckle_dir, &dir) {
+            eprintln!("buckle: could not compress unused versions in the cache: {err}");
+        }
+    }
+    if let Some(manifest) = manifest::get_manifest(&buckle_dir)? {
+        // A fresh download was checked before it was installed, but a cached one may have been
+        // changed since. A dry run may not have installed anything to check.
+        if is_installed(&dir) {
+            manifest.verify(&tag, &dir)?;
+        }
+    }
+    if env_flag("BUCKLE_WRITE_LOCK") && !env_flag("BUCKLE_DRY_RUN") {
+        match get_buck2_project_root() {
+            Some(root) => {
+                if lock::write_lock(root, &buckle_dir, &tag, &dir)? {
+                    eprintln!(
+                        "buckle: locked buck2 {tag} in {}",
+                        lock::lock_path(root).display()
+                    );
+                }
+            }
+            None => {
+                eprintln!("buckle: not in a buck2 project, so there is no buckle.lock to write")
+            }
+        }
+    }
+    Ok((tag, dir))
+}
+
+/// The hash buck2 releases expect a cell's submodule to be at, if they pin one. Only the
+/// prelude is pinned, by `prelude_hash`.
+fn get_expected_cell_hash(cell: &str) -> Option<Result<&'static str, Error>> {
+    match cell {
+        "prelude" => Some(get_expected_prelude_hash()),
+        _ => None,
+    }
+}
+
+/// Warn about every pinned cell whose submodule does not match what buck2 expects.
+fn verify_cells(cells: &[(String, String)]) -> Result<(), Error> {
+    for (cell, path) in cells {
+        match get_expected_cell_hash(cell) {
+            Some(Ok(expected_hash)) => verify_cell(cell, path, expected_hash)?,
+            Some(Err(err)) => eprintln!("buckle: skipping {cell} check: {err}"),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// The outcome of comparing a cell's submodule against the hash buck2 expects.
+enum CellCheck {
+    Matches,
+    /// There was nothing to compare, such as a cell that is not a git submodule.
+    Unchecked,
+    /// The check could not be done, for the reason given.
+    Skipped(String),
+    Mismatch {
+        absolute_path: PathBuf,
+        hash: String,
+    },
+}
+
+fn check_cell(cell: &str, cell_path: &str, expected_hash: &str) -> CellCheck {
+    let Some(project_root) = get_buck2_project_root() else {
+        return CellCheck::Unchecked;
+    };
+    let mut absolute_cell_path = project_root.to_path_buf();
+    absolute_cell_path.push(cell_path);
+    let absolute_cell_path = fs::canonicalize(&absolute_cell_path).unwrap_or(absolute_cell_path);
+    let git = match vcs::Git::open(cell) {
+        Ok(git) => git,
+        Err(check) => return check,
+    };
+    let Ok(git_relative_cell_path) = absolute_cell_path.strip_prefix(git.workdir()) else {
+        return CellCheck::Skipped(format!(
+            "{}/.buckconfig indicates the {cell} should be located at {} which is not within \
+            this git repo.",
+            project_root.display(),
+            absolute_cell_path.display(),
+        ));
+    };
+    let Some(git_relative_cell_path) = git_relative_cell_path.to_str() else {
+        return CellCheck::Skipped(format!(
+            "the {cell} path {} is not valid UTF-8",
+            git_relative_cell_path.display()
+        ));
+    };
+    match vcs::check_submodule(&git, git_relative_cell_path, expected_hash) {
+        SubmoduleCheck::Matches => CellCheck::Matches,
+        SubmoduleCheck::Unchecked => {
+            debug_log(&format!("not checking the {cell}"));
+            CellCheck::Unchecked
+        }
+        SubmoduleCheck::Mismatch(hash) => CellCheck::Mismatch {
+            absolute_path: absolute_cell_path,
+            hash,
+        },
+    }
+}
+
+// Warn if the cell does not match expected, or with `BUCKLE_PRELUDE_AUTOFIX=1` move it to what
+// is expected.
+fn verify_cell(cell: &str, cell_path: &str, expected_hash: &str) -> Result<(), Error> {
+    match check_cell(cell, cell_path, expected_hash) {
+        CellCheck::Matches | CellCheck::Unchecked => Ok(()),
+        CellCheck::Skipped(reason) => {
+            eprintln!("buckle: skipping {cell} check: {reason}");
+            Ok(())
+        }
+        CellCheck::Mismatch {
+            absolute_path,
+            hash,
+        } if env_flag("BUCKLE_PRELUDE_AUTOFIX") => {
+            if let Err(err) = autofix::checkout_expected(&absolute_path, expected_hash) {
+                eprintln!("buckle: could not move the {cell} submodule to {expected_hash}: {err}");
+                return mismatched_cell_msg(cell, &absolute_path, &hash, expected_hash);
+            }
+            match check_cell(cell, cell_path, expected_hash) {
+                CellCheck::Matches => {
+                    eprintln!("buckle: moved the {cell} submodule from {hash} to {expected_hash}");
+                    Ok(())
+                }
+                _ => mismatched_cell_msg(cell, &absolute_path, &hash, expected_hash),
+            }
+        }
+        CellCheck::Mismatch {
+            absolute_path,
+            hash,
+        } => mismatched_cell_msg(cell, &absolute_path, &hash, expected_hash),
+    }
+}
+
+/// `buckle url [version]`: print where buck2 and its prelude_hash would be downloaded from for
+/// `version`, or the project's version, without fetching either.
+fn print_download_urls(args: &[OsString]) -> Result<(), Error> {
+    let version = match args {
+        [] => read_buck2_version()?,
+        [version] => version
+            .to_str()
+            .ok_or(anyhow!(
+                "The version {} is not valid UTF-8",
+                version.to_string_lossy()
+            ))?
+            .to_string(),
+        _ => return Err(anyhow!("Usage: buckle url [version]")),
+    };
+    validate_version(&version)?;
+    let buckle_dir = ensure_buckle_dir()?;
+    // A direct download fetches the tag as given, without looking it up.
+    let tag = match get_direct_dir(&buckle_dir, &version)? {
+        Some(_) => version,
+        None => {
+            let releases = get_releases_for(&buckle_dir, Some(&version))?;
+            let resolved = resolve_release(&version, &releases)?;
+            arch_fallback(resolved, &releases, &buckle_dir)?
+                .tag_name
+                .clone()
+        }
+    };
+    let base_url = get_base_url()?;
+    println!(
+        "{}",
+        auth::redact_url(&format!("{base_url}/{tag}/buck2-{}.zst", get_triple()?))
+    );
+    println!(
+        "{}",
+        auth::redact_url(&format!("{base_url}/{tag}/prelude_hash"))
+    );
+    Ok(())
+}
+
+/// `buckle version-info [--json] <version>`: describe the release `version` resolves to, from
+/// the cached releases list while it is fresh.
+fn print_version_info(args: &[OsString]) -> Result<(), Error> {
+    let usage = || anyhow!("Usage: buckle version-info [--json] <version>");
+    let mut json = false;
+    let mut version = None;
+    for arg in args {
+        match arg.to_str() {
+            Some("--json") => json = true,
+            Some(arg) if version.is_none() && !arg.starts_with('-') => version = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let version = version.ok_or_else(usage)?;
+    let buckle_dir = ensure_buckle_dir()?;
+    let releases = get_releases_for(&buckle_dir, Some(version))?;
+    let release = resolve_release(version, &releases)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(release)?);
+        return Ok(());
+    }
+    let names = asset_names(release);
+    println!("tag: {}", release.tag_name);
+    println!(
+        "published: {}",
+        release
+            .published_at
+            .as_deref()
+            .or(release.created_at.as_deref())
+            .unwrap_or("unknown")
+    );
+    println!(
+        "prerelease: {}",
+        if release.prerelease { "yes" } else { "no" }
+    );
+    if names.is_empty() {
+        println!("assets: none listed");
+    } else {
+        println!("assets: {}", names.join(", "));
+    }
+    if let Some(body) = release
+        .body
+        .as_deref()
+        .filter(|body| !body.trim().is_empty())
+    {
+        println!();
+        println!("{}", body.trim_end());
+    }
+    Ok(())
+}
+
+/// `buckle bin-dir`: print the directory of the project's buck2, downloading it if needed, for
+/// putting on `PATH`.
+fn print_bin_dir() -> Result<(), Error> {
+    let (tag, dir) = get_buck2_dir()?;
+    if !is_installed(&dir) {
+        return Err(anyhow!("buck2 {tag} is not installed at {}", dir.display()));
+    }
+    println!("{}", dir.display());
+    Ok(())
+}
+
+/// `buckle cache-dir`: print the directory buckle keeps its cache in, as an absolute path,
+/// without creating it.
+fn print_cache_dir() -> Result<(), Error> {
+    let buckle_dir = get_buckle_dir()?;
+    let buckle_dir = if buckle_dir.is_absolute() {
+        buckle_dir
+    } else {
+        env::current_dir()?.join(buckle_dir)
+    };
+    println!("{}", buckle_dir.display());
+    Ok(())
+}
+
+/// `buckle refresh`: fetch the releases list now rather than waiting for the cached one to
+/// expire.
+fn refresh_releases() -> Result<(), Error> {
+    if is_offline()? {
+        return Err(anyhow!(
+            "buckle refresh needs the network to fetch the releases list, but buckle is offline"
+        ));
+    }
+    let releases = fetch_releases(&ensure_buckle_dir()?.join("releases.json"), false, None)?;
+    let published = releases
+        .iter()
+        .filter(|release| !release.draft && release.tag_name != "latest");
+    match newest_release(published) {
+        Some(newest) => println!(
+            "Fetched {} releases, the newest is {}",
+            releases.len(),
+            newest.tag_name
+        ),
+        None => println!("Fetched {} releases", releases.len()),
+    }
+    Ok(())
+}
+
+/// `buckle prelude-hash`: print the prelude hash the version expects, then the hash of the
+/// project's prelude submodule if it can be found.
+fn print_prelude_hash() -> Result<(), Error> {
+    let expected_hash = get_expected_prelude_hash()?;
+    println!("{expected_hash}");
+    let Some((_, path)) = get_cells().into_iter().find(|(cell, _)| cell == "prelude") else {
+        return Ok(());
+    };
+    match check_cell("prelude", &path, expected_hash) {
+        CellCheck::Matches => println!("actual: {expected_hash}"),
+        CellCheck::Mismatch { hash, .. } => println!("actual: {hash}"),
+        CellCheck::Unchecked => {}
+        CellCheck::Skipped(reason) => {
+            eprintln!("buckle: could not find the prelude hash: {reason}")
+        }
+    }
+    Ok(())
+}
+
+/// Notify user of a cell mismatch and suggest solution.
+// TODO make this much better
+fn mismatched_cell_msg(
+    cell: &str,
+    absolute_cell_path: &Path,
+    cell_hash: &str,
+    expected_hash: &str,
+) -> Result<(), Error> {
+    eprintln!(
+        "buckle: Git submodule for {cell} ({cell_hash}) is not the expected {expected_hash}."
+    );
+    let abs_path = absolute_cell_path.display();
+    eprintln!("buckle: cd {abs_path} && git fetch && git checkout {expected_hash}");
+    if is_prelude_mismatch_error() {
+        return Err(BuckleError::PreludeMismatch(format!(
+            "The {cell} submodule is at {cell_hash} instead of {expected_hash}, and \
+            BUCKLE_PRELUDE_CHECK=ERROR"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Whether an environment variable is buckle configuration rather than something for buck2.
+fn is_buckle_var(key: &OsStr) -> bool {
+    key.to_str()
+        .map(|key| key.starts_with("BUCKLE_") || key == "USE_BUCK2_VERSION")
+        .unwrap_or(false)
+}
+
+fn prelude_check_enabled() -> Result<bool, Error> {
+    match env::var("BUCKLE_PRELUDE_CHECK") {
+        Ok(var) => Ok(var.to_uppercase() != "NO"),
+        Err(_) => Ok(get_config()?.prelude_check.unwrap_or(true)),
+    }
+}
+
+/// Whether a mismatched cell fails the run, with `BUCKLE_PRELUDE_CHECK=ERROR`, rather than only
+/// warning.
+fn is_prelude_mismatch_error() -> bool {
+    env::var("BUCKLE_PRELUDE_CHECK")
+        .map(|var| var.to_uppercase() == "ERROR")
+        .unwrap_or(false)
+}
+
+/// Whether a `.buckconfig` buckle can't parse should be reported, with
+/// `BUCKLE_PRELUDE_CHECK=STRICT` or `BUCKLE_STRICT_CONFIG=1`, rather than left for buck2.
+fn is_strict_config() -> bool {
+    let strict_check = env::var("BUCKLE_PRELUDE_CHECK")
+        .map(|var| var.to_uppercase() == "STRICT")
+        .unwrap_or(false);
+    strict_check || env_flag("BUCKLE_STRICT_CONFIG")
+}
+
+/// The cells configured in the project's .buckconfig as `(name, path)`, from both the `[cells]`
+/// section and the older `[repositories]` one.
+fn get_cells() -> Vec<(String, String)> {
+    // If we can't find the project root, just skip checking the cells and call the buck2 binary
+    let Some(root) = get_buck2_project_root() else {
+        return vec![];
+    };
+    // If we fail to parse the ini file, don't throw an error. We can't parse it for
+    // some reason, so we should fall back on buck2 to throw a better error.
+    let buck2config: PathBuf = [root, Path::new(".buckconfig")].iter().collect();
+    let ini = match Ini::load_from_file(&buck2config) {
+        Ok(ini) => ini,
+        Err(err) => {
+            if is_strict_config() {
+                eprintln!(
+                    "buckle: could not parse {}, so no cells were checked: {err}",
+                    buck2config.display()
+                );
+            }
+            return vec![];
+        }
+    };
+    let mut cells: Vec<(String, String)> = vec![];
+    for section in ["cells", "repositories"] {
+        for (cell, path) in ini
+            .section(Some(section))
+            .into_iter()
+            .flat_map(|s| s.iter())
+        {
+            if !cells.iter().any(|(known, _)| known == cell) {
+                cells.push((cell.to_string(), path.to_string()));
+            }
+        }
+    }
+    cells
+}
+
+/// Split a command line into words, honoring single and double quotes and backslash escapes
+/// the way a POSIX shell would, but without any expansion.
+fn split_command_line(line: &str) -> Result<Vec<String>, Error> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("Unterminated ' in {line:?}")),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(anyhow!("Unterminated \" in {line:?}")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("Unterminated \" in {line:?}")),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(anyhow!("Trailing \\ in {line:?}")),
+            },
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// The command buck2 should be run under, from `BUCKLE_EXEC_WRAPPER`.
+fn get_exec_wrapper() -> Result<Option<Vec<String>>, Error> {
+    match env::var("BUCKLE_EXEC_WRAPPER") {
+        Ok(line) => {
+            let wrapper = split_command_line(&line)
+                .map_err(|err| anyhow!("BUCKLE_EXEC_WRAPPER could not be parsed: {err}"))?;
+            Ok(Some(wrapper).filter(|wrapper| !wrapper.is_empty()))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Default arguments for every buck2 invocation, from `BUCKLE_BUCK2_ARGS`. They go before the
+/// user's own arguments, so they are buck2's global options such as `--isolation-dir`.
+fn get_default_buck2_args() -> Result<Vec<String>, Error> {
+    match env::var("BUCKLE_BUCK2_ARGS") {
+        Ok(line) => split_command_line(&line)
+            .map_err(|err| anyhow!("BUCKLE_BUCK2_ARGS could not be parsed: {err}")),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// The command line, split into buckle's own flags and the arguments intended for buck2.
+struct BuckleArgs {
+    root: Option<PathBuf>,
+    /// `--buckle-env`: describe the effective configuration instead of running buck2.
+    env: bool,
+    /// `--buckle-help`: describe buckle's own commands instead of running buck2.
+    help: bool,
+    buck2_args: Vec<OsString>,
+}
+
+impl BuckleArgs {
+    fn parse(args: impl Iterator<Item = OsString>) -> Result<Self, Error> {
+        let mut root = None;
+        let mut env = false;
+        let mut help = false;
+        let mut buck2_args = vec![];
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                // Everything after `--` belongs to buck2 or whatever it runs.
+                Some("--") => {
+                    buck2_args.push(arg);
+                    buck2_args.extend(args.by_ref());
+                }
+                Some("--buckle-root") => {
+                    let value = args
+                        .next()
+                        .ok_or(anyhow!("--buckle-root requires a path"))?;
+                    root = Some(PathBuf::from(value));
+                }
+                Some(flag) if flag.starts_with("--buckle-root=") => {
+                    root = Some(PathBuf::from(&flag["--buckle-root=".len()..]));
+                }
+                Some("--buckle-env") => env = true,
+                Some("--buckle-help") => help = true,
+                _ => buck2_args.push(arg),
+            }
+        }
+        Ok(BuckleArgs {
+            root,
+            env,
+            help,
+            buck2_args,
+        })
+    }
+}
+
+const BUCKLE_HELP: &str = "\
+buckle: a launcher for buck2. Any arguments it doesn't recognise are passed to buck2.
+
+Usage: buckle [buckle flags] [buck2 arguments]
+
+Commands:
+  bin-dir               Print the directory holding the project's buck2
+  cache-dir             Print the directory buckle keeps its cache in
+  doctor [--verify-cache]
+                        Check buckle's setup and report what needs fixing
+  prelude-hash          Print the prelude hash the buck2 version expects
+  refresh               Fetch the releases list now
+  upgrade [--to <tag>]  Update .buckversion to a newer buck2
+  url [version]         Print the download URLs for a buck2 version
+  use <version> [args]  Run a cached buck2 version instead of the project's
+  version-info <version>
+                        Print a release's date, assets and notes
+  warm [--arch <triple>]... [--version <version>]
+                        Download buck2 for several platforms into the cache
+
+Flags:
+  --buckle-env          Print the effective configuration
+  --buckle-help         Print this help
+  --buckle-root <path>  Use <path> as the project root
+
+Environment variables are described at https://github.com/benbrittain/buckle.";
+
+/// The `buckle` command line.
+pub fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {err:?}");
+        std::process::exit(error::exit_code(&err));
+    }
+}
+
+fn run() -> Result<(), Error> {
+    // An escape hatch for when buckle itself is the problem, so it reads nothing else first.
+    if env_flag("BUCKLE_PASSTHROUGH") {
+        return run_passthrough();
+    }
+    timing::start();
+    fds::record_inherited();
+    // Surface a broken config file up front rather than whenever a setting is first needed.
+    get_config()?;
+    let buckle_args = BuckleArgs::parse(env::args_os().skip(1))?;
+    if let Some(root) = buckle_args
+        .root
+        .clone()
+        .or_else(|| env::var_os("BUCKLE_ROOT").map(PathBuf::from))
+    {
+        set_project_root_override(&root)?;
+    }
+
+    if buckle_args.env {
+        return env_dump::print_buckle_env();
+    }
+    if buckle_args.help {
+        println!("{BUCKLE_HELP}");
+        return Ok(());
+    }
+
+    // Buckle's own subcommands, which never run buck2.
+    let (subcommand, subcommand_args) = match buckle_args.buck2_args.split_first() {
+        Some((subcommand, args)) => (subcommand.to_str(), args),
+        None => (None, &[][..]),
+    };
+    let companion = get_companion_name()?;
+    let used_args = match subcommand {
+        Some("bin-dir") => return print_bin_dir(),
+        Some("cache-dir") => return print_cache_dir(),
+        Some("doctor") => return doctor::doctor(subcommand_args),
+        Some("prelude-hash") => return print_prelude_hash(),
+        Some("refresh") => return refresh_releases(),
+        Some("upgrade") => return upgrade::upgrade(subcommand_args),
+        Some("url") => return print_download_urls(subcommand_args),
+        Some("version-info") => return print_version_info(subcommand_args),
+        Some("warm") => return warm::warm(subcommand_args),
+        Some("use") => Some(use_cached_version(subcommand_args)?),
+        _ => None,
+    };
+    // `buckle use` picks a released version, and companions only come from releases, so both
+    // take precedence over a local buck2.
+    let buck2_bin_override = match (&used_args, &companion) {
+        (None, None) => get_buck2_bin_override()?,
+        _ => None,
+    };
+    // Held until buck2 exits, so that no other buckle compresses it in the meantime.
+    let mut _held: Option<File> = None;
+    let (tag, buck2_path) = match &buck2_bin_override {
+        Some(buck2_bin) => (None, buck2_bin.clone()),
+        None => {
+            let (tag, dir) = get_buck2_dir()?;
+            if !env_flag("BUCKLE_DRY_RUN") {
+                _held = cache::hold_version(&get_buckle_dir()?, &dir)?;
+            }
+            let path = match &companion {
+                Some(name) => get_companion(&tag, &dir, name)?,
+                None => dir.join("buck2"),
+            };
+            (Some(tag), path)
+        }
+    };
+    // The prelude and buck2's own arguments mean nothing to a companion binary.
+    let runs_buck2 = companion.is_none();
+    if env_flag("BUCKLE_DRY_RUN") {
+        let cells = if runs_buck2 && prelude_check_enabled()? {
+            get_cells()
+        } else {
+            vec![]
+        };
+        let checked: Vec<_> = cells
+            .iter()
+            .filter(|(cell, _)| get_expected_cell_hash(cell).is_some())
+            .collect();
+        if checked.is_empty() {
+            eprintln!("buckle: dry run: would not verify the prelude");
+        }
+        for (cell, path) in checked {
+            eprintln!("buckle: dry run: would verify the {cell} cell at {path}");
+        }
+        match get_exec_wrapper()? {
+            Some(wrapper) => eprintln!(
+                "buckle: dry run: would run {} under {}",
+                buck2_path.display(),
+                wrapper.join(" ")
+            ),
+            None => eprintln!("buckle: dry run: would run {}", buck2_path.display()),
+        }
+        return Ok(());
+    }
+
+    if let Some(tag) = &tag {
+        if !buck2_path.exists() {
+            return Err(BuckleError::CacheCorrupt(format!(
+                "The buckle cache is corrupted: buck2 {tag} should be at {}, but it is missing. \
+                Suggested fix is to remove {}",
+                buck2_path.display(),
+                get_buckle_dir()?.display()
+            ))
+            .into());
+        }
+
+        // mode() is only available on unix systems
+        #[cfg(unix)]
+        if !is_executable(&buck2_path)? {
+            return Err(BuckleError::CacheCorrupt(format!(
+                "The buckle cache is corrupted: buck2 {tag} at {} is not executable. \
+                Suggested fix is to remove {}",
+                buck2_path.display(),
+                get_buckle_dir()?.display()
+            ))
+            .into());
+        }
+
+        if runs_buck2 {
+            if env_flag("BUCKLE_ASSERT_VERSION") {
+                assert_buck2_version(tag, &buck2_path)?;
+            }
+            if let Err(err) = daemon::kill_stale_daemon(&get_buckle_dir()?, tag, &buck2_path) {
+                eprintln!("buckle: could not record which buck2 this project ran: {err}");
+            }
+        }
+    }
+
+    // Bare `buckle` shows buck2's help, which doesn't mention that buckle is in the way.
+    if runs_buck2 && buckle_args.buck2_args.is_empty() && !env_flag("BUCKLE_QUIET") {
+        let buck2 = match &tag {
+            Some(tag) => format!("buck2 {tag}"),
+            None => format!("{} (BUCKLE_BUCK2_BIN)", buck2_path.display()),
+        };
+        eprintln!("buckle: running {buck2}, see `buckle --buckle-help` for buckle's own commands");
+    }
+
+    if buck2_bin_override.is_none() && runs_buck2 && env_flag("BUCKLE_VERIFY_ON_RUN") {
+        verify_installed_binary(&buck2_path)?;
+    }
+
+    // Only read the .buckconfig when the check is on, so a disabled check costs nothing.
+    if runs_buck2 && prelude_check_enabled()? {
+        timing::time("prelude check", || verify_cells(&get_cells()))?;
+    }
+
+    // Collect information indented for buck2 binary.
+    let default_args = if runs_buck2 {
+        get_default_buck2_args()?
+    } else {
+        vec![]
+    };
+    let args = used_args.unwrap_or(buckle_args.buck2_args);
+    // Buckle's own configuration means nothing to buck2, so keep it out of build actions.
+    let keep_env = env_flag("BUCKLE_KEEP_ENV");
+    let envs = env::vars_os().filter(|(key, _)| keep_env || !is_buckle_var(key));
+
+    let exec_wrapper = get_exec_wrapper()?;
+    let (program, mut command) = match &exec_wrapper {
+        Some(wrapper) => {
+            let mut command = Command::new(&wrapper[0]);
+            command.args(&wrapper[1..]).arg(&buck2_path);
+            (wrapper[0].clone(), command)
+        }
+        None => (buck2_path.display().to_string(), Command::new(&buck2_path)),
+    };
+
+    timing::report();
+    // The standard streams are inherited explicitly, and any other descriptor buckle was
+    // started with, such as a pipe on FD 3, is kept open for buck2.
+    fds::keep_inherited_open();
+    // Streams that are also logged go through a pipe, and are copied on to the console.
+    let log_stdout = tee::open_log("BUCKLE_LOG_STDOUT")?;
+    let log_stderr = tee::open_log("BUCKLE_LOG_STDERR")?;
+    let stream = |log: &Option<File>| match log {
+        Some(_) => Stdio::piped(),
+        None => Stdio::inherit(),
+    };
+    let mut child = command
+        .args(default_args)
+        .args(args)
+        .env_clear()
+        .envs(envs)
+        .stdin(Stdio::inherit())
+        .stdout(stream(&log_stdout))
+        .stderr(stream(&log_stderr))
+        .spawn()
+        .map_err(|err| match (&tag, &exec_wrapper) {
+            (Some(_), None) => launch_error(&buck2_path, err),
+            _ => anyhow!("Could not run {program}: {err}"),
+        })?;
+    let tees = [
+        child
+            .stdout
+            .take()
+            .zip(log_stdout)
+            .map(|(from, log)| tee::spawn(from, io::stdout(), log)),
+        child
+            .stderr
+            .take()
+            .zip(log_stderr)
+            .map(|(from, log)| tee::spawn(from, io::stderr(), log)),
+    ];
+    let status = child.wait()?;
+    for tee in tees.into_iter().flatten() {
+        if let Ok(Err(err)) = tee.join() {
+            eprintln!("buckle: could not write buck2's output to its log: {err}");
+        }
+    }
+
+    exit_on_failure(status);
+    Ok(())
+}
+
+/// Exit the way buck2 did if it failed.
+fn exit_on_failure(status: std::process::ExitStatus) {
+    if !status.success() {
+        // Mirror the shell convention for a child killed by a signal.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                std::process::exit(128 + signal);
+            }
+        }
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// The first `buck2` on `PATH` that isn't buckle itself, as when buckle is installed as
+/// `buck2`.
+fn find_buck2_on_path() -> Option<PathBuf> {
+    let this = env::current_exe()
+        .ok()
+        .and_then(|exe| fs::canonicalize(exe).ok());
+    let name = format!("buck2{}", env::consts::EXE_SUFFIX);
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .filter(|candidate| candidate.is_file())
+        .find(|candidate| this.is_none() || fs::canonicalize(candidate).ok() != this)
+}
+
+/// `BUCKLE_PASSTHROUGH=1`: run the system buck2 from `PATH` with the arguments and environment
+/// exactly as given, skipping everything else buckle does.
+fn run_passthrough() -> Result<(), Error> {
+    let buck2 = find_buck2_on_path().ok_or_else(|| {
+        anyhow!("BUCKLE_PASSTHROUGH is set, but there is no buck2 on PATH other than buckle")
+    })?;
+    let status = Command::new(&buck2)
+        .args(env::args_os().skip(1))
+        .status()
+        .map_err(|err| anyhow!("Could not run {}: {err}", buck2.display()))?;
+    exit_on_failure(status);
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! `buckle.lock`: record exactly which buck2 a project's moving version resolved to.
+//!
+//! A project that follows `latest` can commit its lock so that every checkout runs the same
+//! buck2, until `buckle upgrade` refreshes it.
+
+use crate::{
+    download_http, ensure_buckle_dir, find_tag, get_buck2_project_root, get_releases_for,
+    get_triple, installed_digest, read_cached_releases, read_prelude_hash, resolve_release,
+    write_file_atomically, Release, ASSET_DIGEST_NAME,
+};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Lock {
+    pub tag_name: String,
+    /// Unknown for a version downloaded without the releases list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_commitish: Option<String>,
+    /// The SHA256 of the `buck2-<triple>.zst` release asset, as GitHub publishes it. Unknown
+    /// for a version cached before buckle recorded it, when the release doesn't list it either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The SHA256 of the buck2 binary, as in a `.buckversion` digest pin.
+    pub binary_sha256: String,
+    pub prelude_hash: String,
+}
+
+pub fn lock_path(root: &Path) -> PathBuf {
+    root.join("buckle.lock")
+}
+
+fn parse_lock(path: &Path) -> Result<Option<Lock>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(anyhow!("Could not read {}: {err}", path.display())),
+    };
+    toml::from_str(&contents)
+        .map(Some)
+        .map_err(|err| anyhow!("{} is not a valid buckle lock: {err}", path.display()))
+}
+
+/// The current project's lock, if it has one.
+pub fn read_lock() -> Result<Option<Lock>, Error> {
+    match get_buck2_project_root() {
+        Some(root) => parse_lock(&lock_path(root)),
+        None => Ok(None),
+    }
+}
+
+/// The SHA256 of the release asset `dir` was installed from: as recorded when it was
+/// downloaded, or as the releases list publishes it.
+fn asset_digest(dir: &Path, release: Option<&Release>) -> Result<Option<String>, Error> {
+    if let Ok(digest) = fs::read_to_string(dir.join(ASSET_DIGEST_NAME)) {
+        return Ok(Some(digest.trim().to_string()));
+    }
+    let name = format!("buck2-{}.zst", get_triple()?);
+    Ok(release.and_then(|release| {
+        let asset = release
+            .assets
+            .iter()
+            .find(|asset| asset.get("name").and_then(|name| name.as_str()) == Some(&name))?;
+        let digest = asset.get("digest")?.as_str()?.strip_prefix("sha256:")?;
+        Some(digest.to_string())
+    }))
+}
+
+/// Lock the project at `root` to `tag`, installed in `dir`. Returns whether the lock changed.
+pub fn write_lock(root: &Path, buckle_dir: &Path, tag: &str, dir: &Path) -> Result<bool, Error> {
+    let releases = read_cached_releases(buckle_dir).ok();
+    let release = releases
+        .as_deref()
+        .and_then(|releases| find_tag(releases, tag));
+    let sha256 = asset_digest(dir, release)?;
+    if sha256.is_none() {
+        eprintln!(
+            "buckle: the SHA256 of the buck2 {tag} release asset is unknown, download it again \
+            to record it in the lock"
+        );
+    }
+    let lock = Lock {
+        tag_name: tag.to_string(),
+        target_commitish: release.map(|release| release.target_commitish.clone()),
+        sha256,
+        binary_sha256: installed_digest(&dir.join("buck2"))?,
+        prelude_hash: read_prelude_hash(&dir.join("prelude_hash"))?,
+    };
+    let path = lock_path(root);
+    if matches!(parse_lock(&path), Ok(Some(existing)) if existing == lock) {
+        return Ok(false);
+    }
+    let contents = format!(
+        "# Written by buckle. Refresh it with `buckle upgrade`.\n{}",
+        toml::to_string(&lock)?
+    );
+    write_file_atomically(&path, contents.as_bytes())?;
+    Ok(true)
+}
+
+/// `buckle upgrade` in a locked project: lock it to whatever `version` resolves to now,
+/// downloading it to record its hashes.
+pub fn refresh_lock(root: &Path, version: &str, dry_run: bool) -> Result<(), Error> {
+    let path = lock_path(root);
+    let before = parse_lock(&path)?.map(|lock| lock.tag_name);
+    let buckle_dir = ensure_buckle_dir()?;
+    let releases = get_releases_for(&buckle_dir, Some(version))?;
+    let after = resolve_release(version, &releases)?.tag_name.clone();
+
+    println!("{} -> {after}", before.as_deref().unwrap_or("(unlocked)"));
+    if before.as_deref() == Some(after.as_str()) {
+        return Ok(());
+    }
+    if dry_run {
+        println!("Would update {}", path.display());
+        return Ok(());
+    }
+    let (tag, dir) = download_http(after, None, &buckle_dir)?;
+    write_lock(root, &buckle_dir, &tag, &dir)?;
+    println!("Updated {}", path.display());
+    Ok(())
+}
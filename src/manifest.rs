@@ -0,0 +1,166 @@
+//! `BUCKLE_MANIFEST_URL`: an organization's record of the buck2 releases it approves.
+//!
+//! The manifest maps each approved tag to the SHA256 of its binary and its prelude hash, as
+//! TOML or JSON. Every buck2 is checked against it before it is installed and again before it
+//! runs, so a release that was retagged or tampered with upstream is refused even when it
+//! matches GitHub's own checksum.
+//!
+//! ```toml
+//! ["2023-07-15"]
+//! sha256 = "…"
+//! prelude_hash = "…"
+//! ```
+
+use crate::{
+    auth, get_releases_ttl_secs, hash_binary, is_offline, read_prelude_hash, write_file_atomically,
+};
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    sha256: String,
+    prelude_hash: String,
+}
+
+pub struct Manifest {
+    entries: BTreeMap<String, Entry>,
+    /// Where the manifest came from, for errors.
+    source: String,
+}
+
+fn is_remote(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Fetched manifests are cached under `<buckle>/manifests`, keyed by their URL.
+fn get_cache_path(buckle_dir: &Path, url: &str) -> PathBuf {
+    let key: String = Sha256::digest(url.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    buckle_dir.join("manifests").join(key)
+}
+
+fn is_fresh(path: &Path) -> bool {
+    let age = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok());
+    matches!(age, Some(age) if age.as_secs() < get_releases_ttl_secs())
+}
+
+/// The manifest at `url`, from the cache while it is as fresh as the releases list would be.
+/// A stale copy is used if it can't be fetched again.
+fn fetch(buckle_dir: &Path, url: &str) -> Result<String, Error> {
+    let path = get_cache_path(buckle_dir, url);
+    if is_fresh(&path) || (path.exists() && is_offline()?) {
+        return Ok(fs::read_to_string(&path)?);
+    }
+    if is_offline()? {
+        return Err(anyhow!(
+            "buckle is offline and the manifest {} is not cached",
+            auth::redact_url(url)
+        ));
+    }
+    let fetched = auth::get_ok(url).and_then(|resp| Ok(resp.text()?));
+    match fetched {
+        Ok(contents) => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            write_file_atomically(&path, contents.as_bytes())?;
+            Ok(contents)
+        }
+        Err(err) if path.exists() => {
+            eprintln!("buckle: using the cached manifest, as it could not be fetched: {err}");
+            Ok(fs::read_to_string(&path)?)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn parse(contents: &str, source: &str) -> Result<BTreeMap<String, Entry>, Error> {
+    if contents.trim_start().starts_with('{') {
+        serde_json::from_str(contents)
+            .map_err(|err| anyhow!("The manifest {source} is not valid JSON: {err}"))
+    } else {
+        toml::from_str(contents)
+            .map_err(|err| anyhow!("The manifest {source} is not valid TOML: {err}"))
+    }
+}
+
+/// The manifest named by `BUCKLE_MANIFEST_URL`, a URL or a local path, if one is set.
+pub fn get_manifest(buckle_dir: &Path) -> Result<Option<Manifest>, Error> {
+    let Ok(location) = env::var("BUCKLE_MANIFEST_URL") else {
+        return Ok(None);
+    };
+    let (contents, source) = if is_remote(&location) {
+        (fetch(buckle_dir, &location)?, auth::redact_url(&location))
+    } else {
+        let path = location.strip_prefix("file://").unwrap_or(&location);
+        let contents = fs::read_to_string(path)
+            .map_err(|err| anyhow!("Could not read the manifest {path}: {err}"))?;
+        (contents, path.to_string())
+    };
+    Ok(Some(Manifest {
+        entries: parse(&contents, &source)?,
+        source,
+    }))
+}
+
+impl Manifest {
+    /// The manifest's entry for `tag`, failing if it approves no such release.
+    fn entry(&self, tag: &str) -> Result<&Entry, Error> {
+        self.entries.get(tag).ok_or_else(|| {
+            anyhow!(
+                "Refusing to run buck2 {tag}: it is not in the manifest {}",
+                self.source
+            )
+        })
+    }
+
+    /// Fail unless the manifest lists buck2 `tag` at all, before anything is downloaded.
+    pub fn check_tag(&self, tag: &str) -> Result<(), Error> {
+        self.entry(tag).map(|_| ())
+    }
+
+    /// Fail unless buck2 `tag`, whose binary hashes to `digest` and which expects
+    /// `prelude_hash`, is exactly what the manifest approves.
+    pub fn check(&self, tag: &str, digest: &str, prelude_hash: &str) -> Result<(), Error> {
+        let entry = self.entry(tag)?;
+        if !digest.eq_ignore_ascii_case(entry.sha256.trim()) {
+            return Err(anyhow!(
+                "Refusing to run buck2 {tag}: its SHA256 is {digest}, but the manifest {} \
+                expects {}",
+                self.source,
+                entry.sha256.trim()
+            ));
+        }
+        if prelude_hash != entry.prelude_hash.trim() {
+            return Err(anyhow!(
+                "Refusing to run buck2 {tag}: its prelude_hash is {prelude_hash}, but the \
+                manifest {} expects {}",
+                self.source,
+                entry.prelude_hash.trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// [`Manifest::check`] buck2 `tag` installed in `dir`. The binary itself is hashed, as the
+    /// `buck2.sha256` beside it is no more trustworthy than the binary.
+    pub fn verify(&self, tag: &str, dir: &Path) -> Result<(), Error> {
+        self.check_tag(tag)?;
+        let digest = hash_binary(&dir.join("buck2"))?;
+        self.check(tag, &digest, &read_prelude_hash(&dir.join("prelude_hash"))?)
+    }
+}
@@ -0,0 +1,70 @@
+//! Remember versions that are not buck2 releases for a little while.
+//!
+//! A typo in `.buckversion` would otherwise refetch the releases list on every invocation,
+//! which in a CI retry loop uses up the GitHub rate limit fast. Only a definitive "not found"
+//! is remembered, never a network failure.
+
+use crate::{env_flag, error::BuckleError, get_base_url, get_releases_url, write_file_atomically};
+use anyhow::Error;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How long a missing version is remembered for.
+const NOT_FOUND_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The marker for `version` looked up from the current releases list and base URL, so that
+/// pointing buckle at another mirror looks again.
+fn get_marker_path(buckle_dir: &Path, version: &str) -> Result<PathBuf, Error> {
+    let key = format!("{version}\n{}\n{}", get_releases_url()?, get_base_url()?);
+    let key: String = Sha256::digest(key.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok(buckle_dir.join("not-found").join(key))
+}
+
+/// Fail with the remembered error if `version` was not found moments ago.
+pub fn check(buckle_dir: &Path, version: &str) -> Result<(), Error> {
+    let path = get_marker_path(buckle_dir, version)?;
+    let Some(age) = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+    else {
+        return Ok(());
+    };
+    if age >= NOT_FOUND_TTL {
+        let _ = fs::remove_file(&path);
+        return Ok(());
+    }
+    let message = fs::read_to_string(&path).unwrap_or_default();
+    Err(BuckleError::VersionNotFound(format!(
+        "{message} This was found {}s ago, remove {} to look again sooner.",
+        age.as_secs(),
+        path.display()
+    ))
+    .into())
+}
+
+/// Remember that `version` was not found, if that is what `err` says.
+pub fn remember(buckle_dir: &Path, version: &str, err: &Error) {
+    let Some(BuckleError::VersionNotFound(message)) = err.downcast_ref::<BuckleError>() else {
+        return;
+    };
+    if env_flag("BUCKLE_DRY_RUN") {
+        return;
+    }
+    let Ok(path) = get_marker_path(buckle_dir, version) else {
+        return;
+    };
+    // Like the session, this is a nicety that a read-only cache must not break.
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = write_file_atomically(&path, message.as_bytes());
+}
@@ -0,0 +1,132 @@
+//! Keep moving versions such as `latest` resolving to the same release for a while.
+//!
+//! Without this, a release published part way through a build can make two buckle invocations
+//! in the same session run different buck2s. The first resolution of an alias is recorded per
+//! project root and reused for `BUCKLE_RELEASES_TTL_SECS`.
+
+use crate::{
+    env_flag, get_buck2_project_root, get_releases_ttl_secs, get_version_dir, is_installed,
+    resolve_release, write_file_atomically, Release,
+};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Versions whose meaning changes as buck2 publishes releases.
+const MOVING_VERSIONS: &[&str] = &["latest", "stable", "nightly", "prerelease"];
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    version: String,
+    release: Release,
+}
+
+/// Whether `version` is an alias or moving tag rather than a fixed release.
+pub fn is_moving(version: &str) -> bool {
+    MOVING_VERSIONS.contains(&version)
+}
+
+/// Forget what moving versions resolved to for the current project.
+pub fn end_session(buckle_dir: &Path) {
+    let _ = fs::remove_file(get_session_path(buckle_dir));
+}
+
+/// The session marker for the current project, or for invocations outside of any project.
+fn get_session_path(buckle_dir: &Path) -> PathBuf {
+    buckle_dir.join("sessions").join(project_key())
+}
+
+/// A short name for the current project root, or for being outside of any project, to key
+/// per-project state in the cache by.
+pub fn project_key() -> String {
+    let root = get_buck2_project_root()
+        .map(|root| root.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Sha256::digest(root.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The release recorded for `version`, if it is recent and still installed.
+fn read_session(path: &Path, version: &str, buckle_dir: &Path) -> Option<Release> {
+    let age = fs::metadata(path)
+        .ok()?
+        .modified()
+        .ok()?
+        .elapsed()
+        .unwrap_or(Duration::ZERO);
+    if age.as_secs() >= get_releases_ttl_secs() {
+        return None;
+    }
+    let session: Session = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    // Only reuse a release that need not be downloaded again, as a moving tag like `latest`
+    // would fetch whatever it points at now.
+    let installed = is_installed(&get_version_dir(buckle_dir, &session.release).ok()?);
+    (session.version == version && installed).then_some(session.release)
+}
+
+/// What [`resolve_session_release`] would resolve `version` to, without starting or ending a
+/// session.
+pub fn peek_session_release(
+    version: &str,
+    releases: &[Release],
+    buckle_dir: &Path,
+) -> Result<Release, Error> {
+    if is_moving(version) {
+        if let Some(release) = read_session(&get_session_path(buckle_dir), version, buckle_dir) {
+            return Ok(release);
+        }
+    }
+    resolve_release(version, releases).cloned()
+}
+
+/// Resolve `version` like [`resolve_release`], keeping moving versions stable for a session.
+pub fn resolve_session_release(
+    version: &str,
+    releases: &[Release],
+    buckle_dir: &Path,
+) -> Result<Release, Error> {
+    if !is_moving(version) {
+        // An explicit pin ends the session.
+        if !env_flag("BUCKLE_DRY_RUN") {
+            end_session(buckle_dir);
+        }
+        return resolve_release(version, releases).cloned();
+    }
+    let path = get_session_path(buckle_dir);
+    if let Some(release) = read_session(&path, version, buckle_dir) {
+        if release.tag_name != version {
+            eprintln!(
+                "buckle: {version} resolved to {} earlier in this session",
+                release.tag_name
+            );
+        }
+        return Ok(release);
+    }
+    let release = resolve_release(version, releases)?.clone();
+    if !env_flag("BUCKLE_DRY_RUN") {
+        let session = Session {
+            version: version.to_string(),
+            release: release.clone(),
+        };
+        // The session is a nicety, so a cache that can't be written to (such as a read-only,
+        // pre-populated one) must not stop buck2 from running.
+        let _ = write_session(&path, &session);
+    }
+    Ok(release)
+}
+
+fn write_session(path: &Path, session: &Session) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_file_atomically(path, serde_json::to_string(session)?.as_bytes())?;
+    Ok(())
+}
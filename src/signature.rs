@@ -0,0 +1,128 @@
+//! Verification of release signatures against a trusted public key.
+//!
+//! A key is configured with `BUCKLE_VERIFY_KEY`. Its format picks the backend: minisign keys
+//! are verified natively, while armored GPG keys are verified with the `gpg` executable.
+
+use anyhow::{anyhow, Error};
+use std::{
+    env,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::TempDir;
+
+/// A way of checking that a file was signed by a trusted key.
+pub trait SignatureVerifier {
+    /// The suffix of the signature asset published next to the signed one.
+    fn signature_suffix(&self) -> &'static str;
+
+    /// Check `signature` is a valid signature of the contents of `path`.
+    fn verify(&self, path: &Path, signature: &[u8]) -> Result<(), Error>;
+}
+
+pub struct Minisign {
+    key: minisign_verify::PublicKey,
+}
+
+impl SignatureVerifier for Minisign {
+    fn signature_suffix(&self) -> &'static str {
+        ".minisig"
+    }
+
+    fn verify(&self, path: &Path, signature: &[u8]) -> Result<(), Error> {
+        let signature = std::str::from_utf8(signature)
+            .map_err(|_| anyhow!("The minisign signature is not valid UTF-8"))?;
+        let signature = minisign_verify::Signature::decode(signature)
+            .map_err(|err| anyhow!("Could not decode the minisign signature: {err}"))?;
+        let mut verifier = self
+            .key
+            .verify_stream(&signature)
+            .map_err(|err| anyhow!("The minisign signature can't be checked: {err}"))?;
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            verifier.update(&buf[..read]);
+        }
+        verifier
+            .finalize()
+            .map_err(|err| anyhow!("Bad minisign signature: {err}"))
+    }
+}
+
+pub struct Gpg {
+    key_path: PathBuf,
+}
+
+impl SignatureVerifier for Gpg {
+    fn signature_suffix(&self) -> &'static str {
+        ".sig"
+    }
+
+    fn verify(&self, path: &Path, signature: &[u8]) -> Result<(), Error> {
+        // Use a throwaway keyring holding only the trusted key so that nothing else the user
+        // has imported is accepted.
+        let home = TempDir::new()?;
+        let gpg = |args: &[&std::ffi::OsStr]| {
+            Command::new("gpg")
+                .arg("--batch")
+                .arg("--homedir")
+                .arg(home.path())
+                .args(args)
+                .output()
+                .map_err(|err| anyhow!("Could not run gpg to verify the signature: {err}"))
+        };
+        let output = gpg(&["--import".as_ref(), self.key_path.as_os_str()])?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Could not import {}: {}",
+                self.key_path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let signature_path = home.path().join("signature");
+        fs::write(&signature_path, signature)?;
+        let output = gpg(&[
+            "--verify".as_ref(),
+            signature_path.as_os_str(),
+            path.as_os_str(),
+        ])?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Bad GPG signature: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The verifier for the key in `BUCKLE_VERIFY_KEY`, if one is configured.
+pub fn get_signature_verifier() -> Result<Option<Box<dyn SignatureVerifier>>, Error> {
+    let Some(key_path) = env::var_os("BUCKLE_VERIFY_KEY").map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let key = fs::read_to_string(&key_path).map_err(|err| {
+        anyhow!(
+            "Could not read the BUCKLE_VERIFY_KEY {}: {err}",
+            key_path.display()
+        )
+    })?;
+    if key.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+        return Ok(Some(Box::new(Gpg { key_path })));
+    }
+    let key = minisign_verify::PublicKey::decode(&key)
+        .or_else(|_| minisign_verify::PublicKey::from_base64(key.trim()))
+        .map_err(|_| {
+            anyhow!(
+                "The BUCKLE_VERIFY_KEY {} is neither a minisign nor an armored GPG public key",
+                key_path.display()
+            )
+        })?;
+    Ok(Some(Box::new(Minisign { key })))
+}
@@ -0,0 +1,63 @@
+//! `BUCKLE_LOG_STDOUT` and `BUCKLE_LOG_STDERR`: copy what buck2 writes to files as well as to
+//! the console, so CI can keep a log without losing the live output.
+
+use crate::env_flag;
+use anyhow::{anyhow, Error};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::PathBuf,
+    thread::{self, JoinHandle},
+};
+
+/// The log file named by `var`, created along with its directory if need be. It is truncated
+/// unless `BUCKLE_LOG_APPEND=1`.
+pub fn open_log(var: &str) -> Result<Option<File>, Error> {
+    let Some(path) = env::var_os(var).map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let open = || -> io::Result<File> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let append = env_flag("BUCKLE_LOG_APPEND");
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+    };
+    let file = open().map_err(|err| anyhow!("Could not open {var} {}: {err}", path.display()))?;
+    Ok(Some(file))
+}
+
+/// Copy everything read from `from` to both `console` and `log` as it arrives, until `from`
+/// closes. If the console goes away the log still gets the rest, so buck2 never blocks on a
+/// full pipe.
+pub fn spawn<R, W>(mut from: R, mut console: W, mut log: File) -> JoinHandle<io::Result<()>>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0; 64 * 1024];
+        let mut console_open = true;
+        loop {
+            let read = match from.read(&mut buf) {
+                Ok(0) => return log.flush(),
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if console_open {
+                console_open = console
+                    .write_all(&buf[..read])
+                    .and_then(|_| console.flush())
+                    .is_ok();
+            }
+            log.write_all(&buf[..read])?;
+        }
+    })
+}
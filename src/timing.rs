@@ -0,0 +1,63 @@
+//! `BUCKLE_TIMING=1`: how long buckle spent on each step before running buck2.
+//!
+//! Purely local and off by default, when it costs no more than checking a flag per step.
+
+use crate::env_flag;
+use once_cell::sync::OnceCell;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Time spent per step, in the order the steps first ran.
+static STEPS: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+static START: OnceCell<Instant> = OnceCell::new();
+
+fn enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| env_flag("BUCKLE_TIMING"))
+}
+
+/// Mark the start of the invocation, for the total.
+pub fn start() {
+    if enabled() {
+        START.get_or_init(Instant::now);
+    }
+}
+
+/// Run `f`, adding the time it takes to `step`.
+pub fn time<T>(step: &'static str, f: impl FnOnce() -> T) -> T {
+    if !enabled() {
+        return f();
+    }
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+    let mut steps = STEPS.lock().unwrap_or_else(|err| err.into_inner());
+    match steps.iter_mut().find(|(name, _)| *name == step) {
+        Some((_, total)) => *total += elapsed,
+        None => steps.push((step, elapsed)),
+    }
+    result
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Print the breakdown, just before buck2 starts.
+pub fn report() {
+    if !enabled() {
+        return;
+    }
+    let steps = STEPS.lock().unwrap_or_else(|err| err.into_inner());
+    for (step, duration) in steps.iter() {
+        eprintln!("buckle: timing: {step}: {}", millis(*duration));
+    }
+    if let Some(start) = START.get() {
+        eprintln!(
+            "buckle: timing: total before buck2: {}",
+            millis(start.elapsed())
+        );
+    }
+}
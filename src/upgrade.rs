@@ -0,0 +1,120 @@
+//! `buckle upgrade`: move the project's `.buckversion` pin, or its `buckle.lock`, to a newer
+//! release.
+
+use crate::{
+    channel_release, ensure_buckle_dir, find_buckversion, find_tag, get_buck2_project_root,
+    get_releases_for, lock, parse_buckversion, session,
+};
+use anyhow::{anyhow, Error};
+use std::{ffi::OsString, fs};
+
+struct UpgradeArgs {
+    dry_run: bool,
+    to: Option<String>,
+}
+
+impl UpgradeArgs {
+    fn parse(args: &[OsString]) -> Result<Self, Error> {
+        let mut upgrade_args = UpgradeArgs {
+            dry_run: false,
+            to: None,
+        };
+        let mut args = args.iter().map(|arg| {
+            arg.to_str()
+                .ok_or_else(|| anyhow!("Invalid argument {}", arg.to_string_lossy()))
+        });
+        while let Some(arg) = args.next() {
+            match arg? {
+                "--dry-run" => upgrade_args.dry_run = true,
+                "--to" => {
+                    let tag = args.next().ok_or(anyhow!("--to requires a version"))??;
+                    upgrade_args.to = Some(tag.to_string());
+                }
+                arg if arg.starts_with("--to=") => {
+                    upgrade_args.to = Some(arg["--to=".len()..].to_string());
+                }
+                arg => {
+                    return Err(anyhow!(
+                        "Unknown argument '{arg}' to buckle upgrade. \
+                        Usage: buckle upgrade [--dry-run] [--to <version>]"
+                    ))
+                }
+            }
+        }
+        Ok(upgrade_args)
+    }
+}
+
+/// Swap the version in `.buckversion` contents, leaving comments and layout alone.
+fn replace_version(contents: &str, old: &str, new: &str) -> String {
+    let mut replaced = false;
+    let mut lines: Vec<String> = contents
+        .lines()
+        .map(|line| {
+            let content = line.trim_start_matches('\u{feff}').trim();
+            if replaced || content.is_empty() || content.starts_with('#') {
+                return line.to_string();
+            }
+            replaced = true;
+            line.replacen(old, new, 1)
+        })
+        .collect();
+    if contents.ends_with('\n') {
+        lines.push(String::new());
+    }
+    lines.join("\n")
+}
+
+pub fn upgrade(args: &[OsString]) -> Result<(), Error> {
+    let args = UpgradeArgs::parse(args)?;
+    let root = get_buck2_project_root().ok_or(anyhow!(
+        "buckle upgrade must be run from within a buck2 project"
+    ))?;
+    let buckversion_path = find_buckversion().unwrap_or_else(|| root.join(".buckversion"));
+    let contents = if buckversion_path.exists() {
+        Some(fs::read_to_string(&buckversion_path)?)
+    } else {
+        None
+    };
+    let before = contents.as_deref().and_then(parse_buckversion);
+
+    // A locked project keeps following its moving version, so it is the lock that moves on.
+    let follows_moving = match before {
+        Some(before) => session::is_moving(before),
+        None => true,
+    };
+    if follows_moving && lock::lock_path(root).exists() {
+        let version = args.to.as_deref().or(before).unwrap_or("latest");
+        return lock::refresh_lock(root, version, args.dry_run);
+    }
+
+    // A tag to move to may be older than the newest page of releases.
+    let releases = get_releases_for(&ensure_buckle_dir()?, args.to.as_deref())?;
+    let after = match &args.to {
+        Some(tag) => {
+            find_tag(&releases, tag).ok_or_else(|| anyhow!("{tag} is not a buck2 release"))?
+        }
+        None => channel_release(&releases, false).ok_or(anyhow!(
+            "There are no stable releases of buck2 to upgrade to"
+        ))?,
+    };
+    let after = after.tag_name.as_str();
+
+    println!("{} -> {after}", before.unwrap_or("(unpinned)"));
+    // A digest pin only holds for its own tag, so moving to another tag replaces it too.
+    let before_tag = before.map(|before| before.split_once('@').map_or(before, |(tag, _)| tag));
+    if before_tag == Some(after) {
+        return Ok(());
+    }
+    if args.dry_run {
+        println!("Would update {}", buckversion_path.display());
+        return Ok(());
+    }
+    let updated = match (&contents, before) {
+        (Some(contents), Some(before)) => replace_version(contents, before, after),
+        _ => format!("{after}\n"),
+    };
+    fs::write(&buckversion_path, updated)?;
+    println!("Updated {}", buckversion_path.display());
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! How the prelude check asks version control which commit a cell is checked out at.
+//!
+//! The decision of whether a cell matches is kept apart from git itself, behind
+//! [`VcsPrelude`], so that other version control systems can be added and the decision can be
+//! exercised without a real repository.
+
+use crate::{debug_log, CellCheck};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A working copy that can report the commits of its submodules.
+pub trait VcsPrelude {
+    /// The commit checked out for the submodule at `rel_path`, relative to the root of the
+    /// working copy. `None` if there is no such submodule or it isn't checked out.
+    fn submodule_hash(&self, rel_path: &str) -> Option<String>;
+}
+
+/// What a cell's submodule is at, compared with what buck2 expects.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubmoduleCheck {
+    Matches,
+    /// There is nothing to compare, such as a cell that is not a submodule.
+    Unchecked,
+    /// The submodule is at the given commit instead.
+    Mismatch(String),
+}
+
+/// Compare the submodule at `rel_path` in `vcs` with `expected_hash`.
+pub fn check_submodule(
+    vcs: &dyn VcsPrelude,
+    rel_path: &str,
+    expected_hash: &str,
+) -> SubmoduleCheck {
+    match vcs.submodule_hash(rel_path) {
+        None => SubmoduleCheck::Unchecked,
+        Some(hash) if hash == expected_hash => SubmoduleCheck::Matches,
+        Some(hash) => SubmoduleCheck::Mismatch(hash),
+    }
+}
+
+/// The git repository buckle is run from.
+pub struct Git {
+    repo: git2::Repository,
+    workdir: PathBuf,
+}
+
+impl Git {
+    /// The repository around the current directory, or why the `cell` can't be checked in it.
+    pub(crate) fn open(cell: &str) -> Result<Self, CellCheck> {
+        // It's ok if it's not a git repo, but we don't have support
+        // for checking other methods yet. Do not throw an error.
+        let repo = match git2::Repository::open_from_env() {
+            Ok(repo) => repo,
+            Err(err) => {
+                if err.code() != git2::ErrorCode::NotFound {
+                    debug_log(&format!(
+                        "not checking the {cell}, git is not usable here: {err}"
+                    ));
+                }
+                return Err(CellCheck::Unchecked);
+            }
+        };
+        // It makes no sense for buck2 to be invoked on a bare git repo.
+        let Some(workdir) = repo.workdir() else {
+            return Err(CellCheck::Skipped(format!(
+                "{} is a bare git repo",
+                repo.path().display()
+            )));
+        };
+        let workdir = fs::canonicalize(workdir).unwrap_or_else(|_| workdir.to_path_buf());
+        Ok(Git { repo, workdir })
+    }
+
+    /// The root of the working copy, with symlinks resolved.
+    pub(crate) fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+}
+
+impl VcsPrelude for Git {
+    fn submodule_hash(&self, rel_path: &str) -> Option<String> {
+        // Shallow or partial clones can know of the submodule without having it checked out.
+        let submodule = match self.repo.find_submodule(rel_path) {
+            Ok(submodule) => submodule,
+            Err(err) => {
+                debug_log(&format!("{rel_path} is not a usable submodule: {err}"));
+                return None;
+            }
+        };
+        let Some(hash) = submodule.workdir_id() else {
+            debug_log(&format!("the {rel_path} submodule is not checked out"));
+            return None;
+        };
+        Some(hash.to_string())
+    }
+}
@@ -0,0 +1,125 @@
+//! `buckle warm`: download buck2 for several platforms at once, such as when building a CI
+//! image whose jobs should never download.
+
+use crate::{
+    cache, deferring_size_cap, download_http, ensure_buckle_dir, find_tag, get_direct_dir,
+    get_releases_for, get_triple, get_version_dir, is_installed, read_buck2_version,
+    resolve_release, with_triple,
+};
+use anyhow::{anyhow, Error};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+const USAGE: &str = "Usage: buckle warm [--arch <triple>]... [--version <version>]";
+
+struct WarmArgs {
+    triples: Vec<String>,
+    version: Option<String>,
+}
+
+impl WarmArgs {
+    fn parse(args: &[OsString]) -> Result<Self, Error> {
+        let mut warm_args = WarmArgs {
+            triples: vec![],
+            version: None,
+        };
+        let mut args = args.iter().map(|arg| {
+            arg.to_str()
+                .ok_or_else(|| anyhow!("Invalid argument {}", arg.to_string_lossy()))
+        });
+        while let Some(arg) = args.next() {
+            match arg? {
+                "--arch" => {
+                    let triple = args.next().ok_or(anyhow!("--arch requires a triple"))??;
+                    warm_args.triples.push(triple.to_string());
+                }
+                arg if arg.starts_with("--arch=") => {
+                    warm_args.triples.push(arg["--arch=".len()..].to_string());
+                }
+                "--version" => {
+                    let version = args
+                        .next()
+                        .ok_or(anyhow!("--version requires a version"))??;
+                    warm_args.version = Some(version.to_string());
+                }
+                arg if arg.starts_with("--version=") => {
+                    warm_args.version = Some(arg["--version=".len()..].to_string());
+                }
+                arg => return Err(anyhow!("Unknown argument '{arg}' to buckle warm. {USAGE}")),
+            }
+        }
+        Ok(warm_args)
+    }
+}
+
+/// Download `version`, or the project's version, for every triple asked for, or just this
+/// platform's. A platform that fails is reported and the rest carry on.
+pub fn warm(args: &[OsString]) -> Result<(), Error> {
+    let args = WarmArgs::parse(args)?;
+    let triples = if args.triples.is_empty() {
+        vec![get_triple()?]
+    } else {
+        args.triples
+    };
+    let version = match args.version {
+        Some(version) => version,
+        None => read_buck2_version()?,
+    };
+    let buckle_dir = ensure_buckle_dir()?;
+
+    // Resolve once, so that every platform gets the same release.
+    let (tag, releases) = match get_direct_dir(&buckle_dir, &version)? {
+        Some(_) => (version, None),
+        None => {
+            let releases = get_releases_for(&buckle_dir, Some(&version))?;
+            let tag = resolve_release(&version, &releases)?.tag_name.clone();
+            (tag, Some(releases))
+        }
+    };
+
+    let mut failed = 0;
+    let mut warmed_dirs = vec![];
+    for triple in &triples {
+        let warmed = with_triple(triple, || -> Result<(bool, PathBuf), Error> {
+            let expected = match &releases {
+                Some(releases) => match find_tag(releases, &tag) {
+                    Some(release) => Some(get_version_dir(&buckle_dir, release)?),
+                    None => None,
+                },
+                None => get_direct_dir(&buckle_dir, &tag)?,
+            };
+            let cached = matches!(&expected, Some(expected) if is_installed(expected));
+            let (_, dir) = deferring_size_cap(|| download_http(tag.clone(), None, &buckle_dir))?;
+            Ok((cached && expected.as_ref() == Some(&dir), dir))
+        });
+        match warmed {
+            Ok((true, dir)) => {
+                println!(
+                    "{triple}: buck2 {tag} is already cached in {}",
+                    dir.display()
+                );
+                warmed_dirs.push(dir);
+            }
+            Ok((false, dir)) => {
+                println!("{triple}: fetched buck2 {tag} into {}", dir.display());
+                warmed_dirs.push(dir);
+            }
+            Err(err) => {
+                eprintln!("buckle: could not warm buck2 {tag} for {triple}: {err}");
+                failed += 1;
+            }
+        }
+    }
+    // Every platform warmed in this run is kept, however far over budget they are together.
+    let keep: Vec<&Path> = warmed_dirs.iter().map(PathBuf::as_path).collect();
+    cache::enforce_size_cap(&buckle_dir, &keep)?;
+    if failed > 0 {
+        return Err(anyhow!(
+            "Could not warm the cache for {failed} of {} platforms",
+            triples.len()
+        ));
+    }
+    Ok(())
+}
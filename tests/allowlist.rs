@@ -0,0 +1,64 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+const NEWER_TAG: &str = "2023-08-01";
+const NEWER_COMMITISH: &str = "2222222222222222222222222222222222222222";
+
+/// A cache with [`TAG`] and a newer release, both installed.
+#[cfg(unix)]
+fn two_release_cache() -> TempDir {
+    let cache = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[release(NEWER_TAG, NEWER_COMMITISH), release(TAG, COMMITISH)],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), NEWER_COMMITISH, PRELUDE_HASH.as_bytes());
+    cache
+}
+
+/// A pin outside the allowlist is refused, naming the policy.
+#[cfg(unix)]
+#[test]
+fn test_disallowed_pin() {
+    let cache = two_release_cache();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_ALLOWED_VERSIONS", NEWER_TAG)
+        .assert()
+        .failure();
+    let stderr_denied = stderr(&assert);
+    assert!(
+        stderr_denied.contains(&format!(
+            "buck2 {TAG} is not allowed by BUCKLE_ALLOWED_VERSIONS, which permits {NEWER_TAG}"
+        )),
+        "found {stderr_denied}"
+    );
+    assert!(!stdout(&assert).contains(COMMITISH));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_ALLOWED_VERSIONS", format!("{NEWER_TAG}, {TAG}"))
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(COMMITISH));
+}
+
+/// `latest` resolves to the newest allowed release rather than the newest release.
+#[cfg(unix)]
+#[test]
+fn test_latest_within_allowlist() {
+    let cache = two_release_cache();
+    let cwd = TempDir::new().unwrap();
+    let allowlist = cwd.path().join("allowed-versions");
+    std::fs::write(&allowlist, format!("# Approved releases\n{TAG}\n")).unwrap();
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "latest")
+        .env("BUCKLE_ALLOWED_VERSIONS", &allowlist)
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains(&format!("latest resolved to {TAG}")));
+    assert!(stdout(&assert).contains(COMMITISH));
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+#[cfg(unix)]
+#[test]
+fn test_buckle_env() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("--buckle-env")
+        .env("BUCKLE_AUTH_TOKEN", "hunter2")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(!stdout.contains("buck2 stub"), "ran buck2: {stdout}");
+    let buckle_dir = buckle_dir(cache.path());
+    assert!(
+        stdout.contains(&format!("cache dir: {}", buckle_dir.display())),
+        "found {stdout}"
+    );
+    assert!(stdout.contains(&format!("version: {TAG}")));
+    assert!(stdout.contains(&format!("tag: {TAG}")));
+    let buck2 = version_dir(cache.path(), COMMITISH).join("buck2");
+    assert!(stdout.contains(&format!("buck2: {} (installed)", buck2.display())));
+    assert!(stdout.contains("BUCKLE_REPO (unset)"));
+    assert!(stdout.contains("BUCKLE_AUTH_TOKEN=********"));
+    assert!(!stdout.contains("hunter2"));
+}
+
+/// The dump only inspects: it neither creates the cache nor starts a session for `latest`.
+#[cfg(unix)]
+#[test]
+fn test_buckle_env_changes_nothing() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("--buckle-env")
+        .assert()
+        .success();
+    let buckle_dir = buckle_dir(cache.path());
+    assert!(stdout(&assert).contains("(not created yet)"));
+    assert!(!buckle_dir.exists());
+
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("--buckle-env")
+        .env("USE_BUCK2_VERSION", "latest")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(&format!("tag: {TAG}")));
+    assert!(!buckle_dir.join("sessions").exists());
+}
+
+/// A pinned tag older than the newest page of releases is found as a real run would find it.
+#[cfg(unix)]
+#[test]
+fn test_buckle_env_pages_for_old_tag() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    std::fs::create_dir_all(buckle_dir(cache.path())).unwrap();
+    let server = mock_github();
+    mount_release_page(
+        &server,
+        "/releases",
+        &[release(
+            "2023-08-01",
+            "1111111111111111111111111111111111111111",
+        )],
+        "/releases?page=2",
+    );
+    mount_release_page(
+        &server,
+        "/releases?page=2",
+        &[release(TAG, COMMITISH)],
+        "/releases?page=3",
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("--buckle-env")
+        .env("USE_BUCK2_VERSION", TAG)
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains(&format!("tag: {TAG}")), "found {stdout}");
+    assert_eq!(server.hits("/releases?page=2"), 1);
+}
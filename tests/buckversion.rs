@@ -0,0 +1,166 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+/// Run buckle in a project whose `.buckversion` has `contents`.
+fn run_with_buckversion(contents: &str) -> assert_cmd::assert::Assert {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    #[cfg(unix)]
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(project.path().join(".buckversion"), contents).unwrap();
+    buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .assert()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plain_buckversion() {
+    run_with_buckversion(&format!("{TAG}\n")).success();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_buckversion_with_bom() {
+    run_with_buckversion(&format!("\u{feff}{TAG}\n")).success();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_buckversion_with_trailing_comment() {
+    run_with_buckversion(&format!("{TAG} # pinned for repro\n")).success();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_buckversion_with_comment_lines() {
+    run_with_buckversion(&format!(
+        "# The buck2 release this project builds with.\n\n  {TAG}  \n2023-01-01\n"
+    ))
+    .success();
+}
+
+#[test]
+fn test_buckversion_without_version() {
+    let assert = run_with_buckversion("# nothing pinned\n\n").failure();
+    assert!(stderr(&assert).contains("does not contain a version"));
+}
+
+/// Versions that could escape the cache or mangle a download URL are refused before use,
+/// whether from `.buckversion` or `USE_BUCK2_VERSION`.
+#[test]
+fn test_unsafe_versions_are_rejected() {
+    let assert = run_with_buckversion("../../etc\n").code(26);
+    assert!(stderr(&assert).contains("\"../../etc\" is not a valid buck2 version"));
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    for version in ["..", "2023-07-15/../..", "a b", "2023-07-15 2023-07-16"] {
+        let assert = buckle(cache.path(), cwd.path())
+            .env("USE_BUCK2_VERSION", version)
+            .assert()
+            .code(26);
+        assert!(stderr(&assert).contains(&format!("{version:?} is not a valid buck2 version")));
+    }
+    assert!(!cache.path().join("etc").exists());
+}
+
+/// A mirror whose releases list has a commitish that isn't a plain name is refused rather
+/// than used as a cache directory.
+#[test]
+fn test_unsafe_commitish_is_rejected() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, "../escaped")]);
+    let assert = buckle(cache.path(), cwd.path()).assert().failure();
+    assert!(stderr(&assert).contains("can't be used as a cache directory"));
+    assert!(!buckle_dir(cache.path())
+        .parent()
+        .unwrap()
+        .join("escaped")
+        .exists());
+}
+
+/// In a project with nested `.buckversion` files the one closest to the current directory
+/// wins, and outside of the nested one the root's applies.
+#[cfg(unix)]
+#[test]
+fn test_nearest_buckversion_wins() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let nested = project.path().join("nested");
+    let deeper = nested.join("deeper");
+    fs::create_dir_all(&deeper).unwrap();
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(project.path().join(".buckversion"), "1999-01-01\n").unwrap();
+    fs::write(nested.join(".buckconfig"), "").unwrap();
+    fs::write(nested.join(".buckversion"), format!("{TAG}\n")).unwrap();
+
+    let run = |cwd: &std::path::Path| {
+        buckle(cache.path(), cwd)
+            .env_remove("USE_BUCK2_VERSION")
+            .env("BUCKLE_OFFLINE", "1")
+            .assert()
+    };
+    let assert = run(&deeper).success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    let assert = run(project.path()).code(23);
+    assert!(stderr(&assert).contains("1999-01-01 was not available"));
+
+    // The environment still takes precedence over any file.
+    buckle(cache.path(), project.path())
+        .env("USE_BUCK2_VERSION", TAG)
+        .env("BUCKLE_OFFLINE", "1")
+        .assert()
+        .success();
+}
+
+/// `USE_BUCK2_VERSION` is trimmed, and when it is empty or only whitespace, as a defined but
+/// unset CI variable is, the project's `.buckversion` is used instead.
+#[cfg(unix)]
+#[test]
+fn test_blank_use_buck2_version_is_ignored() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(project.path().join(".buckversion"), format!("{TAG}\n")).unwrap();
+
+    for version in ["", "  ", "\t\n"] {
+        let assert = buckle(cache.path(), project.path())
+            .env("USE_BUCK2_VERSION", version)
+            .env("BUCKLE_DEBUG", "1")
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+        let reported = stderr(&assert);
+        assert!(
+            reported.contains("ignoring USE_BUCK2_VERSION, which is set but empty"),
+            "found {reported}"
+        );
+        assert!(
+            reported.contains(".buckversion, running buck2"),
+            "found {reported}"
+        );
+    }
+
+    let assert = buckle(cache.path(), project.path())
+        .env("USE_BUCK2_VERSION", format!(" {TAG}\n"))
+        .env("BUCKLE_DEBUG", "1")
+        .assert()
+        .success();
+    let reported = stderr(&assert);
+    assert!(
+        reported.contains(&format!("version {TAG} from USE_BUCK2_VERSION")),
+        "found {reported}"
+    );
+}
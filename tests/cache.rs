@@ -0,0 +1,504 @@
+mod common;
+
+use common::*;
+use std::fs::{File, FileTimes};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+const OLD_COMMITISHES: [&str; 3] = [
+    "1111111111111111111111111111111111111111",
+    "2222222222222222222222222222222222222222",
+    "3333333333333333333333333333333333333333",
+];
+
+/// Versions last used one, two and three days ago are evicted oldest first once a new
+/// download takes the cache over budget.
+#[cfg(unix)]
+#[test]
+fn test_cache_max_bytes_evicts_least_recently_used() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    for (days, commitish) in (1..).zip(OLD_COMMITISHES.iter().rev()) {
+        let dir = seed_version(cache.path(), commitish, PRELUDE_HASH.as_bytes());
+        let used = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(dir.join("buck2"))
+            .unwrap()
+            .set_times(FileTimes::new().set_accessed(used).set_modified(used))
+            .unwrap();
+    }
+
+    // Room for the new download, with the digests it records, and the most recently used of
+    // the others.
+    let version_bytes = (stub_buck2().len() + PRELUDE_HASH.len()) as u64;
+    let budget = 2 * version_bytes + 2 * 64;
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_CACHE_MAX_BYTES", budget.to_string())
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("evicting"), "found {stderr}");
+
+    assert!(version_dir(cache.path(), COMMITISH).join("buck2").exists());
+    assert!(!buckle_dir(cache.path()).join(OLD_COMMITISHES[0]).exists());
+    assert!(!buckle_dir(cache.path()).join(OLD_COMMITISHES[1]).exists());
+    assert!(version_dir(cache.path(), OLD_COMMITISHES[2])
+        .join("buck2")
+        .exists());
+}
+
+/// Installing another version into a capped cache keeps the one the project runs, even when it
+/// is the least recently used.
+#[cfg(unix)]
+#[test]
+fn test_cache_max_bytes_keeps_project_version() {
+    const OLD_TAG: &str = "2023-06-01";
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(
+        &server,
+        &[
+            release(TAG, COMMITISH),
+            release(OLD_TAG, OLD_COMMITISHES[0]),
+        ],
+    );
+    mount_release(&server, OLD_TAG, &stub_buck2(), PRELUDE_HASH);
+    let project_dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let used = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    File::options()
+        .write(true)
+        .open(project_dir.join("buck2"))
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(used).set_modified(used))
+        .unwrap();
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_CACHE_MAX_BYTES", "1")
+        .args(["warm", "--version", OLD_TAG])
+        .assert()
+        .success();
+    assert!(project_dir.join("buck2").exists());
+    assert!(version_dir(cache.path(), OLD_COMMITISHES[0])
+        .join("buck2")
+        .exists());
+}
+
+/// A directory another buckle is still staging an install in is neither evicted nor
+/// compressed out from under it.
+#[cfg(unix)]
+#[test]
+fn test_staging_dir_is_left_alone() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let staging = buckle_dir(cache.path())
+        .join(OLD_COMMITISHES[0])
+        .join(".x.tmp-abc");
+    write_script(&staging.join("buck2"), "echo staged\n");
+    let used = SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60);
+    File::options()
+        .write(true)
+        .open(staging.join("buck2"))
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(used).set_modified(used))
+        .unwrap();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_CACHE_MAX_BYTES", "1")
+        .env("BUCKLE_COMPRESS_CACHE", "1")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("evicting"));
+    assert!(staging.join("buck2").exists());
+    assert!(!staging.join("buck2.zst").exists());
+}
+
+/// Without a budget nothing is evicted.
+#[cfg(unix)]
+#[test]
+fn test_no_cache_max_bytes() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    for commitish in OLD_COMMITISHES {
+        seed_version(cache.path(), commitish, PRELUDE_HASH.as_bytes());
+    }
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("evicting"));
+    for commitish in OLD_COMMITISHES {
+        assert!(version_dir(cache.path(), commitish).join("buck2").exists());
+    }
+}
+
+/// A cache that cannot be created names the path and how to pick another.
+#[test]
+fn test_cache_cannot_be_created() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let file = cache.path().join("file");
+    std::fs::write(&file, "").unwrap();
+    let assert = buckle(&file, cwd.path()).assert().failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "could not write to its cache at {}",
+            buckle_dir(&file).display()
+        )),
+        "found {stderr}"
+    );
+    assert!(stderr.contains("Set BUCKLE_CACHE to a writable directory"));
+}
+
+/// A cache directory without write permission says so.
+#[cfg(unix)]
+#[test]
+fn test_cache_permission_denied() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    std::fs::create_dir(buckle_dir(cache.path())).unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let readonly = std::fs::Permissions::from_mode(0o555);
+    std::fs::set_permissions(buckle_dir(cache.path()), readonly).unwrap();
+    if File::create(buckle_dir(cache.path()).join("probe")).is_ok() {
+        eprintln!("permissions are not enforced for this user, skipping");
+        return;
+    }
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("permission denied"), "found {stderr}");
+    assert!(stderr.contains("Set BUCKLE_CACHE"), "found {stderr}");
+    let writable = std::fs::Permissions::from_mode(0o755);
+    std::fs::set_permissions(buckle_dir(cache.path()), writable).unwrap();
+}
+
+/// Downloads can be staged in `BUCKLE_TMPDIR`, which must be writable.
+#[cfg(unix)]
+#[test]
+fn test_buckle_tmpdir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let tmpdir = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let readonly = std::fs::Permissions::from_mode(0o555);
+    std::fs::set_permissions(tmpdir.path(), readonly).unwrap();
+    let probe = tmpdir.path().join("probe");
+    if File::create(&probe).is_ok() {
+        eprintln!("permissions are not enforced for this user, skipping");
+        std::fs::remove_file(probe).unwrap();
+    } else {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("BUCKLE_TMPDIR", tmpdir.path())
+            .assert()
+            .failure();
+        let stderr = stderr(&assert);
+        assert!(
+            stderr.contains(&format!(
+                "could not create a temporary file in BUCKLE_TMPDIR {}: permission denied",
+                tmpdir.path().display()
+            )),
+            "found {stderr}"
+        );
+    }
+    let writable = std::fs::Permissions::from_mode(0o755);
+    std::fs::set_permissions(tmpdir.path(), writable).unwrap();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_TMPDIR", tmpdir.path())
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+}
+
+/// A `BUCKLE_TMPDIR` that isn't a directory is named in the error.
+#[cfg(unix)]
+#[test]
+fn test_buckle_tmpdir_not_a_directory() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let tmpdir = cache.path().join("not-a-dir");
+    std::fs::write(&tmpdir, "").unwrap();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_TMPDIR", &tmpdir)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "could not create a temporary file in BUCKLE_TMPDIR {}",
+            tmpdir.display()
+        )),
+        "found {stderr}"
+    );
+    assert!(!version_dir(cache.path(), COMMITISH).join("buck2").exists());
+}
+
+/// `BUCKLE_PROJECT_CACHE` installs buck2 under the project root, and falls back to the user
+/// cache outside a project.
+#[cfg(unix)]
+#[test]
+fn test_project_cache() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    std::fs::write(project.path().join(".buckconfig"), "").unwrap();
+    let server = mock_github();
+
+    let assert = buckle_with_server(cache.path(), project.path(), &server)
+        .env("BUCKLE_PROJECT_CACHE", "1")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    let project_dir = std::fs::canonicalize(project.path()).unwrap();
+    assert!(project_dir
+        .join(".buckle")
+        .join(COMMITISH)
+        .join(host_triple())
+        .join("buck2")
+        .exists());
+    assert!(!buckle_dir(cache.path()).join(COMMITISH).exists());
+
+    let cwd = TempDir::new().unwrap();
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_PROJECT_CACHE", "1")
+        .assert()
+        .success();
+    assert!(version_dir(cache.path(), COMMITISH).join("buck2").exists());
+}
+
+#[cfg(unix)]
+fn cache_dir_line(home: &std::path::Path, xdg_cache_home: Option<&std::path::Path>) -> String {
+    let cwd = TempDir::new().unwrap();
+    let mut cmd = buckle(home, cwd.path());
+    cmd.env_remove("BUCKLE_CACHE")
+        .env_remove("XDG_CACHE_HOME")
+        .env("HOME", home)
+        .arg("--buckle-env");
+    if let Some(xdg_cache_home) = xdg_cache_home {
+        cmd.env("XDG_CACHE_HOME", xdg_cache_home);
+    }
+    let assert = cmd.assert().success();
+    // The dump doesn't create the cache, so it says so.
+    let line = stdout(&assert).lines().next().unwrap().to_string();
+    line.trim_end_matches(" (not created yet)").to_string()
+}
+
+/// `XDG_CACHE_HOME` is honoured on macOS as well as Linux.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn test_xdg_cache_home() {
+    let home = TempDir::new().unwrap();
+    let xdg_cache_home = TempDir::new().unwrap();
+    assert_eq!(
+        cache_dir_line(home.path(), Some(xdg_cache_home.path())),
+        format!(
+            "cache dir: {}",
+            xdg_cache_home.path().join("buckle").display()
+        )
+    );
+}
+
+/// Without `XDG_CACHE_HOME`, macOS uses `~/Library/Caches`.
+#[cfg(target_os = "macos")]
+#[test]
+fn test_macos_library_caches() {
+    let home = TempDir::new().unwrap();
+    let expected = home.path().join("Library").join("Caches").join("buckle");
+    assert_eq!(
+        cache_dir_line(home.path(), None),
+        format!("cache dir: {}", expected.display())
+    );
+}
+
+/// A cache path that isn't valid UTF-8, whether from `BUCKLE_CACHE` or `XDG_CACHE_HOME`, is
+/// used as is rather than ignored or panicked on.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_non_utf8_cache_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let tmp = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let cache = tmp.path().join(OsStr::from_bytes(b"cache-\xff"));
+    seed_releases(&cache, &[release(TAG, COMMITISH)]);
+    seed_version(&cache, COMMITISH, b"");
+
+    let assert = buckle(&cache, cwd.path()).assert().success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+
+    let assert = buckle(tmp.path(), cwd.path())
+        .env_remove("BUCKLE_CACHE")
+        .env("XDG_CACHE_HOME", &cache)
+        .arg("--buckle-env")
+        .assert()
+        .success();
+    assert!(stdout(&assert)
+        .lines()
+        .next()
+        .unwrap()
+        .starts_with("cache dir: "));
+    assert!(String::from_utf8_lossy(&assert.get_output().stdout).contains("cache-\u{fffd}"));
+}
+
+/// With `BUCKLE_COMPRESS_CACHE=1` a version unused for a while is compressed, and the next run
+/// of it transparently decompresses it again, leaving the version in use alone.
+#[cfg(unix)]
+#[test]
+fn test_compress_cache() {
+    const OLD_TAG: &str = "2023-06-01";
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[
+            release(TAG, COMMITISH),
+            release(OLD_TAG, OLD_COMMITISHES[0]),
+        ],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let old_dir = seed_version(cache.path(), OLD_COMMITISHES[0], PRELUDE_HASH.as_bytes());
+    let used = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+    File::options()
+        .write(true)
+        .open(old_dir.join("buck2"))
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(used).set_modified(used))
+        .unwrap();
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_COMPRESS_CACHE", "1")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert!(version_dir(cache.path(), COMMITISH).join("buck2").exists());
+    assert!(!old_dir.join("buck2").exists());
+    assert!(old_dir.join("buck2.zst").exists());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_COMPRESS_CACHE", "1")
+        .env("USE_BUCK2_VERSION", OLD_TAG)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert_eq!(std::fs::read(old_dir.join("buck2")).unwrap(), stub_buck2());
+    assert!(!old_dir.join("buck2.zst").exists());
+    assert!(version_dir(cache.path(), COMMITISH).join("buck2").exists());
+}
+
+/// Make `path` look like it was last used `age` ago. It is opened read only, as it may be
+/// running.
+fn age_binary(path: &std::path::Path, age: Duration) {
+    let used = SystemTime::now() - age;
+    File::open(path)
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(used).set_modified(used))
+        .unwrap();
+}
+
+/// A version another buckle is running is not compressed out from under it, and is once it
+/// exits.
+#[cfg(unix)]
+#[test]
+fn test_compress_cache_skips_version_in_use() {
+    const OLD_TAG: &str = "2023-06-01";
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[
+            release(TAG, COMMITISH),
+            release(OLD_TAG, OLD_COMMITISHES[0]),
+        ],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let old_dir = seed_version(cache.path(), OLD_COMMITISHES[0], PRELUDE_HASH.as_bytes());
+    let started = cwd.path().join("started");
+    let finish = cwd.path().join("finish");
+    write_script(
+        &old_dir.join("buck2"),
+        &format!(
+            "touch {}\nwhile [ ! -e {} ]; do sleep 0.1; done\n",
+            started.display(),
+            finish.display()
+        ),
+    );
+
+    let running = {
+        let (cache, cwd) = (cache.path().to_owned(), cwd.path().to_owned());
+        std::thread::spawn(move || {
+            buckle(&cache, &cwd)
+                .env("USE_BUCK2_VERSION", OLD_TAG)
+                .assert()
+                .success();
+        })
+    };
+    while !started.exists() {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let idle = Duration::from_secs(2 * 24 * 60 * 60);
+    age_binary(&old_dir.join("buck2"), idle);
+    buckle(cache.path(), cwd.path())
+        .env("BUCKLE_COMPRESS_CACHE", "1")
+        .assert()
+        .success();
+    assert!(old_dir.join("buck2").exists());
+    assert!(!old_dir.join("buck2.zst").exists());
+
+    std::fs::write(&finish, "").unwrap();
+    running.join().unwrap();
+    age_binary(&old_dir.join("buck2"), idle);
+    buckle(cache.path(), cwd.path())
+        .env("BUCKLE_COMPRESS_CACHE", "1")
+        .assert()
+        .success();
+    assert!(!old_dir.join("buck2").exists());
+    assert!(old_dir.join("buck2.zst").exists());
+}
+
+/// `buckle cache-dir` prints the absolute cache directory `BUCKLE_CACHE` resolves to, without
+/// creating it or touching the network.
+#[test]
+fn test_cache_dir() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let unused = cache.path().join("unused");
+
+    let assert = buckle_with_server(&unused, cwd.path(), &server)
+        .arg("cache-dir")
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        format!("{}\n", buckle_dir(&unused).display())
+    );
+    assert!(!unused.exists());
+    assert!(server.requests().is_empty());
+
+    let assert = buckle(std::path::Path::new("relative"), cwd.path())
+        .arg("cache-dir")
+        .assert()
+        .success();
+    let cwd = std::fs::canonicalize(cwd.path()).unwrap();
+    assert_eq!(
+        stdout(&assert),
+        format!("{}\n", buckle_dir(&cwd.join("relative")).display())
+    );
+}
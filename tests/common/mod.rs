@@ -0,0 +1,250 @@
+//! Helpers shared by the integration tests. They seed a buckle cache on disk with a stub buck2,
+//! or serve releases from a local [`MockServer`], so that tests can exercise buckle without
+//! touching the network.
+#![allow(dead_code)]
+
+mod server;
+
+pub use server::*;
+
+use assert_cmd::{assert::Assert, Command};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const TAG: &str = "2023-07-15";
+pub const COMMITISH: &str = "8a5b8e4c5c5e6d3b1f0a9b8c7d6e5f4a3b2c1d0e";
+pub const PRELUDE_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+/// A minimal GitHub release as returned by the releases API.
+pub fn release(tag: &str, commitish: &str) -> Value {
+    json!({
+        "url": format!("https://api.github.com/repos/facebook/buck2/releases/{tag}"),
+        "html_url": format!("https://github.com/facebook/buck2/releases/tag/{tag}"),
+        "assets_url": format!("https://api.github.com/repos/facebook/buck2/releases/{tag}/assets"),
+        "upload_url": "https://uploads.github.com/repos/facebook/buck2/releases/assets{?name,label}",
+        "tarball_url": null,
+        "zipball_url": null,
+        "id": 1,
+        "node_id": "RE_test",
+        "tag_name": tag,
+        "target_commitish": commitish,
+        "name": tag,
+        "body": null,
+        "draft": false,
+        "prerelease": false,
+        "created_at": null,
+        "published_at": null,
+        "author": {},
+        "assets": [],
+    })
+}
+
+/// The target triple buckle downloads buck2 for on this machine.
+pub fn host_triple() -> &'static str {
+    match (std::env::consts::ARCH, std::env::consts::OS) {
+        ("x86_64", "linux") => "x86_64-unknown-linux-musl",
+        ("x86_64", "macos") => "x86_64-apple-darwin",
+        ("x86_64", "windows") => "x86_64-pc-windows-msvc",
+        ("aarch64", "linux") => "aarch64-unknown-linux-gnu",
+        ("aarch64", "macos") => "aarch64-apple-darwin",
+        (arch, os) => panic!("no buck2 builds for {arch}/{os}"),
+    }
+}
+
+/// Serve a zstd compressed `buck2` and its `prelude_hash` for `tag`.
+pub fn mount_release(server: &MockServer, tag: &str, buck2: &[u8], prelude_hash: &str) {
+    mount_release_for(server, tag, host_triple(), buck2, prelude_hash);
+}
+
+/// Like [`mount_release`], but serving the binary for `triple`.
+pub fn mount_release_for(
+    server: &MockServer,
+    tag: &str,
+    triple: &str,
+    buck2: &[u8],
+    prelude_hash: &str,
+) {
+    server.mount(
+        &format!("/download/{tag}/buck2-{triple}.zst"),
+        Response::ok(zstd::encode_all(buck2, 0).unwrap()),
+    );
+    server.mount(
+        &format!("/download/{tag}/prelude_hash"),
+        Response::ok(prelude_hash),
+    );
+}
+
+pub fn mount_releases(server: &MockServer, releases: &[Value]) {
+    server.mount(
+        "/releases",
+        Response::ok(serde_json::to_string(releases).unwrap()),
+    );
+}
+
+/// Serve `releases` as one page of the releases list at `path`, linking to `next`.
+pub fn mount_release_page(server: &MockServer, path: &str, releases: &[Value], next: &str) {
+    server.mount(
+        path,
+        Response::ok(serde_json::to_string(releases).unwrap()).with_header(
+            "Link",
+            &format!(
+                "<{}{next}>; rel=\"next\", <{}{next}>; rel=\"last\"",
+                server.url(),
+                server.url()
+            ),
+        ),
+    );
+}
+
+/// A mock GitHub serving the canned `fixtures/releases.json` and a stub buck2 for [`TAG`].
+/// Point buckle at it with [`buckle_with_server`].
+pub fn mock_github() -> MockServer {
+    let server = MockServer::start();
+    server.mount(
+        "/releases",
+        Response::ok(fs::read(fixture("releases.json")).unwrap()),
+    );
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    server
+}
+
+/// Path to a file checked in under `tests/fixtures`.
+pub fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+        .iter()
+        .collect()
+}
+
+/// The contents of a stub buck2 that reports how it was invoked: its path, arguments and
+/// environment.
+pub fn stub_buck2() -> Vec<u8> {
+    fs::read(fixture("buck2")).unwrap()
+}
+
+/// The directory buckle keeps its state in when `BUCKLE_CACHE` points at `cache`.
+pub fn buckle_dir(cache: &Path) -> PathBuf {
+    cache.join("buckle")
+}
+
+/// Write a fresh `releases.json` so buckle does not try to refetch it.
+pub fn seed_releases(cache: &Path, releases: &[Value]) {
+    let dir = buckle_dir(cache);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("releases.json"),
+        serde_json::to_string(releases).unwrap(),
+    )
+    .unwrap();
+}
+
+/// Write an executable shell script to `path`.
+#[cfg(unix)]
+pub fn write_script(path: &Path, body: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, format!("#!/bin/sh\n{body}")).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[cfg(unix)]
+pub fn write_stub_buck2(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, stub_buck2()).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Where `commitish` is installed for this machine.
+pub fn version_dir(cache: &Path, commitish: &str) -> PathBuf {
+    buckle_dir(cache).join(commitish).join(host_triple())
+}
+
+/// Populate the cache with an installed version: a stub buck2 and its prelude_hash.
+#[cfg(unix)]
+pub fn seed_version(cache: &Path, commitish: &str, prelude_hash: &[u8]) -> PathBuf {
+    let dir = version_dir(cache, commitish);
+    write_stub_buck2(&dir.join("buck2"));
+    fs::write(dir.join("prelude_hash"), prelude_hash).unwrap();
+    dir
+}
+
+/// A buckle invocation against `cache`, run from `cwd`, resolving to [`TAG`].
+pub fn buckle(cache: &Path, cwd: &Path) -> Command {
+    let mut cmd = Command::cargo_bin("buckle").unwrap();
+    // Keep the user's own config file out of the way.
+    cmd.current_dir(cwd)
+        .env("BUCKLE_CONFIG", cache.join("no-config.toml"))
+        .env("BUCKLE_CACHE", cache)
+        .env("USE_BUCK2_VERSION", TAG)
+        .env_remove("BUCKLE_PRELUDE_CHECK");
+    cmd
+}
+
+/// A buckle invocation that downloads from `server` rather than GitHub.
+pub fn buckle_with_server(cache: &Path, cwd: &Path, server: &MockServer) -> Command {
+    let mut cmd = buckle(cache, cwd);
+    cmd.env("BUCKLE_RELEASES_URL", format!("{}/releases", server.url()))
+        .env("BUCKLE_BASE_URL", format!("{}/download", server.url()));
+    cmd
+}
+
+pub fn stdout(assert: &Assert) -> String {
+    String::from_utf8_lossy(&assert.get_output().stdout).to_string()
+}
+
+pub fn stderr(assert: &Assert) -> String {
+    String::from_utf8_lossy(&assert.get_output().stderr).to_string()
+}
+
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .current_dir(dir)
+        .args([
+            "-c",
+            "user.name=buckle",
+            "-c",
+            "user.email=buckle@example.com",
+            "-c",
+            "protocol.file.allow=always",
+        ])
+        .args(args)
+        .output()
+        .expect("git must be installed to run this test");
+    assert!(
+        output.status.success(),
+        "git {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Create a git repo in `root` with a `.buckconfig` pointing at a `prelude` submodule cloned
+/// from a fresh repo in `upstream`. Returns the commit the submodule is checked out at.
+pub fn init_project_with_prelude(root: &Path, upstream: &Path) -> String {
+    git(upstream, &["init", "-q"]);
+    fs::write(upstream.join("prelude.bzl"), "").unwrap();
+    git(upstream, &["add", "."]);
+    git(upstream, &["commit", "-q", "-m", "prelude"]);
+    let prelude_hash = git(upstream, &["rev-parse", "HEAD"]);
+
+    git(root, &["init", "-q"]);
+    fs::write(
+        root.join(".buckconfig"),
+        "[repositories]\nprelude = prelude\n",
+    )
+    .unwrap();
+    git(
+        root,
+        &[
+            "submodule",
+            "add",
+            "-q",
+            upstream.to_str().unwrap(),
+            "prelude",
+        ],
+    );
+    git(root, &["add", "."]);
+    git(root, &["commit", "-q", "-m", "project"]);
+    prelude_hash
+}
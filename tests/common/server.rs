@@ -0,0 +1,194 @@
+//! A tiny HTTP server standing in for GitHub and release mirrors in tests.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status: 200,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Serve a `Range: bytes=<start>-<end>` request with just those bytes, as a 206.
+    pub fn with_ranges(self) -> Self {
+        self.with_header("Accept-Ranges", "bytes")
+    }
+
+    fn range(&self, request: &Request) -> Option<Self> {
+        if !self
+            .headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case("accept-ranges"))
+        {
+            return None;
+        }
+        let (start, end) = request
+            .header("range")?
+            .strip_prefix("bytes=")?
+            .split_once('-')?;
+        let start: usize = start.parse().ok()?;
+        let end = end
+            .parse::<usize>()
+            .ok()?
+            .min(self.body.len().checked_sub(1)?);
+        if start > end {
+            return Some(Response::status(416));
+        }
+        let mut ranged = self.clone();
+        ranged.status = 206;
+        ranged.body = self.body[start..=end].to_vec();
+        Some(ranged.with_header(
+            "Content-Range",
+            &format!("bytes {start}-{end}/{}", self.body.len()),
+        ))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    routes: HashMap<String, Response>,
+    requests: Vec<Request>,
+}
+
+/// Serves mounted responses by path and records every request it sees. Anything not mounted
+/// is a 404.
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = server_state.clone();
+                thread::spawn(move || handle(stream, &state));
+            }
+        });
+        MockServer { url, state }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn mount(&self, path: &str, response: Response) {
+        let mut state = self.state.lock().unwrap();
+        state.routes.insert(path.to_string(), response);
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// How many requests were made for `path`.
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests()
+            .iter()
+            .filter(|request| request.path == path)
+            .count()
+    }
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() {
+            return;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let request = Request {
+        method,
+        path,
+        headers,
+    };
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        match state.routes.get(&request.path) {
+            Some(response) => response.range(&request).unwrap_or_else(|| response.clone()),
+            None => Response::status(404),
+        }
+    };
+
+    let mut head = format!("HTTP/1.1 {} Mock\r\nConnection: close\r\n", response.status);
+    let has_length = response
+        .headers
+        .iter()
+        .any(|(key, _)| key.eq_ignore_ascii_case("content-length"));
+    if !has_length {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{key}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    let mut stream = &stream;
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&response.body);
+    let _ = stream.flush();
+}
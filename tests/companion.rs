@@ -0,0 +1,98 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+const RUST_PROJECT: &[u8] = b"#!/bin/sh\necho \"rust-project stub: $*\"\n";
+
+/// A mock release of [`TAG`] that also ships `rust-project`.
+fn companion_server() -> MockServer {
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    server.mount(
+        &format!("/download/{TAG}/rust-project-{}.zst", host_triple()),
+        Response::ok(zstd::encode_all(RUST_PROJECT, 0).unwrap()),
+    );
+    server
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_companion() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = companion_server();
+    let path = format!("/download/{TAG}/rust-project-{}.zst", host_triple());
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_BINARY", "rust-project")
+        .args(["develop", "--stdout"])
+        .assert()
+        .success();
+    let stdout_run = stdout(&assert);
+    assert!(
+        stdout_run.contains("rust-project stub: develop --stdout"),
+        "found {stdout_run}"
+    );
+    let installed = version_dir(cache.path(), COMMITISH).join("rust-project");
+    assert_eq!(std::fs::read(installed).unwrap(), RUST_PROJECT);
+
+    // Once cached it runs without another download.
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_BINARY", "rust-project")
+        .arg("develop")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("rust-project stub: develop"));
+    assert_eq!(server.hits(&path), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_missing_companion() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = companion_server();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_BINARY", "buck2-lsp")
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!("buck2 {TAG} has no buck2-lsp binary for")),
+        "found {stderr}"
+    );
+}
+
+#[test]
+fn test_invalid_companion_name() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_BINARY", "../buck2")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("BUCKLE_BINARY must name a binary from the buck2 release"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_buck2_run_passes_through() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = companion_server();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args(["run", "//foo:bar"])
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(
+        stdout.contains("arg: run\narg: //foo:bar"),
+        "found {stdout}"
+    );
+    let path = format!("/download/{TAG}/rust-project-{}.zst", host_triple());
+    assert_eq!(server.hits(&path), 0);
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+fn write_config(dir: &TempDir, contents: &str) -> std::path::PathBuf {
+    let path = dir.path().join("config.toml");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+/// The cache location can come from the config file.
+#[cfg(unix)]
+#[test]
+fn test_config_sets_cache() {
+    let cache = TempDir::new().unwrap();
+    let config_dir = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let config = write_config(
+        &config_dir,
+        &format!("cache = {:?}\n", cache.path().to_str().unwrap()),
+    );
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env_remove("BUCKLE_CACHE")
+        .env("BUCKLE_CONFIG", config)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// An environment variable wins over the config file.
+#[cfg(unix)]
+#[test]
+fn test_env_overrides_config() {
+    let cache = TempDir::new().unwrap();
+    let config_dir = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    // The configured cache is empty and offline, so using it would fail.
+    let config = write_config(
+        &config_dir,
+        &format!(
+            "cache = {:?}\noffline = true\n",
+            config_dir.path().to_str().unwrap()
+        ),
+    );
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_CONFIG", config)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// A config file that doesn't parse is reported with its path.
+#[test]
+fn test_invalid_config() {
+    let cache = TempDir::new().unwrap();
+    let config_dir = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let config = write_config(&config_dir, "cache = [\n");
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_CONFIG", &config)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("Could not parse"), "found {stderr}");
+    assert!(stderr.contains("config.toml"), "found {stderr}");
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+const OTHER_TAG: &str = "2023-08-01";
+const OTHER_COMMITISH: &str = "1111111111111111111111111111111111111111";
+
+/// With `BUCKLE_KILL_STALE_DAEMON=1`, moving a project to another buck2 runs `buck2 kill` with
+/// the one it ran before, once, then carries on with the new one.
+#[cfg(unix)]
+#[test]
+fn test_version_change_kills_stale_daemon() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let log = cache.path().join("old-buck2.log");
+    seed_releases(
+        cache.path(),
+        &[release(OTHER_TAG, OTHER_COMMITISH), release(TAG, COMMITISH)],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let old_dir = seed_version(cache.path(), OTHER_COMMITISH, PRELUDE_HASH.as_bytes());
+    write_script(
+        &old_dir.join("buck2"),
+        &format!("echo \"$@ in $PWD\" >> {}\n", log.display()),
+    );
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    let run = |version: &str| {
+        buckle(cache.path(), project.path())
+            .env("USE_BUCK2_VERSION", version)
+            .env("BUCKLE_KILL_STALE_DAEMON", "1")
+            .arg("build")
+            .assert()
+            .success()
+    };
+
+    run(TAG);
+    run(TAG);
+    run(OTHER_TAG);
+    let root = project.path().canonicalize().unwrap();
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        format!("build in {}\n", root.display())
+    );
+
+    let assert = run(TAG);
+    assert!(stdout(&assert).contains("buck2 stub"));
+    let reported = stderr(&assert);
+    assert!(
+        reported.contains(&format!(
+            "this project last ran buck2 {OTHER_TAG}, stopping its daemon for {TAG}"
+        )),
+        "found {reported}"
+    );
+    let lines = fs::read_to_string(&log).unwrap();
+    assert_eq!(lines.lines().count(), 2, "found {lines}");
+    assert!(lines.ends_with(&format!("kill in {}\n", root.display())));
+
+    run(TAG);
+    assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 2);
+}
+
+/// Without it, nothing is killed when the version changes.
+#[cfg(unix)]
+#[test]
+fn test_stale_daemon_left_alone_by_default() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let log = cache.path().join("old-buck2.log");
+    seed_releases(
+        cache.path(),
+        &[release(OTHER_TAG, OTHER_COMMITISH), release(TAG, COMMITISH)],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let old_dir = seed_version(cache.path(), OTHER_COMMITISH, PRELUDE_HASH.as_bytes());
+    write_script(
+        &old_dir.join("buck2"),
+        &format!("echo \"$@\" >> {}\n", log.display()),
+    );
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+
+    for version in [OTHER_TAG, TAG] {
+        buckle(cache.path(), project.path())
+            .env("USE_BUCK2_VERSION", version)
+            .arg("build")
+            .assert()
+            .success();
+    }
+    assert_eq!(fs::read_to_string(&log).unwrap(), "build\n");
+}
+
+/// The kill goes to the daemon of the isolation dir `BUCKLE_BUCK2_ARGS` runs buck2 in.
+#[cfg(unix)]
+#[test]
+fn test_stale_daemon_kill_uses_default_args() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let log = cache.path().join("old-buck2.log");
+    seed_releases(
+        cache.path(),
+        &[release(OTHER_TAG, OTHER_COMMITISH), release(TAG, COMMITISH)],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let old_dir = seed_version(cache.path(), OTHER_COMMITISH, PRELUDE_HASH.as_bytes());
+    write_script(
+        &old_dir.join("buck2"),
+        &format!("echo \"$@\" >> {}\n", log.display()),
+    );
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+
+    for version in [OTHER_TAG, TAG] {
+        buckle(cache.path(), project.path())
+            .env("USE_BUCK2_VERSION", version)
+            .env("BUCKLE_KILL_STALE_DAEMON", "1")
+            .env("BUCKLE_BUCK2_ARGS", "--isolation-dir ci")
+            .arg("build")
+            .assert()
+            .success();
+    }
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        "--isolation-dir ci build\n--isolation-dir ci kill\n"
+    );
+}
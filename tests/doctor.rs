@@ -0,0 +1,118 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+#[cfg(unix)]
+#[test]
+fn test_doctor_healthy() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("doctor")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains("[ ok ] cache: "), "found {stdout}");
+    assert!(stdout.contains("[ ok ] network: "), "found {stdout}");
+    assert!(
+        stdout.contains(&format!("[ ok ] version: {TAG}")),
+        "found {stdout}"
+    );
+    assert!(stdout.contains("[ ok ] binary: "), "found {stdout}");
+    assert!(!stdout.contains("FAIL"), "found {stdout}");
+    assert!(!stdout.contains("buck2 stub"), "ran buck2: {stdout}");
+}
+
+#[test]
+fn test_doctor_rate_limited() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    server.mount(
+        "/releases",
+        Response::status(403).with_header("x-ratelimit-remaining", "0"),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("doctor")
+        .assert()
+        .failure();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains("[FAIL] network: "), "found {stdout}");
+    assert!(stdout.contains("is rate limited"), "found {stdout}");
+    assert!(stdout.contains("[FAIL] version: "), "found {stdout}");
+    assert!(stdout.contains("[skip] binary: "), "found {stdout}");
+    assert!(stderr(&assert).contains("checks failed"));
+}
+
+#[test]
+fn test_doctor_not_downloaded() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("doctor")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains("[warn] binary: "), "found {stdout}");
+    assert!(stdout.contains("is not downloaded yet"), "found {stdout}");
+    assert_eq!(
+        server.hits(&format!("/download/{TAG}/buck2-{}.zst", host_triple())),
+        0
+    );
+}
+
+/// `--verify-cache` re-hashes every cached version and reports each, in order, failing on the
+/// one that no longer matches.
+#[cfg(unix)]
+#[test]
+fn test_doctor_verify_cache() {
+    use sha2::{Digest, Sha256};
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let digest: String = Sha256::digest(stub_buck2())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let commitishes = [
+        "1111111111111111111111111111111111111111",
+        "2222222222222222222222222222222222222222",
+        "3333333333333333333333333333333333333333",
+        "4444444444444444444444444444444444444444",
+    ];
+    for (index, commitish) in commitishes.iter().enumerate() {
+        let dir = seed_version(cache.path(), commitish, PRELUDE_HASH.as_bytes());
+        let recorded = if index == 2 {
+            "0".repeat(64)
+        } else {
+            digest.clone()
+        };
+        std::fs::write(dir.join("buck2.sha256"), recorded).unwrap();
+    }
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args(["doctor", "--verify-cache"])
+        .assert()
+        .failure();
+    let stdout = stdout(&assert);
+    let integrity: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.contains("] integrity: "))
+        .collect();
+    assert_eq!(integrity.len(), 4, "found {stdout}");
+    for (line, commitish) in integrity.iter().zip(commitishes) {
+        assert!(line.contains(commitish), "found {stdout}");
+    }
+    assert!(integrity[0].starts_with("[ ok ]"), "found {stdout}");
+    assert!(integrity[1].starts_with("[ ok ]"), "found {stdout}");
+    assert!(integrity[2].starts_with("[FAIL]"), "found {stdout}");
+    assert!(integrity[3].starts_with("[ ok ]"), "found {stdout}");
+}
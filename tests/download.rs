@@ -0,0 +1,969 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+/// Two versions whose binaries are identical share a single object in the cache.
+#[cfg(unix)]
+#[test]
+fn test_identical_binaries_are_deduplicated() {
+    use std::os::unix::fs::MetadataExt;
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let other_tag = "2023-08-01";
+    let other_commitish = "1111111111111111111111111111111111111111";
+    mount_releases(
+        &server,
+        &[release(TAG, COMMITISH), release(other_tag, other_commitish)],
+    );
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    mount_release(&server, other_tag, &stub_buck2(), PRELUDE_HASH);
+
+    for tag in [TAG, other_tag] {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("USE_BUCK2_VERSION", tag)
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+
+    let objects = buckle_dir(cache.path()).join("buck2").join("objects");
+    assert_eq!(std::fs::read_dir(&objects).unwrap().count(), 1);
+    let first = version_dir(cache.path(), COMMITISH).join("buck2");
+    let second = version_dir(cache.path(), other_commitish).join("buck2");
+    assert_eq!(
+        first.metadata().unwrap().ino(),
+        second.metadata().unwrap().ino()
+    );
+}
+
+/// Every file is renamed into place whole, so a finished install has a complete prelude_hash
+/// and no temporary files left beside it.
+#[cfg(unix)]
+#[test]
+fn test_download_leaves_only_complete_files() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+
+    let dir = version_dir(cache.path(), COMMITISH);
+    let prelude_hash = std::fs::read_to_string(dir.join("prelude_hash")).unwrap();
+    assert!(!prelude_hash.is_empty());
+    assert_eq!(prelude_hash, PRELUDE_HASH);
+    let mut entries: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    entries.sort();
+    assert_eq!(
+        entries,
+        ["asset.sha256", "buck2", "buck2.sha256", "prelude_hash"]
+    );
+}
+
+/// A fresh install fetches both the binary and its prelude hash.
+#[cfg(unix)]
+#[test]
+fn test_download_installs_binary_and_prelude_hash() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+
+    let dir = version_dir(cache.path(), COMMITISH);
+    assert_eq!(std::fs::read(dir.join("buck2")).unwrap(), stub_buck2());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("prelude_hash")).unwrap(),
+        PRELUDE_HASH
+    );
+    assert_eq!(server.hits(&format!("/download/{TAG}/prelude_hash")), 1);
+}
+
+/// A failed prelude_hash fetch fails the install rather than leaving a binary without one.
+#[cfg(unix)]
+#[test]
+fn test_prelude_hash_fetch_failure() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    server.mount(
+        &format!("/download/{TAG}/prelude_hash"),
+        Response::status(500),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("Could not fetch prelude_hash"));
+    let dir = version_dir(cache.path(), COMMITISH);
+    assert!(!dir.join("buck2").exists());
+}
+
+/// A cache shared between platforms keeps a separate binary for each triple.
+#[cfg(unix)]
+#[test]
+fn test_triples_have_distinct_cache_paths() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let other_triple = if host_triple() == "aarch64-apple-darwin" {
+        "x86_64-unknown-linux-musl"
+    } else {
+        "aarch64-apple-darwin"
+    };
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    mount_release_for(&server, TAG, other_triple, &stub_buck2(), PRELUDE_HASH);
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_TRIPLE", other_triple)
+        .assert()
+        .success();
+
+    let commitish_dir = buckle_dir(cache.path()).join(COMMITISH);
+    assert!(commitish_dir.join(host_triple()).join("buck2").exists());
+    assert!(commitish_dir.join(other_triple).join("buck2").exists());
+    assert_eq!(
+        server.hits(&format!("/download/{TAG}/buck2-{other_triple}.zst")),
+        1
+    );
+}
+
+/// A binary cached before the cache was keyed by triple is moved rather than re-downloaded.
+#[cfg(unix)]
+#[test]
+fn test_untripled_cache_is_migrated() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let old_dir = buckle_dir(cache.path()).join(COMMITISH);
+    write_stub_buck2(&old_dir.join("buck2"));
+    std::fs::write(old_dir.join("prelude_hash"), PRELUDE_HASH).unwrap();
+
+    let assert = buckle(cache.path(), cwd.path()).assert().success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert!(!old_dir.join("buck2").exists());
+    let dir = version_dir(cache.path(), COMMITISH);
+    assert_eq!(std::fs::read(dir.join("buck2")).unwrap(), stub_buck2());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("prelude_hash")).unwrap(),
+        PRELUDE_HASH
+    );
+}
+
+/// Several buckles adopting the same old-layout binary at once all run it, offline, and leave
+/// it in the new layout.
+#[cfg(unix)]
+#[test]
+fn test_untripled_cache_is_migrated_concurrently() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let old_dir = buckle_dir(cache.path()).join(COMMITISH);
+    write_stub_buck2(&old_dir.join("buck2"));
+    std::fs::write(old_dir.join("prelude_hash"), PRELUDE_HASH).unwrap();
+
+    let runs: Vec<_> = (0..8)
+        .map(|_| {
+            let (cache, cwd) = (cache.path().to_path_buf(), cwd.path().to_path_buf());
+            std::thread::spawn(move || {
+                buckle(&cache, &cwd)
+                    .env("BUCKLE_OFFLINE", "1")
+                    .output()
+                    .unwrap()
+            })
+        })
+        .collect();
+    for run in runs {
+        let output = run.join().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+        assert!(!stderr.contains("could not move"), "{stderr}");
+        assert!(String::from_utf8_lossy(&output.stdout).contains("buck2 stub"));
+    }
+    assert!(!old_dir.join("buck2").exists());
+    assert!(!old_dir.join("prelude_hash").exists());
+    let dir = version_dir(cache.path(), COMMITISH);
+    assert_eq!(std::fs::read(dir.join("buck2")).unwrap(), stub_buck2());
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The hash of the downloaded binary is stored alongside it.
+#[cfg(unix)]
+#[test]
+fn test_download_stores_sha256() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    let sha256 = version_dir(cache.path(), COMMITISH).join("buck2.sha256");
+    assert_eq!(
+        std::fs::read_to_string(sha256).unwrap(),
+        sha256_hex(&stub_buck2())
+    );
+}
+
+/// `buck2` wrapped in a tar archive, alongside another file, as a repackaging mirror might.
+fn tar_wrapped(buck2: &[u8]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (path, contents) in [("buck2/LICENSE", &b"MIT"[..]), ("buck2/buck2", buck2)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, contents).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+/// An archive that decompresses to a tarball has its buck2 extracted, while a bare binary is
+/// installed as is.
+#[cfg(unix)]
+#[test]
+fn test_tar_wrapped_archive() {
+    for (name, archive) in [("raw", stub_buck2()), ("tar", tar_wrapped(&stub_buck2()))] {
+        let cache = TempDir::new().unwrap();
+        let cwd = TempDir::new().unwrap();
+        let server = MockServer::start();
+        mount_releases(&server, &[release(TAG, COMMITISH)]);
+        mount_release(&server, TAG, &archive, PRELUDE_HASH);
+
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"), "{name}");
+        let dir = version_dir(cache.path(), COMMITISH);
+        assert_eq!(
+            std::fs::read(dir.join("buck2")).unwrap(),
+            stub_buck2(),
+            "{name}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("buck2.sha256")).unwrap(),
+            sha256_hex(&stub_buck2()),
+            "{name}"
+        );
+    }
+}
+
+/// A tarball without a buck2 in it is an error rather than an install of the wrong file.
+#[cfg(unix)]
+#[test]
+fn test_tar_without_buck2() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "README", &b"hi\n"[..])
+        .unwrap();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &builder.into_inner().unwrap(), PRELUDE_HASH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("without a buck2 in it"));
+    assert!(!version_dir(cache.path(), COMMITISH).join("buck2").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_verify_on_run() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    std::fs::write(dir.join("buck2.sha256"), sha256_hex(&stub_buck2())).unwrap();
+    buckle(cache.path(), cwd.path())
+        .env("BUCKLE_VERIFY_ON_RUN", "1")
+        .assert()
+        .success();
+
+    // Simulate the binary rotting on disk after it was downloaded.
+    let mut rotten = stub_buck2();
+    rotten.extend_from_slice(b"\n# bit rot\n");
+    std::fs::write(dir.join("buck2"), rotten).unwrap();
+    buckle(cache.path(), cwd.path()).assert().success();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_VERIFY_ON_RUN", "1")
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("cache is corrupted"), "found {stderr}");
+    assert!(!stdout(&assert).contains("buck2 stub"));
+}
+
+/// A run from an empty cache goes through the releases list, the download and buck2 itself.
+#[cfg(unix)]
+#[test]
+fn test_end_to_end_with_mock_github() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args(["build", "//..."])
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains(COMMITISH), "found {stdout}");
+    assert!(stdout.contains("arg: build\narg: //..."), "found {stdout}");
+    assert!(buckle_dir(cache.path()).join("releases.json").exists());
+    assert_eq!(server.hits("/releases"), 1);
+
+    // A second run is served entirely from the cache.
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert_eq!(server.hits("/releases"), 1);
+    assert_eq!(
+        server.hits(&format!("/download/{TAG}/buck2-{}.zst", host_triple())),
+        1
+    );
+}
+
+/// An archive cut short mid-transfer is reported as truncated and nothing is installed.
+#[cfg(unix)]
+#[test]
+fn test_truncated_download() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let archive = zstd::encode_all(&stub_buck2()[..], 0).unwrap();
+    server.mount(
+        &format!("/download/{TAG}/buck2-{}.zst", host_triple()),
+        Response::ok(&archive[..archive.len() / 2])
+            .with_header("Content-Length", &archive.len().to_string()),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("was truncated"), "found {stderr}");
+    assert!(stderr.contains("Please retry"));
+    let dir = version_dir(cache.path(), COMMITISH);
+    assert!(!dir.join("buck2").exists());
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+
+    // Retrying once the archive is served in full succeeds.
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// A downloaded buck2 ends up executable by everyone.
+#[cfg(unix)]
+#[test]
+fn test_download_is_executable() {
+    use std::os::unix::fs::PermissionsExt;
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    let buck2 = version_dir(cache.path(), COMMITISH).join("buck2");
+    let mode = std::fs::metadata(buck2).unwrap().permissions().mode();
+    assert_eq!(mode & 0o755, 0o755, "found {mode:o}");
+}
+
+/// Every request carries the buckle user-agent, prefixed with `BUCKLE_USER_AGENT`.
+#[cfg(unix)]
+#[test]
+fn test_user_agent() {
+    let version = env!("CARGO_PKG_VERSION");
+    for (configured, expected) in [
+        (None, format!("buckle/{version}")),
+        (Some("acme-ci"), format!("acme-ci buckle/{version}")),
+    ] {
+        let cache = TempDir::new().unwrap();
+        let cwd = TempDir::new().unwrap();
+        let server = mock_github();
+        let mut cmd = buckle_with_server(cache.path(), cwd.path(), &server);
+        if let Some(configured) = configured {
+            cmd.env("BUCKLE_USER_AGENT", configured);
+        }
+        cmd.assert().success();
+
+        let requests = server.requests();
+        let paths: Vec<&str> = requests
+            .iter()
+            .map(|request| request.path.as_str())
+            .collect();
+        assert!(paths.contains(&"/releases"), "found {paths:?}");
+        assert!(paths.contains(&format!("/download/{TAG}/prelude_hash").as_str()));
+        assert!(paths.contains(&format!("/download/{TAG}/buck2-{}.zst", host_triple()).as_str()));
+        for request in &requests {
+            assert_eq!(
+                request.header("user-agent"),
+                Some(expected.as_str()),
+                "{}",
+                request.path
+            );
+        }
+    }
+}
+
+/// A mirror serving a web page instead of the archive is reported as such.
+#[cfg(unix)]
+#[test]
+fn test_html_instead_of_archive() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    server.mount(
+        &format!("/download/{TAG}/buck2-{}.zst", host_triple()),
+        Response::ok("<!DOCTYPE html><p>Sign in</p>")
+            .with_header("Content-Type", "text/html; charset=utf-8"),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("is not a zstd archive"), "found {stderr}");
+    assert!(
+        stderr.contains("text/html; charset=utf-8"),
+        "found {stderr}"
+    );
+    assert!(stderr.contains("3c 21 44 4f 43 54 59 50"), "found {stderr}");
+    assert!(stderr.contains("<!DOCTYPE html>"), "found {stderr}");
+    assert!(stderr.contains("check that the mirror"), "found {stderr}");
+    assert!(!version_dir(cache.path(), COMMITISH).join("buck2").exists());
+}
+
+fn with_assets(tag: &str, commitish: &str, names: &[&str]) -> serde_json::Value {
+    let mut release = release(tag, commitish);
+    release["assets"] = names
+        .iter()
+        .map(|name| serde_json::json!({ "name": name }))
+        .collect();
+    release
+}
+
+/// A release that publishes this platform's binary downloads as usual.
+#[cfg(unix)]
+#[test]
+fn test_release_with_arch_asset() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let asset = format!("buck2-{}.zst", host_triple());
+    mount_releases(
+        &server,
+        &[with_assets(TAG, COMMITISH, &["prelude_hash", &asset])],
+    );
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// A release that exists but has no binary for this platform says so, rather than claiming the
+/// version does not exist.
+#[test]
+fn test_release_without_arch_asset() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(
+        &server,
+        &[with_assets(
+            TAG,
+            COMMITISH,
+            &["buck2-riscv64-unknown-linux-gnu.zst", "prelude_hash"],
+        )],
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "buck2 {TAG} exists but has no binary for {}",
+            host_triple()
+        )),
+        "found {stderr}"
+    );
+    assert!(stderr.contains("buck2-riscv64-unknown-linux-gnu.zst, prelude_hash"));
+    assert!(!stderr.contains("was not available"));
+    assert_eq!(server.hits(&format!("/download/{TAG}/prelude_hash")), 0);
+}
+
+/// A reader polling the version directory during a download only ever sees all of it or
+/// none of it.
+#[cfg(unix)]
+#[test]
+fn test_version_dir_appears_complete() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    for _ in 0..5 {
+        let cache = TempDir::new().unwrap();
+        let cwd = TempDir::new().unwrap();
+        let server = mock_github();
+        let dir = version_dir(cache.path(), COMMITISH);
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let dir = dir.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut partial = vec![];
+                while !done.load(Ordering::SeqCst) {
+                    // One listing is a consistent snapshot, unlike checking each file in turn.
+                    let Ok(entries) = std::fs::read_dir(&dir) else {
+                        continue;
+                    };
+                    let mut names: Vec<_> = entries
+                        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                        .collect();
+                    names.sort();
+                    if !names.is_empty()
+                        && names != ["asset.sha256", "buck2", "buck2.sha256", "prelude_hash"]
+                    {
+                        partial.push(names);
+                    }
+                }
+                partial
+            })
+        };
+        buckle_with_server(cache.path(), cwd.path(), &server)
+            .assert()
+            .success();
+        done.store(true, Ordering::SeqCst);
+        let partial = reader.join().unwrap();
+        assert!(partial.is_empty(), "saw partial version dirs {partial:?}");
+        assert!(dir.join("buck2").exists());
+        let leftovers: Vec<_> = std::fs::read_dir(dir.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers, [host_triple()], "found {leftovers:?}");
+    }
+}
+
+/// A release whose inline assets are cut short is paged through `assets_url` before its
+/// binary is declared missing.
+#[cfg(unix)]
+#[test]
+fn test_paged_assets() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let mut release = with_assets(TAG, COMMITISH, &["buck2-riscv64-unknown-linux-gnu.zst"]);
+    release["assets_url"] = format!("{}/assets", server.url()).into();
+    mount_releases(&server, &[release]);
+    let first_page: Vec<_> = (0..100)
+        .map(|index| serde_json::json!({ "name": format!("extra-{index}") }))
+        .collect();
+    let second_page = vec![serde_json::json!({ "name": format!("buck2-{}.zst", host_triple()) })];
+    server.mount(
+        "/assets?per_page=100&page=1",
+        Response::ok(serde_json::to_string(&first_page).unwrap()),
+    );
+    server.mount(
+        "/assets?per_page=100&page=2",
+        Response::ok(serde_json::to_string(&second_page).unwrap()),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert_eq!(server.hits("/assets?per_page=100&page=2"), 1);
+    assert_eq!(server.hits("/assets?per_page=100&page=3"), 0);
+}
+
+/// With `BUCKLE_ARCH_FALLBACK=1`, a pin without this platform's binary falls back to the
+/// newest older release that has one, and says so.
+#[cfg(unix)]
+#[test]
+fn test_arch_fallback() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let asset = format!("buck2-{}.zst", host_triple());
+    let older_tag = "2023-07-01";
+    let older_commitish = "1111111111111111111111111111111111111111";
+    mount_releases(
+        &server,
+        &[
+            with_assets(
+                "2023-08-01",
+                "3333333333333333333333333333333333333333",
+                &[&asset],
+            ),
+            with_assets(TAG, COMMITISH, &["buck2-riscv64-unknown-linux-gnu.zst"]),
+            with_assets(older_tag, older_commitish, &[&asset]),
+            with_assets(
+                "2023-06-01",
+                "2222222222222222222222222222222222222222",
+                &[&asset],
+            ),
+        ],
+    );
+    mount_release(&server, older_tag, &stub_buck2(), PRELUDE_HASH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_ARCH_FALLBACK", "1")
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "WARNING: buck2 {TAG} has no binary for {}, so {older_tag} is being used instead",
+            host_triple()
+        )),
+        "found {stderr}"
+    );
+    assert!(stdout(&assert).contains(older_commitish));
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_ARCH_FALLBACK", "1")
+        .arg("--buckle-env")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(&format!("tag: {older_tag}")));
+}
+
+#[test]
+fn test_missing_version() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("USE_BUCK2_VERSION", "2020-01-01")
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("2020-01-01 was not available"),
+        "found {stderr}"
+    );
+    assert!(!stderr.contains("has no binary"));
+}
+
+/// The download reports its size from Content-Length, then how long it took.
+#[cfg(unix)]
+#[test]
+fn test_download_reports_size() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let archive_len = zstd::encode_all(&stub_buck2()[..], 0).unwrap().len();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    let reported = stderr(&assert);
+    assert!(
+        reported.contains(&format!("fetching buck2 {TAG} ({archive_len} B)")),
+        "found {reported}"
+    );
+    assert!(
+        reported.contains(&format!("fetched buck2 {TAG} in ")),
+        "found {reported}"
+    );
+
+    let cache = TempDir::new().unwrap();
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_NO_PROGRESS", "1")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("fetch"));
+}
+
+/// Run buckle in a project pinning `.buckversion` to `spec`, downloading from `server`.
+#[cfg(unix)]
+fn run_pinned(cache: &TempDir, server: &MockServer, spec: &str) -> assert_cmd::assert::Assert {
+    let project = TempDir::new().unwrap();
+    std::fs::write(project.path().join(".buckconfig"), "").unwrap();
+    std::fs::write(project.path().join(".buckversion"), format!("{spec}\n")).unwrap();
+    buckle_with_server(cache.path(), project.path(), server)
+        .env_remove("USE_BUCK2_VERSION")
+        .assert()
+}
+
+/// A version pinned to the digest of its binary installs and runs it.
+#[cfg(unix)]
+#[test]
+fn test_pinned_digest_matches() {
+    let cache = TempDir::new().unwrap();
+    let server = mock_github();
+    let spec = format!("{TAG}@sha256:{}", sha256_hex(&stub_buck2()));
+
+    for _ in 0..2 {
+        let assert = run_pinned(&cache, &server, &spec).success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+}
+
+/// A binary whose digest doesn't match the pin is never installed or run, even once cached.
+#[cfg(unix)]
+#[test]
+fn test_pinned_digest_mismatch() {
+    let cache = TempDir::new().unwrap();
+    let server = mock_github();
+    let wrong = "0".repeat(64);
+
+    let assert = run_pinned(&cache, &server, &format!("{TAG}@sha256:{wrong}")).failure();
+    let stderr_download = stderr(&assert);
+    assert!(
+        stderr_download.contains(&format!(
+            "its SHA256 is {}, but {wrong} is pinned",
+            sha256_hex(&stub_buck2())
+        )),
+        "found {stderr_download}"
+    );
+    assert!(!stdout(&assert).contains("buck2 stub"));
+    assert!(!version_dir(cache.path(), COMMITISH).join("buck2").exists());
+
+    run_pinned(&cache, &server, TAG).success();
+    let assert = run_pinned(&cache, &server, &format!("{TAG}@sha256:{wrong}")).failure();
+    assert!(stderr(&assert).contains(&format!("The cached buck2 {TAG} at ")));
+    assert!(!stdout(&assert).contains("buck2 stub"));
+}
+
+#[test]
+fn test_malformed_digest() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", format!("{TAG}@sha256:abc"))
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("expected <version>@sha256:<64 hex digits>"));
+}
+
+/// A version directory missing its binary or its prelude_hash is downloaded again rather than
+/// treated as installed.
+#[cfg(unix)]
+#[test]
+fn test_incomplete_version_is_repaired() {
+    for missing in ["buck2", "prelude_hash"] {
+        let cache = TempDir::new().unwrap();
+        let cwd = TempDir::new().unwrap();
+        let server = mock_github();
+        let dir = seed_version(cache.path(), COMMITISH, b"stale");
+        std::fs::remove_file(dir.join(missing)).unwrap();
+
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .assert()
+            .success();
+        let stderr = stderr(&assert);
+        assert!(
+            stderr.contains("is incomplete"),
+            "{missing}: found {stderr}"
+        );
+        assert!(stdout(&assert).contains("buck2 stub"), "{missing}");
+        assert_eq!(std::fs::read(dir.join("buck2")).unwrap(), stub_buck2());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("prelude_hash")).unwrap(),
+            PRELUDE_HASH
+        );
+    }
+}
+
+/// `BUCKLE_POST_DOWNLOAD_HOOK` runs once when a version is first installed, with its tag and
+/// path, and not when it is already cached.
+#[cfg(unix)]
+#[test]
+fn test_post_download_hook() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let hook = cache.path().join("hook.sh");
+    let log = cache.path().join("hook.log");
+    write_script(
+        &hook,
+        &format!(
+            "echo \"$1 $2 $BUCKLE_DOWNLOADED_VERSION\" >> {}\necho hooked\n",
+            log.display()
+        ),
+    );
+
+    for _ in 0..2 {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("BUCKLE_POST_DOWNLOAD_HOOK", &hook)
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+        assert!(!stdout(&assert).contains("hooked"));
+    }
+    let buck2 = version_dir(cache.path(), COMMITISH).join("buck2");
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        format!("{TAG} {} {TAG}\n", buck2.display())
+    );
+}
+
+/// A failing hook only warns, unless `BUCKLE_POST_DOWNLOAD_STRICT=1` makes it fatal.
+#[cfg(unix)]
+#[test]
+fn test_post_download_hook_failure() {
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let cache = TempDir::new().unwrap();
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_POST_DOWNLOAD_HOOK", "false")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert!(stderr(&assert).contains("the post-download hook for buck2 2023-07-15 failed"));
+
+    let cache = TempDir::new().unwrap();
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_POST_DOWNLOAD_HOOK", "false")
+        .env("BUCKLE_POST_DOWNLOAD_STRICT", "1")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("The post-download hook for buck2 2023-07-15 failed"));
+}
+
+/// With `BUCKLE_PARALLEL_DOWNLOAD`, a server that supports ranges is asked for the archive in
+/// parts, and what is installed is byte for byte what a single stream would have installed.
+#[cfg(unix)]
+#[test]
+fn test_parallel_download() {
+    let mut buck2 = stub_buck2();
+    for line in 0..2000 {
+        buck2.extend(format!("# {}\n", line * 7919 % 10007).as_bytes());
+    }
+    let archive = zstd::encode_all(&buck2[..], 0).unwrap();
+    let server = mock_github();
+    let archive_path = format!("/download/{TAG}/buck2-{}.zst", host_triple());
+    server.mount(&archive_path, Response::ok(archive.clone()).with_ranges());
+    let cwd = TempDir::new().unwrap();
+
+    let single = TempDir::new().unwrap();
+    buckle_with_server(single.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    let ranged_requests = || {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.path == archive_path && request.header("range").is_some())
+            .count()
+    };
+    assert_eq!(ranged_requests(), 0);
+
+    let parallel = TempDir::new().unwrap();
+    let assert = buckle_with_server(parallel.path(), cwd.path(), &server)
+        .env("BUCKLE_PARALLEL_DOWNLOAD", "4")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert!(
+        stderr(&assert).contains(") in 4 parts"),
+        "found {}",
+        stderr(&assert)
+    );
+    // One probe for the length, then the four parts.
+    assert_eq!(ranged_requests(), 5);
+    let installed = |cache: &TempDir| {
+        std::fs::read(version_dir(cache.path(), COMMITISH).join("buck2")).unwrap()
+    };
+    assert_eq!(installed(&parallel), buck2);
+    assert_eq!(installed(&parallel), installed(&single));
+    // The ranges were assembled in a temporary file that didn't outlive the download.
+    let mut files: Vec<_> = std::fs::read_dir(version_dir(parallel.path(), COMMITISH))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        ["asset.sha256", "buck2", "buck2.sha256", "prelude_hash"]
+    );
+}
+
+/// A server claiming an archive far larger than any buck2 is refused before anything is
+/// allocated or fetched for it.
+#[cfg(unix)]
+#[test]
+fn test_parallel_download_rejects_huge_archive() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let archive_path = format!("/download/{TAG}/buck2-{}.zst", host_triple());
+    server.mount(
+        &archive_path,
+        Response::status(206)
+            .with_header("Content-Range", "bytes 0-0/1099511627776")
+            .with_header("Content-Length", "0"),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_PARALLEL_DOWNLOAD", "4")
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("claims to be 1099511627776 bytes"),
+        "found {stderr}"
+    );
+    assert_eq!(server.hits(&archive_path), 1);
+    assert!(!version_dir(cache.path(), COMMITISH).exists());
+}
+
+/// A server that ignores ranges just sends the whole archive, which is used as is.
+#[cfg(unix)]
+#[test]
+fn test_parallel_download_without_ranges() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_PARALLEL_DOWNLOAD", "64")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert!(stderr(&assert).contains("BUCKLE_PARALLEL_DOWNLOAD is capped at 16 parts"));
+    assert_eq!(
+        server.hits(&format!("/download/{TAG}/buck2-{}.zst", host_triple())),
+        1
+    );
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use common::*;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn list_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(list_files(&path));
+        }
+        files.push(path);
+    }
+    files.sort();
+    files
+}
+
+/// A dry run reports what it would fetch without writing anything to the cache.
+#[test]
+fn test_dry_run_writes_nothing() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let before = list_files(cache.path());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_DRY_RUN", "1")
+        .arg("build")
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("would fetch buck2-"), "found {stderr}");
+    assert!(stderr.contains(TAG), "found {stderr}");
+    assert!(stderr.contains(COMMITISH), "found {stderr}");
+    assert_eq!(before, list_files(cache.path()));
+}
+
+/// A dry run against an installed version says so and does not run buck2.
+#[cfg(unix)]
+#[test]
+fn test_dry_run_cached() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_DRY_RUN", "1")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("already cached"));
+    assert!(!stdout(&assert).contains("buck2 stub"));
+}
@@ -0,0 +1,494 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+/// Buckle's own configuration is not forwarded to buck2.
+#[cfg(unix)]
+#[test]
+fn test_buckle_env_is_scrubbed() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("UNRELATED_VAR", "kept")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(!stdout.contains("env: BUCKLE_CACHE="), "found {stdout}");
+    assert!(
+        !stdout.contains("env: USE_BUCK2_VERSION="),
+        "found {stdout}"
+    );
+    assert!(stdout.contains("env: UNRELATED_VAR=kept"), "found {stdout}");
+}
+
+/// `BUCKLE_KEEP_ENV=1` forwards the environment untouched.
+#[cfg(unix)]
+#[test]
+fn test_buckle_keep_env() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_KEEP_ENV", "1")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains("env: BUCKLE_CACHE="), "found {stdout}");
+}
+
+/// A buck2 killed by a signal exits with 128 + the signal number, like a shell would.
+#[cfg(unix)]
+#[test]
+fn test_signal_exit_code() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    write_script(&dir.join("buck2"), "kill -9 $$\n");
+
+    buckle(cache.path(), cwd.path()).assert().code(137);
+}
+
+/// `BUCKLE_EXEC_WRAPPER` runs buck2 under another command, quoting and all.
+#[cfg(unix)]
+#[test]
+fn test_exec_wrapper() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env(
+            "BUCKLE_EXEC_WRAPPER",
+            r#"env 'WRAPPED=under env' "QUOTED=\"yes\"""#,
+        )
+        .args(["build", "//:two words"])
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains("buck2 stub"), "found {stdout}");
+    assert!(
+        stdout.contains("arg: build\narg: //:two words\n"),
+        "found {stdout}"
+    );
+    assert!(stdout.contains("env: WRAPPED=under env"), "found {stdout}");
+    assert!(stdout.contains(r#"env: QUOTED="yes""#), "found {stdout}");
+}
+
+#[test]
+fn test_exec_wrapper_unterminated_quote() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_DRY_RUN", "1")
+        .env("BUCKLE_EXEC_WRAPPER", "nice 'oops")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("BUCKLE_EXEC_WRAPPER could not be parsed"));
+}
+
+/// `BUCKLE_BUCK2_ARGS` goes before the user's arguments, with buckle's own flags removed.
+#[cfg(unix)]
+#[test]
+fn test_default_buck2_args() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    std::fs::write(cwd.path().join(".buckconfig"), "").unwrap();
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_BUCK2_ARGS", "--isolation-dir 'ci dir'")
+        .args(["--buckle-root", cwd.path().to_str().unwrap()])
+        .args(["build", "//:target"])
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(
+        stdout.contains("arg: --isolation-dir\narg: ci dir\narg: build\narg: //:target\n"),
+        "found {stdout}"
+    );
+    assert!(!stdout.contains("--buckle-root"), "found {stdout}");
+}
+
+/// `BUCKLE_BUCK2_BIN` runs a local buck2 without downloading anything.
+#[cfg(unix)]
+#[test]
+fn test_buck2_bin_override() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let local = TempDir::new().unwrap();
+    let buck2_bin = local.path().join("buck2");
+    write_stub_buck2(&buck2_bin);
+    // Any attempt to fetch releases or binaries would fail against this.
+    let server = MockServer::start();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_BUCK2_BIN", &buck2_bin)
+        .arg("build")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(
+        stdout.contains(&format!("buck2 stub {}", buck2_bin.display())),
+        "found {stdout}"
+    );
+    assert!(stdout.contains("arg: build"), "found {stdout}");
+    assert!(server.requests().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_buck2_bin_override_not_executable() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let local = TempDir::new().unwrap();
+    let buck2_bin = local.path().join("buck2");
+    std::fs::write(&buck2_bin, stub_buck2()).unwrap();
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_BUCK2_BIN", &buck2_bin)
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("which is not executable"));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_BUCK2_BIN", local.path().join("missing"))
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("which is not a file"));
+}
+
+/// A cached buck2 that has lost its execute bit is reported along with its version and path.
+#[cfg(unix)]
+#[test]
+fn test_non_executable_cached_buck2() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let buck2 = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes()).join("buck2");
+    std::fs::set_permissions(&buck2, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+    let assert = buckle(cache.path(), cwd.path()).assert().failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "buck2 {TAG} at {} is not executable",
+            buck2.display()
+        )),
+        "found {stderr}"
+    );
+    assert!(
+        stderr.contains("Suggested fix is to remove"),
+        "found {stderr}"
+    );
+}
+
+/// `buckle bin-dir` prints just the directory holding an executable buck2, for `PATH`.
+#[cfg(unix)]
+#[test]
+fn test_bin_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_OFFLINE", "1")
+        .arg("bin-dir")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert_eq!(stdout.lines().count(), 1, "found {stdout}");
+    let buck2 = std::path::Path::new(stdout.trim_end()).join("buck2");
+    let mode = buck2.metadata().unwrap().permissions().mode();
+    assert!(mode & 0o111 != 0, "{} is not executable", buck2.display());
+    assert!(!stdout.contains("buck2 stub"));
+}
+
+/// Offline, a version that isn't cached is an error rather than an empty path.
+#[test]
+fn test_bin_dir_offline_not_cached() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_OFFLINE", "1")
+        .arg("bin-dir")
+        .assert()
+        .failure();
+    assert!(stdout(&assert).is_empty());
+    assert!(stderr(&assert).contains("is not cached and buckle is offline"));
+}
+
+/// `BUCKLE_TIMING=1` breaks down where the time went before buck2 started, and nothing is
+/// printed without it.
+#[cfg(unix)]
+#[test]
+fn test_timing() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_TIMING", "1")
+        .assert()
+        .success();
+    let stderr_timed = stderr(&assert);
+    for step in ["releases list", "download", "total before buck2"] {
+        assert!(
+            stderr_timed.contains(&format!("buckle: timing: {step}: ")),
+            "found {stderr_timed}"
+        );
+    }
+    assert!(stdout(&assert).contains("buck2 stub"));
+
+    let assert = buckle(cache.path(), cwd.path()).assert().success();
+    assert!(!stderr(&assert).contains("timing"));
+}
+
+/// Descriptors beyond the standard streams that buckle is started with reach buck2.
+#[cfg(unix)]
+#[test]
+fn test_extra_file_descriptor() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let local = TempDir::new().unwrap();
+    let buck2_bin = local.path().join("buck2");
+    write_script(
+        &buck2_bin,
+        "echo \"fd 3: $(cat <&3)\"\necho \"fd 7: $(cat <&7)\"\n",
+    );
+    let input = local.path().join("input");
+    std::fs::write(&input, "from the caller").unwrap();
+
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg("exec \"$BUCKLE\" 3<\"$INPUT\" 7<\"$INPUT\"")
+        .current_dir(cwd.path())
+        .env("BUCKLE", assert_cmd::cargo::cargo_bin("buckle"))
+        .env("INPUT", &input)
+        .env("BUCKLE_CONFIG", cache.path().join("no-config.toml"))
+        .env("BUCKLE_CACHE", cache.path())
+        .env("BUCKLE_BUCK2_BIN", &buck2_bin)
+        .env_remove("BUCKLE_PRELUDE_CHECK")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{output:?}");
+    assert!(stdout.contains("fd 3: from the caller"), "found {stdout}");
+    assert!(stdout.contains("fd 7: from the caller"), "found {stdout}");
+}
+
+/// Bare `buckle` names the buck2 it picked before buck2 prints its own help.
+#[cfg(unix)]
+#[test]
+fn test_no_arguments_banner() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let banner = format!("buckle: running buck2 {TAG}, see `buckle --buckle-help`");
+
+    let assert = buckle(cache.path(), cwd.path()).assert().success();
+    assert!(stderr(&assert).contains(&banner));
+    assert!(stdout(&assert).contains("buck2 stub"));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_QUIET", "1")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("buckle: running"));
+    assert!(stdout(&assert).contains("buck2 stub"));
+
+    // Only a bare invocation gets the banner.
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("build")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("buckle: running"));
+}
+
+/// `--buckle-help` describes buckle without running buck2.
+#[test]
+fn test_buckle_help() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("--buckle-help")
+        .assert()
+        .success();
+    let stdout_help = stdout(&assert);
+    assert!(stdout_help.starts_with("buckle: a launcher for buck2"));
+    assert!(stdout_help.contains("--buckle-env"));
+    assert!(!stdout_help.contains("buck2 stub"));
+}
+
+/// `BUCKLE_LOG_STDOUT` and `BUCKLE_LOG_STDERR` copy buck2's output, byte for byte, to files as
+/// well as the console, and keep its exit code.
+#[cfg(unix)]
+#[test]
+fn test_log_buck2_output() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    write_script(
+        &dir.join("buck2"),
+        "printf 'out\\377\\n'\nprintf 'err\\n' >&2\nexit 3\n",
+    );
+    let logs = cache.path().join("logs").join("nested");
+
+    let run = || {
+        buckle(cache.path(), cwd.path())
+            .env("BUCKLE_LOG_STDOUT", logs.join("stdout.log"))
+            .env("BUCKLE_LOG_STDERR", logs.join("stderr.log"))
+            .assert()
+            .code(3)
+    };
+    let assert = run();
+    assert_eq!(assert.get_output().stdout, b"out\xff\n");
+    assert!(stderr(&assert).ends_with("err\n"));
+    assert_eq!(
+        std::fs::read(logs.join("stdout.log")).unwrap(),
+        b"out\xff\n"
+    );
+    assert_eq!(std::fs::read(logs.join("stderr.log")).unwrap(), b"err\n");
+
+    // Each run replaces the log, unless BUCKLE_LOG_APPEND=1.
+    run();
+    assert_eq!(
+        std::fs::read(logs.join("stdout.log")).unwrap(),
+        b"out\xff\n"
+    );
+    buckle(cache.path(), cwd.path())
+        .env("BUCKLE_LOG_STDOUT", logs.join("stdout.log"))
+        .env("BUCKLE_LOG_APPEND", "1")
+        .assert()
+        .code(3);
+    assert_eq!(
+        std::fs::read(logs.join("stdout.log")).unwrap(),
+        b"out\xff\nout\xff\n"
+    );
+}
+
+/// `BUCKLE_PASSTHROUGH=1` runs the `buck2` on `PATH` with the arguments as given, skipping a
+/// `buck2` that is buckle itself, and downloads nothing.
+#[cfg(unix)]
+#[test]
+fn test_passthrough() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let bin = TempDir::new().unwrap();
+    let alias = TempDir::new().unwrap();
+    std::os::unix::fs::symlink(
+        assert_cmd::cargo::cargo_bin("buckle"),
+        alias.path().join("buck2"),
+    )
+    .unwrap();
+    write_script(
+        &bin.path().join("buck2"),
+        "echo \"system buck2 $*\"\nexit 4\n",
+    );
+    let path = std::env::join_paths([alias.path(), bin.path()]).unwrap();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_PASSTHROUGH", "1")
+        .env("PATH", &path)
+        .args(["build", "--buckle-env", "//..."])
+        .assert()
+        .code(4);
+    assert_eq!(stdout(&assert), "system buck2 build --buckle-env //...\n");
+    assert!(server.requests().is_empty());
+    assert!(!buckle_dir(cache.path()).exists());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_PASSTHROUGH", "1")
+        .env("PATH", alias.path())
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("there is no buck2 on PATH other than buckle"));
+}
+
+/// A cached buck2 that is executable but can't be run, as on a file system mounted `noexec`,
+/// fails with an explanation rather than a bare permission error. Here the refusal comes from
+/// an interpreter that isn't executable, which the kernel reports the same way.
+#[cfg(unix)]
+#[test]
+fn test_noexec_cache_is_explained() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let interpreter = cache.path().join("interpreter");
+    std::fs::write(&interpreter, "").unwrap();
+    std::fs::write(dir.join("buck2"), format!("#!{}\n", interpreter.display())).unwrap();
+
+    let assert = buckle(cache.path(), cwd.path()).assert().failure();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("It is executable, but"), "found {stderr}");
+    assert!(stderr.contains("mounted noexec"), "found {stderr}");
+    assert!(stderr.contains("Set BUCKLE_CACHE"), "found {stderr}");
+    assert!(!stderr.contains("panicked"), "found {stderr}");
+}
+
+/// With `BUCKLE_ASSERT_VERSION=1`, a cached buck2 that doesn't report the resolved version is
+/// refused before it runs, and one that does, by commit or by tag, runs as usual.
+#[cfg(unix)]
+#[test]
+fn test_assert_version() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_ASSERT_VERSION", "1")
+        .arg("build")
+        .assert()
+        .failure();
+    let reported = stderr(&assert);
+    assert!(
+        reported.contains(&format!(
+            "reports its version as \"buck2 stub {}\" rather than buck2 {TAG} (commit {COMMITISH})",
+            dir.join("buck2").display()
+        )),
+        "found {reported}"
+    );
+    assert!(!stdout(&assert).contains("arg: build"));
+
+    for version in [
+        COMMITISH[..12].to_string(),
+        COMMITISH.to_string(),
+        format!("{TAG}-{}", &COMMITISH[..8]),
+    ] {
+        write_script(
+            &dir.join("buck2"),
+            &format!(
+                "if [ \"$1\" = --version ]; then echo \"buck2 {version} <build-id>\"; \
+                else echo \"ran $1\"; fi\n"
+            ),
+        );
+        let assert = buckle(cache.path(), cwd.path())
+            .env("BUCKLE_ASSERT_VERSION", "1")
+            .arg("build")
+            .assert()
+            .success();
+        assert_eq!(stdout(&assert), "ran build\n");
+    }
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn test_version_not_found() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "1999-01-01")
+        .assert()
+        .code(23);
+    assert!(stderr(&assert).contains("1999-01-01 was not available"));
+}
+
+#[test]
+fn test_config_error() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", format!("{TAG}@sha256:1234"))
+        .assert()
+        .code(26);
+    assert!(stderr(&assert).contains("is not a valid version"));
+
+    let config = cache.path().join("config.toml");
+    fs::write(&config, "not_a_setting = true\n").unwrap();
+    buckle(cache.path(), cwd.path())
+        .env("BUCKLE_CONFIG", &config)
+        .assert()
+        .code(26);
+}
+
+#[test]
+fn test_network_error() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    server.mount("/releases", Response::status(500));
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .code(20);
+    assert!(stderr(&assert).contains("Could not fetch the releases list"));
+
+    // As is a server that can't be reached at all.
+    buckle(cache.path(), cwd.path())
+        .env("BUCKLE_RELEASES_URL", "http://127.0.0.1:1/releases")
+        .assert()
+        .code(20);
+}
+
+#[test]
+fn test_rate_limited() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    server.mount(
+        "/releases",
+        Response::status(403).with_header("x-ratelimit-remaining", "0"),
+    );
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .code(21);
+    assert!(stderr(&assert).contains("is rate limited"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cache_corrupt() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let dir = seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    fs::write(dir.join("buck2.sha256"), "0".repeat(64)).unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_VERIFY_ON_RUN", "1")
+        .assert()
+        .code(24);
+    assert!(stderr(&assert).contains("The buckle cache is corrupted"));
+}
+
+/// A prelude mismatch only fails the run with `BUCKLE_PRELUDE_CHECK=ERROR`.
+#[cfg(unix)]
+#[test]
+fn test_prelude_mismatch() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    buckle(cache.path(), project.path()).assert().success();
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_PRELUDE_CHECK", "ERROR")
+        .assert()
+        .code(25);
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("BUCKLE_PRELUDE_CHECK=ERROR"),
+        "found {stderr}"
+    );
+    assert!(stdout(&assert).is_empty());
+}
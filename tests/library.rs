@@ -0,0 +1,37 @@
+//! The library API, driven in-process. It has a test binary of its own, as it configures
+//! buckle through this process's environment.
+
+mod common;
+
+use common::*;
+use std::env;
+use tempfile::TempDir;
+
+/// `list_releases` fetches the releases list, caches it, and serves later calls from the
+/// cache.
+#[test]
+fn test_list_releases() {
+    let cache = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(
+        &server,
+        &[
+            release("2023-08-01", "1111111111111111111111111111111111111111"),
+            release(TAG, COMMITISH),
+        ],
+    );
+    env::set_var("BUCKLE_CONFIG", cache.path().join("no-config.toml"));
+    env::set_var("BUCKLE_CACHE", cache.path());
+    env::set_var("BUCKLE_RELEASES_URL", format!("{}/releases", server.url()));
+
+    for _ in 0..2 {
+        let releases = buckle::list_releases().unwrap();
+        let tags: Vec<&str> = releases
+            .iter()
+            .map(|release| release.tag_name.as_str())
+            .collect();
+        assert_eq!(tags, ["2023-08-01", TAG]);
+    }
+    assert_eq!(server.hits("/releases"), 1);
+    assert!(buckle_dir(cache.path()).join("releases.json").exists());
+}
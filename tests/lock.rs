@@ -0,0 +1,152 @@
+mod common;
+
+use common::*;
+use sha2::{Digest, Sha256};
+use std::fs;
+use tempfile::TempDir;
+
+const NEWEST_TAG: &str = "2023-12-01";
+const NEWEST_COMMITISH: &str = "4444444444444444444444444444444444444444";
+
+/// A project following `latest`, and a cache with [`TAG`] and a newer release installed.
+#[cfg(unix)]
+fn setup() -> (TempDir, TempDir) {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[
+            release(NEWEST_TAG, NEWEST_COMMITISH),
+            release(TAG, COMMITISH),
+        ],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), NEWEST_COMMITISH, PRELUDE_HASH.as_bytes());
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(project.path().join(".buckversion"), "latest\n").unwrap();
+    (cache, project)
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn stub_digest() -> String {
+    sha256(&stub_buck2())
+}
+
+fn write_lock(project: &TempDir, tag: &str, commitish: &str) {
+    let lock = format!(
+        "tag_name = \"{tag}\"\ntarget_commitish = \"{commitish}\"\nbinary_sha256 = \"{}\"\n\
+        prelude_hash = \"{PRELUDE_HASH}\"\n",
+        stub_digest()
+    );
+    fs::write(project.path().join("buckle.lock"), lock).unwrap();
+}
+
+fn read_lock(project: &TempDir) -> toml::Value {
+    toml::from_str(&fs::read_to_string(project.path().join("buckle.lock")).unwrap()).unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn test_write_lock() {
+    let (cache, project) = setup();
+    let assert = buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .env("BUCKLE_WRITE_LOCK", "1")
+        .assert()
+        .success();
+    let stderr_lock = stderr(&assert);
+    assert!(stderr_lock.contains(&format!("locked buck2 {NEWEST_TAG}")));
+    // A version seeded into the cache was never downloaded, so its asset's digest is unknown.
+    assert!(stderr_lock.contains("release asset is unknown"));
+    let lock = read_lock(&project);
+    assert_eq!(lock["tag_name"].as_str(), Some(NEWEST_TAG));
+    assert_eq!(lock["target_commitish"].as_str(), Some(NEWEST_COMMITISH));
+    assert!(lock.get("sha256").is_none());
+    assert_eq!(lock["binary_sha256"].as_str(), Some(stub_digest().as_str()));
+    assert_eq!(lock["prelude_hash"].as_str(), Some(PRELUDE_HASH));
+
+    // An unchanged lock is left alone.
+    let assert = buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .env("BUCKLE_WRITE_LOCK", "1")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("locked buck2"));
+}
+
+/// The lock records the SHA256 of the release asset as downloaded, which is what GitHub
+/// publishes, alongside that of the binary it decodes to.
+#[cfg(unix)]
+#[test]
+fn test_lock_records_asset_digest() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    let server = mock_github();
+
+    buckle_with_server(cache.path(), project.path(), &server)
+        .env("BUCKLE_WRITE_LOCK", "1")
+        .assert()
+        .success();
+    let lock = read_lock(&project);
+    let asset = zstd::encode_all(&stub_buck2()[..], 0).unwrap();
+    assert_eq!(lock["sha256"].as_str(), Some(sha256(&asset).as_str()));
+    assert_eq!(lock["binary_sha256"].as_str(), Some(stub_digest().as_str()));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_lock_is_honored_over_latest() {
+    let (cache, project) = setup();
+    write_lock(&project, TAG, COMMITISH);
+    let assert = buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(COMMITISH));
+
+    // The lock holds the binary too, not just the tag.
+    fs::write(
+        version_dir(cache.path(), COMMITISH).join("buck2.sha256"),
+        "0".repeat(64),
+    )
+    .unwrap();
+    let assert = buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("is pinned"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_upgrade_refreshes_lock() {
+    let (cache, project) = setup();
+    write_lock(&project, TAG, COMMITISH);
+    let assert = buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .args(["upgrade", "--dry-run"])
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(&format!("{TAG} -> {NEWEST_TAG}")));
+    assert_eq!(read_lock(&project)["tag_name"].as_str(), Some(TAG));
+
+    buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .arg("upgrade")
+        .assert()
+        .success();
+    let lock = read_lock(&project);
+    assert_eq!(lock["tag_name"].as_str(), Some(NEWEST_TAG));
+    assert_eq!(lock["target_commitish"].as_str(), Some(NEWEST_COMMITISH));
+    assert_eq!(
+        fs::read_to_string(project.path().join(".buckversion")).unwrap(),
+        "latest\n"
+    );
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use common::*;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn manifest_json(sha256: &str) -> String {
+    serde_json::json!({ TAG: { "sha256": sha256, "prelude_hash": PRELUDE_HASH } }).to_string()
+}
+
+/// A release matching the manifest runs, and the manifest is fetched once while it is fresh.
+#[cfg(unix)]
+#[test]
+fn test_matching_manifest() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    server.mount(
+        "/manifest.json",
+        Response::ok(manifest_json(&sha256(&stub_buck2()))),
+    );
+
+    for _ in 0..2 {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env(
+                "BUCKLE_MANIFEST_URL",
+                format!("{}/manifest.json", server.url()),
+            )
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+    assert_eq!(server.hits("/manifest.json"), 1);
+}
+
+/// A binary whose digest differs from the manifest is refused, even though it downloaded
+/// cleanly, before it is installed or handed to the post-download hook.
+#[cfg(unix)]
+#[test]
+fn test_mismatched_manifest_digest() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let expected = "0".repeat(64);
+    let manifest = cwd.path().join("manifest.json");
+    std::fs::write(&manifest, manifest_json(&expected)).unwrap();
+
+    let hooked = cwd.path().join("hooked");
+    let hook = cwd.path().join("hook.sh");
+    write_script(&hook, &format!("touch {}\n", hooked.display()));
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .env("BUCKLE_POST_DOWNLOAD_HOOK", &hook)
+        .assert()
+        .failure();
+    let stderr_refused = stderr(&assert);
+    assert!(
+        stderr_refused.contains(&format!(
+            "Refusing to run buck2 {TAG}: its SHA256 is {}, but the manifest {} expects \
+            {expected}",
+            sha256(&stub_buck2()),
+            manifest.display()
+        )),
+        "found {stderr_refused}"
+    );
+    assert!(!stdout(&assert).contains("buck2 stub"));
+    let dir = version_dir(cache.path(), COMMITISH);
+    assert!(!dir.join("buck2").exists() && !dir.join("prelude_hash").exists());
+    assert!(!hooked.exists());
+}
+
+/// A cached binary is hashed again before it runs, rather than trusting the `buck2.sha256`
+/// recorded beside it.
+#[cfg(unix)]
+#[test]
+fn test_manifest_rehashes_cached_binary() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let manifest = cwd.path().join("manifest.json");
+    std::fs::write(&manifest, manifest_json(&sha256(&stub_buck2()))).unwrap();
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .assert()
+        .success();
+
+    let buck2 = version_dir(cache.path(), COMMITISH).join("buck2");
+    write_script(&buck2, "echo tampered\n");
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!("Refusing to run buck2 {TAG}: its SHA256 is")),
+        "found {stderr}"
+    );
+    assert!(!stdout(&assert).contains("tampered"));
+}
+
+/// A TOML manifest without the release is refused, as are mismatched prelude hashes.
+#[cfg(unix)]
+#[test]
+fn test_toml_manifest() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let manifest = cwd.path().join("manifest.toml");
+    let digest = sha256(&stub_buck2());
+    std::fs::write(
+        &manifest,
+        format!("[\"2023-08-01\"]\nsha256 = \"{digest}\"\nprelude_hash = \"{PRELUDE_HASH}\"\n"),
+    )
+    .unwrap();
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains(&format!("buck2 {TAG}: it is not in the manifest")));
+
+    let other_hash = "f".repeat(PRELUDE_HASH.len());
+    std::fs::write(
+        &manifest,
+        format!("[\"{TAG}\"]\nsha256 = \"{digest}\"\nprelude_hash = \"{other_hash}\"\n"),
+    )
+    .unwrap();
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains(&format!("its prelude_hash is {PRELUDE_HASH}")));
+
+    std::fs::write(
+        &manifest,
+        format!("[\"{TAG}\"]\nsha256 = \"{digest}\"\nprelude_hash = \"{PRELUDE_HASH}\"\n"),
+    )
+    .unwrap();
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .assert()
+        .success();
+}
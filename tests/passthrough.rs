@@ -0,0 +1,81 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+/// A buckle invocation running the `buck2-exit` fixture from `local` with `BUCKLE_BUCK2_BIN`,
+/// so nothing is downloaded.
+#[cfg(unix)]
+fn buckle_with_stub(cache: &TempDir, cwd: &TempDir, local: &TempDir) -> assert_cmd::Command {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let stub = local.path().join("buck2");
+    fs::copy(fixture("buck2-exit"), &stub).unwrap();
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut cmd = buckle(cache.path(), cwd.path());
+    cmd.env("BUCKLE_BUCK2_BIN", &stub);
+    cmd
+}
+
+#[cfg(unix)]
+#[test]
+fn test_args_pass_through_verbatim() {
+    let (cache, cwd, local) = (
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    );
+    let args = [
+        "build",
+        "//foo:bar",
+        "",
+        "two words",
+        "--",
+        "-c",
+        "quote\"d",
+        "it's",
+        "$HOME",
+        "*",
+        "ünïcödé",
+    ];
+    let assert = buckle_with_stub(&cache, &cwd, &local)
+        .args(args)
+        .assert()
+        .code(0);
+    let expected: String = args.iter().map(|arg| format!("arg: [{arg}]\n")).collect();
+    assert_eq!(stdout(&assert), expected);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_exit_code_passes_through() {
+    let (cache, cwd, local) = (
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    );
+    for code in [1, 2, 42, 255] {
+        buckle_with_stub(&cache, &cwd, &local)
+            .env("STUB_EXIT_CODE", code.to_string())
+            .arg("test")
+            .assert()
+            .code(code);
+    }
+}
+
+/// A child killed by a signal exits with 128 + the signal number, like a shell would.
+#[cfg(unix)]
+#[test]
+fn test_signal_passes_through() {
+    let (cache, cwd, local) = (
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+        TempDir::new().unwrap(),
+    );
+    for (signal, code) in [("TERM", 143), ("INT", 130)] {
+        buckle_with_stub(&cache, &cwd, &local)
+            .env("STUB_SIGNAL", signal)
+            .assert()
+            .code(code);
+    }
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+/// On a host buck2 has no binary for, the error names the detected platform and lists the
+/// supported ones, exiting with 22. This only runs on such a host.
+#[cfg(not(any(
+    all(
+        target_arch = "x86_64",
+        any(target_os = "linux", target_os = "macos", target_os = "windows")
+    ),
+    all(target_arch = "aarch64", any(target_os = "linux", target_os = "macos"))
+)))]
+#[test]
+fn test_unsupported_platform() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path()).assert().code(22);
+    let stderr = stderr(&assert);
+    let detected = format!("{}/{}", std::env::consts::ARCH, std::env::consts::OS);
+    assert!(
+        stderr.contains(&format!("know of a buck2 binary for {detected}")),
+        "found {stderr}"
+    );
+    assert!(stderr.contains("x86_64/linux"), "found {stderr}");
+    assert!(stderr.contains("https://github.com/facebook/buck2/tags"));
+}
+
+/// The doctor reports the triple the detected platform maps to.
+#[cfg(any(
+    all(
+        target_arch = "x86_64",
+        any(target_os = "linux", target_os = "macos", target_os = "windows")
+    ),
+    all(target_arch = "aarch64", any(target_os = "linux", target_os = "macos"))
+))]
+#[test]
+fn test_supported_platform() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path()).arg("doctor").assert();
+    let stdout = stdout(&assert);
+    assert!(
+        stdout.contains(&format!("[ ok ] platform: {}", host_triple())),
+        "found {stdout}"
+    );
+}
+
+/// Put a `sysctl` on PATH that reports the process as translated by Rosetta.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+fn translated_path(bin: &std::path::Path) -> std::ffi::OsString {
+    write_script(&bin.join("sysctl"), "echo 1\n");
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin.to_path_buf()];
+    paths.extend(std::env::split_paths(&path));
+    std::env::join_paths(paths).unwrap()
+}
+
+/// Under Rosetta the x86_64 buck2 is still picked, with a warning saying why.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+#[test]
+fn test_rosetta_warns() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let bin = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path())
+        .env("PATH", translated_path(bin.path()))
+        .env_remove("BUCKLE_PREFER_NATIVE_ARCH")
+        .arg("doctor")
+        .assert();
+    assert!(stdout(&assert).contains("[ ok ] platform: x86_64-apple-darwin"));
+    assert!(stderr(&assert).contains("running under Rosetta"));
+}
+
+/// `BUCKLE_PREFER_NATIVE_ARCH=1` picks the aarch64 buck2 under Rosetta.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+#[test]
+fn test_rosetta_prefer_native_arch() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let bin = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path())
+        .env("PATH", translated_path(bin.path()))
+        .env("BUCKLE_PREFER_NATIVE_ARCH", "1")
+        .arg("doctor")
+        .assert();
+    assert!(stdout(&assert).contains("[ ok ] platform: aarch64-apple-darwin"));
+    assert!(!stderr(&assert).contains("running under Rosetta"));
+}
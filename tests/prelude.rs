@@ -0,0 +1,524 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+/// An empty prelude_hash is reported and the check skipped instead of panicking.
+#[cfg(unix)]
+#[test]
+fn test_empty_prelude_hash_skips_check() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, b"");
+
+    let assert = buckle(cache.path(), project.path()).arg("build").assert();
+    let stderr = stderr(&assert);
+    let stdout = stdout(&assert);
+    assert!(stderr.contains("skipping prelude check"), "found {stderr}");
+    assert!(stderr.contains("is empty"), "found {stderr}");
+    assert!(!stderr.contains("panicked"), "found {stderr}");
+    assert!(stdout.contains("arg: build"), "found {stdout}");
+    assert.success();
+}
+
+/// A prelude_hash that isn't a git hash, or isn't even text, is reported and the check skipped.
+#[cfg(unix)]
+#[test]
+fn test_invalid_prelude_hash_skips_check() {
+    for (contents, reason) in [
+        (&b"not a hash\n"[..], "does not contain a valid git hash"),
+        (&b"\xff\xfe"[..], "is not valid UTF-8"),
+    ] {
+        let cache = TempDir::new().unwrap();
+        let project = TempDir::new().unwrap();
+        let upstream = TempDir::new().unwrap();
+        init_project_with_prelude(project.path(), upstream.path());
+        seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+        seed_version(cache.path(), COMMITISH, contents);
+
+        let assert = buckle(cache.path(), project.path()).assert().success();
+        let stderr = stderr(&assert);
+        assert!(stderr.contains("skipping prelude check"), "found {stderr}");
+        assert!(stderr.contains(reason), "found {stderr}");
+        assert!(!stderr.contains("panicked"), "found {stderr}");
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+}
+
+/// Running from a symlink to the project still finds its root and checks the prelude.
+#[cfg(unix)]
+#[test]
+fn test_symlinked_current_dir() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let links = TempDir::new().unwrap();
+    let prelude_hash = init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let link = links.path().join("project");
+    std::os::unix::fs::symlink(project.path(), &link).unwrap();
+
+    std::fs::create_dir(project.path().join("src")).unwrap();
+
+    let assert = buckle(cache.path(), &link.join("src"))
+        .env("PWD", link.join("src"))
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "({prelude_hash}) is not the expected {PRELUDE_HASH}"
+        )),
+        "found {stderr}"
+    );
+    assert!(!stderr.contains("panicked"), "found {stderr}");
+}
+
+/// A prelude outside of the git repo skips the check rather than failing.
+#[cfg(unix)]
+#[test]
+fn test_prelude_outside_workdir() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    init_project_with_prelude(project.path(), upstream.path());
+    std::fs::write(
+        project.path().join(".buckconfig"),
+        format!("[repositories]\nprelude = {}\n", upstream.path().display()),
+    )
+    .unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path()).assert().success();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("not within this git repo"),
+        "found {stderr}"
+    );
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// A bare git repo skips the check rather than failing.
+#[cfg(unix)]
+#[test]
+fn test_bare_repo() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let bare = TempDir::new().unwrap();
+    git(bare.path(), &["init", "-q", "--bare"]);
+    std::fs::write(
+        project.path().join(".buckconfig"),
+        "[repositories]\nprelude = prelude\n",
+    )
+    .unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path())
+        .env("GIT_DIR", bare.path())
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("is a bare git repo"), "found {stderr}");
+    assert!(!stderr.contains("panicked"), "found {stderr}");
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// With the check disabled the .buckconfig is never opened. It is a FIFO here, so reading it
+/// would block until the timeout.
+#[cfg(unix)]
+#[test]
+fn test_disabled_check_does_not_read_buckconfig() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let status = std::process::Command::new("mkfifo")
+        .arg(project.path().join(".buckconfig"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    for dry_run in ["0", "1"] {
+        let assert = buckle(cache.path(), project.path())
+            .env("BUCKLE_PRELUDE_CHECK", "NO")
+            .env("BUCKLE_DRY_RUN", dry_run)
+            .timeout(std::time::Duration::from_secs(30))
+            .arg("build")
+            .assert()
+            .success();
+        if dry_run == "1" {
+            assert!(stderr(&assert).contains("would not verify the prelude"));
+        } else {
+            assert!(stdout(&assert).contains("arg: build"));
+        }
+    }
+}
+
+/// A `[cells]` config is checked like `[repositories]`: the prelude cell is verified, while cells
+/// buck2 releases pin no hash for are skipped quietly.
+#[cfg(unix)]
+#[test]
+fn test_cells_section() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let other_upstream = TempDir::new().unwrap();
+    let prelude_hash = init_project_with_prelude(project.path(), upstream.path());
+    git(other_upstream.path(), &["init", "-q"]);
+    git(
+        other_upstream.path(),
+        &["commit", "-q", "--allow-empty", "-m", "other"],
+    );
+    git(
+        project.path(),
+        &[
+            "submodule",
+            "add",
+            "-q",
+            other_upstream.path().to_str().unwrap(),
+            "third-party/other",
+        ],
+    );
+    std::fs::write(
+        project.path().join(".buckconfig"),
+        "[cells]\nroot = .\nprelude = prelude\nother = third-party/other\ntoolchains = toolchains\n",
+    )
+    .unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_DRY_RUN", "1")
+        .assert()
+        .success();
+    let dry_run = stderr(&assert);
+    assert!(
+        dry_run.contains("would verify the prelude cell at prelude"),
+        "found {dry_run}"
+    );
+    assert!(!dry_run.contains("other"), "found {dry_run}");
+
+    let assert = buckle(cache.path(), project.path()).assert().success();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "Git submodule for prelude ({prelude_hash}) is not the expected {PRELUDE_HASH}"
+        )),
+        "found {stderr}"
+    );
+    assert!(!stderr.contains("other"), "found {stderr}");
+    assert!(!stderr.contains("toolchains"), "found {stderr}");
+}
+
+/// A cell in both sections is checked once, with `[cells]` taking precedence.
+#[cfg(unix)]
+#[test]
+fn test_cells_and_repositories() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    init_project_with_prelude(project.path(), upstream.path());
+    std::fs::write(
+        project.path().join(".buckconfig"),
+        "[repositories]\nprelude = elsewhere\n\n[cells]\nprelude = prelude\n",
+    )
+    .unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path()).assert().success();
+    let stderr = stderr(&assert);
+    assert_eq!(
+        stderr.matches("is not the expected").count(),
+        1,
+        "found {stderr}"
+    );
+    assert!(
+        stderr.contains("cd ") && !stderr.contains("elsewhere"),
+        "found {stderr}"
+    );
+}
+
+/// A local buck2 still has its prelude checked against the cached release.
+#[cfg(unix)]
+#[test]
+fn test_buck2_bin_override_checks_prelude() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let local = TempDir::new().unwrap();
+    let prelude_hash = init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let buck2_bin = local.path().join("buck2");
+    write_stub_buck2(&buck2_bin);
+
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_BUCK2_BIN", &buck2_bin)
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "({prelude_hash}) is not the expected {PRELUDE_HASH}"
+        )),
+        "found {stderr}"
+    );
+    assert!(stdout(&assert).contains(&format!("buck2 stub {}", buck2_bin.display())));
+}
+
+/// `buckle prelude-hash` prints the expected hash, then the submodule's, without running buck2.
+#[cfg(unix)]
+#[test]
+fn test_prelude_hash_subcommand() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let outside = TempDir::new().unwrap();
+    let prelude_hash = init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path())
+        .arg("prelude-hash")
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        format!("{PRELUDE_HASH}\nactual: {prelude_hash}\n")
+    );
+
+    // Outside of a project there is no submodule to report.
+    let assert = buckle(cache.path(), outside.path())
+        .arg("prelude-hash")
+        .assert()
+        .success();
+    assert_eq!(stdout(&assert), format!("{PRELUDE_HASH}\n"));
+}
+
+/// An unparseable .buckconfig is left for buck2 to complain about, unless the check is strict.
+#[cfg(unix)]
+#[test]
+fn test_malformed_buckconfig() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    std::fs::write(
+        project.path().join(".buckconfig"),
+        "[cells\nprelude = prelude\n",
+    )
+    .unwrap();
+
+    let assert = buckle(cache.path(), project.path()).assert().success();
+    assert!(!stderr(&assert).contains("could not parse"));
+
+    for (name, value) in [
+        ("BUCKLE_PRELUDE_CHECK", "STRICT"),
+        ("BUCKLE_STRICT_CONFIG", "1"),
+    ] {
+        let assert = buckle(cache.path(), project.path())
+            .env(name, value)
+            .assert()
+            .success();
+        let stderr = stderr(&assert);
+        assert!(
+            stderr.contains(&format!(
+                "could not parse {}",
+                project.path().join(".buckconfig").display()
+            )),
+            "{name}: found {stderr}"
+        );
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+}
+
+/// A clone without its submodules checked out, as in a shallow CI checkout, skips the check
+/// quietly, explaining why with `BUCKLE_DEBUG=1`.
+#[cfg(unix)]
+#[test]
+fn test_submodule_not_checked_out() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let clones = TempDir::new().unwrap();
+    init_project_with_prelude(project.path(), upstream.path());
+    let clone = clones.path().join("clone");
+    git(
+        clones.path(),
+        &["clone", "-q", project.path().to_str().unwrap(), "clone"],
+    );
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), &clone).assert().success();
+    let stderr_quiet = stderr(&assert);
+    assert!(!stderr_quiet.contains("prelude"), "found {stderr_quiet}");
+    assert!(stdout(&assert).contains("buck2 stub"));
+
+    let assert = buckle(cache.path(), &clone)
+        .env("BUCKLE_DEBUG", "1")
+        .assert()
+        .success();
+    let stderr_debug = stderr(&assert);
+    assert!(
+        stderr_debug.contains("the prelude submodule is not checked out"),
+        "found {stderr_debug}"
+    );
+}
+
+/// A `GIT_DIR` that points nowhere leaves the check skipped rather than failing.
+#[cfg(unix)]
+#[test]
+fn test_unusable_git_dir() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path())
+        .env("GIT_DIR", project.path().join("missing"))
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(!stderr.contains("is not the expected"), "found {stderr}");
+    assert!(!stderr.contains("panicked"), "found {stderr}");
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// A project whose prelude submodule is one commit behind the hash the cached buck2 expects.
+/// Returns the submodule's current hash and the expected one.
+#[cfg(unix)]
+fn behind_project(cache: &TempDir, project: &TempDir, upstream: &TempDir) -> (String, String) {
+    let current = init_project_with_prelude(project.path(), upstream.path());
+    std::fs::write(upstream.path().join("prelude.bzl"), "# newer\n").unwrap();
+    git(upstream.path(), &["commit", "-q", "-am", "newer prelude"]);
+    let expected = git(upstream.path(), &["rev-parse", "HEAD"]);
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, expected.as_bytes());
+    (current, expected)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_prelude_autofix() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let (current, expected) = behind_project(&cache, &project, &upstream);
+    let prelude = project.path().join("prelude");
+
+    // Never without opting in.
+    let assert = buckle(cache.path(), project.path()).assert().success();
+    assert!(stderr(&assert).contains("is not the expected"));
+    assert_eq!(git(&prelude, &["rev-parse", "HEAD"]), current);
+
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_PRELUDE_AUTOFIX", "1")
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "moved the prelude submodule from {current} to {expected}"
+        )),
+        "found {stderr}"
+    );
+    assert!(!stderr.contains("is not the expected"), "found {stderr}");
+    assert_eq!(git(&prelude, &["rev-parse", "HEAD"]), expected);
+    assert_eq!(
+        std::fs::read_to_string(prelude.join("prelude.bzl")).unwrap(),
+        "# newer\n"
+    );
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_prelude_autofix_dirty_submodule() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let (current, _) = behind_project(&cache, &project, &upstream);
+    let prelude = project.path().join("prelude");
+    std::fs::write(prelude.join("prelude.bzl"), "# local patch\n").unwrap();
+
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_PRELUDE_AUTOFIX", "1")
+        .assert()
+        .success();
+    let stderr = stderr(&assert);
+    assert!(stderr.contains("has local changes"), "found {stderr}");
+    assert!(stderr.contains("is not the expected"), "found {stderr}");
+    assert_eq!(git(&prelude, &["rev-parse", "HEAD"]), current);
+    assert_eq!(
+        std::fs::read_to_string(prelude.join("prelude.bzl")).unwrap(),
+        "# local patch\n"
+    );
+}
+
+/// `BUCKLE_EXPECTED_PRELUDE_HASH`, or a `.buckprelude` file, replaces the release's
+/// prelude_hash as what the submodule is checked against.
+#[cfg(unix)]
+#[test]
+fn test_expected_prelude_hash_override() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let upstream = TempDir::new().unwrap();
+    let prelude_hash = init_project_with_prelude(project.path(), upstream.path());
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_EXPECTED_PRELUDE_HASH", &prelude_hash)
+        .env("BUCKLE_PRELUDE_CHECK", "ERROR")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("is not the expected"));
+
+    let fork_hash = "fedcba9876543210fedcba9876543210fedcba98";
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_EXPECTED_PRELUDE_HASH", fork_hash)
+        .assert()
+        .success();
+    let reported = stderr(&assert);
+    assert!(
+        reported.contains(&format!("({prelude_hash}) is not the expected {fork_hash}")),
+        "found {reported}"
+    );
+
+    std::fs::write(
+        project.path().join(".buckprelude"),
+        format!("{prelude_hash}\n"),
+    )
+    .unwrap();
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_PRELUDE_CHECK", "ERROR")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("is not the expected"));
+    let assert = buckle(cache.path(), project.path())
+        .arg("prelude-hash")
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        format!("{prelude_hash}\nactual: {prelude_hash}\n")
+    );
+
+    std::fs::write(project.path().join(".buckprelude"), "main\n").unwrap();
+    let assert = buckle(cache.path(), project.path()).assert().success();
+    assert!(
+        stderr(&assert).contains("does not contain a valid git hash: \"main\""),
+        "found {}",
+        stderr(&assert)
+    );
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use common::*;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const OTHER_TAG: &str = "2023-08-01";
+const OTHER_COMMITISH: &str = "1111111111111111111111111111111111111111";
+
+fn make_project(dir: &Path, version: &str) {
+    fs::write(dir.join(".buckconfig"), "").unwrap();
+    fs::write(dir.join(".buckversion"), version).unwrap();
+}
+
+#[cfg(unix)]
+fn two_version_cache() -> TempDir {
+    let cache = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[release(TAG, COMMITISH), release(OTHER_TAG, OTHER_COMMITISH)],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), OTHER_COMMITISH, PRELUDE_HASH.as_bytes());
+    cache
+}
+
+/// `BUCKLE_ROOT` wins over the root found by walking up from the current directory.
+#[cfg(unix)]
+#[test]
+fn test_buckle_root_env_overrides_walk() {
+    let cache = two_version_cache();
+    let cwd = TempDir::new().unwrap();
+    let root = TempDir::new().unwrap();
+    make_project(cwd.path(), TAG);
+    make_project(root.path(), OTHER_TAG);
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .env("BUCKLE_ROOT", root.path())
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(OTHER_COMMITISH));
+}
+
+/// `--buckle-root` works like `BUCKLE_ROOT` and is not passed on to buck2.
+#[cfg(unix)]
+#[test]
+fn test_buckle_root_flag() {
+    let cache = two_version_cache();
+    let cwd = TempDir::new().unwrap();
+    let root = TempDir::new().unwrap();
+    make_project(cwd.path(), TAG);
+    make_project(root.path(), OTHER_TAG);
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .arg("--buckle-root")
+        .arg(root.path())
+        .arg("build")
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains(OTHER_COMMITISH), "found {stdout}");
+    assert!(!stdout.contains("--buckle-root"), "found {stdout}");
+    assert!(stdout.contains("arg: build"), "found {stdout}");
+}
+
+#[test]
+fn test_buckle_root_must_be_a_project() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let root = TempDir::new().unwrap();
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_ROOT", root.path())
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("contains neither a .buckconfig nor a .buckroot"));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_ROOT", root.path().join("missing"))
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("is not usable"));
+}
+
+/// A current directory that has been deleted leaves buckle rootless rather than panicking.
+#[cfg(unix)]
+#[test]
+fn test_deleted_current_dir() {
+    let cache = two_version_cache();
+    let parent = TempDir::new().unwrap();
+    let cwd = parent.path().join("gone");
+    fs::create_dir(&cwd).unwrap();
+    let assert = assert_cmd::Command::new("sh")
+        .arg("-c")
+        .arg(r#"cd "$1" && rmdir "$1" && exec "$2""#)
+        .arg("sh")
+        .arg(&cwd)
+        .arg(assert_cmd::cargo::cargo_bin("buckle"))
+        .env("BUCKLE_CONFIG", cache.path().join("no-config.toml"))
+        .env("BUCKLE_CACHE", cache.path())
+        .env("USE_BUCK2_VERSION", TAG)
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("could not determine the current directory"));
+    assert!(stdout(&assert).contains(COMMITISH));
+}
+
+/// Two projects sharing a cache but pinning different versions each run their own buck2, and
+/// the debug log says which project and version were used.
+#[cfg(unix)]
+#[test]
+fn test_projects_sharing_a_cache_run_their_own_version() {
+    let cache = two_version_cache();
+    let projects = [
+        (TempDir::new().unwrap(), TAG, COMMITISH, OTHER_COMMITISH),
+        (
+            TempDir::new().unwrap(),
+            OTHER_TAG,
+            OTHER_COMMITISH,
+            COMMITISH,
+        ),
+    ];
+    for (project, tag, _, _) in &projects {
+        make_project(project.path(), tag);
+    }
+
+    // Alternate between them, so nothing from one run can carry over to the next.
+    for _ in 0..2 {
+        for (project, tag, commitish, other_commitish) in &projects {
+            let assert = buckle(cache.path(), project.path())
+                .env_remove("USE_BUCK2_VERSION")
+                .env("BUCKLE_DEBUG", "1")
+                .assert()
+                .success();
+            let stdout = stdout(&assert);
+            assert!(stdout.contains(commitish), "found {stdout}");
+            assert!(!stdout.contains(other_commitish), "found {stdout}");
+            let root = fs::canonicalize(project.path()).unwrap();
+            let stderr = stderr(&assert);
+            assert!(
+                stderr.contains(&format!(
+                    "project root {}, version {tag} from",
+                    root.display()
+                )),
+                "found {stderr}"
+            );
+        }
+    }
+}
@@ -0,0 +1,443 @@
+mod common;
+
+use common::*;
+use std::fs::File;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+/// Make the cached releases.json look like it was fetched `age` ago.
+fn age_releases(cache: &std::path::Path, age: Duration) {
+    let path = buckle_dir(cache).join("releases.json");
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() - age)
+        .unwrap();
+}
+
+/// A TTL of zero refetches even a brand new releases.json.
+#[cfg(unix)]
+#[test]
+fn test_zero_ttl_always_refetches() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+
+    for _ in 0..2 {
+        buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("BUCKLE_RELEASES_TTL_SECS", "0")
+            .assert()
+            .success();
+    }
+    assert_eq!(server.hits("/releases"), 2);
+}
+
+/// A very large TTL keeps using an old releases.json.
+#[cfg(unix)]
+#[test]
+fn test_large_ttl_uses_cache() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    age_releases(cache.path(), Duration::from_secs(30 * 24 * 60 * 60));
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_RELEASES_TTL_SECS", "3153600000")
+        .assert()
+        .success();
+    assert_eq!(server.hits("/releases"), 0);
+}
+
+/// By default a releases.json is trusted for an hour.
+#[cfg(unix)]
+#[test]
+fn test_default_ttl_is_an_hour() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+
+    age_releases(cache.path(), Duration::from_secs(50 * 60));
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert_eq!(server.hits("/releases"), 0);
+
+    age_releases(cache.path(), Duration::from_secs(70 * 60));
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert_eq!(server.hits("/releases"), 1);
+}
+
+/// An unparseable TTL is reported and the default used instead.
+#[cfg(unix)]
+#[test]
+fn test_invalid_ttl() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_RELEASES_TTL_SECS", "an hour")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("ignoring invalid BUCKLE_RELEASES_TTL_SECS"));
+    assert_eq!(server.hits("/releases"), 0);
+}
+
+/// With direct downloads an exact pin is fetched from the base URL without asking for the
+/// releases list, while a tag the base URL doesn't have is looked up in the list.
+#[cfg(unix)]
+#[test]
+fn test_direct_download_skips_releases_list() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    for _ in 0..2 {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("BUCKLE_DIRECT_DOWNLOAD", "1")
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+    assert_eq!(server.hits("/releases"), 0);
+    let installed = buckle_dir(cache.path())
+        .join(TAG)
+        .join(host_triple())
+        .join("buck2");
+    assert!(installed.exists());
+    assert!(!buckle_dir(cache.path()).join("releases.json").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_direct_download_falls_back_on_not_found() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    let mut other_arch = release(TAG, COMMITISH);
+    other_arch["assets"] = serde_json::json!([{ "name": "buck2-riscv64gc-unknown-linux-gnu.zst" }]);
+    mount_releases(&server, &[other_arch]);
+    server.mount(
+        &format!("/download/{TAG}/buck2-{}.zst", host_triple()),
+        Response::status(404),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_DIRECT_DOWNLOAD", "1")
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "was not found, looking buck2 {TAG} up in the releases list"
+        )),
+        "found {stderr}"
+    );
+    assert!(stderr.contains("has no binary for"), "found {stderr}");
+    assert_eq!(server.hits("/releases"), 1);
+}
+
+/// A web page in place of the releases list is refused with a hint, and not cached.
+#[test]
+fn test_html_releases_list() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    server.mount(
+        "/releases",
+        Response::ok("<html><body>Please sign in</body></html>")
+            .with_header("Content-Type", "text/html; charset=utf-8"),
+    );
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("is not JSON (text/html; charset=utf-8)"),
+        "found {stderr}"
+    );
+    assert!(!buckle_dir(cache.path()).join("releases.json").exists());
+}
+
+/// A releases list over `BUCKLE_RELEASES_MAX_BYTES` is refused before it is parsed.
+#[test]
+fn test_oversized_releases_list() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &vec![release(TAG, COMMITISH); 4]);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_RELEASES_MAX_BYTES", "1024")
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("is larger than 1.0 KiB (BUCKLE_RELEASES_MAX_BYTES)"),
+        "found {stderr}"
+    );
+    assert!(!buckle_dir(cache.path()).join("releases.json").exists());
+}
+
+/// A version that isn't a release is remembered for a while, so retrying it fails without
+/// fetching the releases list again. Another version is still looked up.
+#[test]
+fn test_missing_version_is_remembered() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+
+    for _ in 0..2 {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("BUCKLE_RELEASES_TTL_SECS", "0")
+            .env("USE_BUCK2_VERSION", "2023-07-51")
+            .assert()
+            .code(23);
+        assert!(stderr(&assert).contains("2023-07-51 was not available"));
+    }
+    assert_eq!(server.hits("/releases"), 1);
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_RELEASES_TTL_SECS", "0")
+        .env("USE_BUCK2_VERSION", "2023-07-52")
+        .assert()
+        .code(23);
+    assert_eq!(server.hits("/releases"), 2);
+}
+
+/// A failure to fetch the releases list is never remembered as a missing version.
+#[test]
+fn test_network_failure_is_not_remembered() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    server.mount("/releases", Response::status(502));
+
+    for _ in 0..2 {
+        buckle_with_server(cache.path(), cwd.path(), &server)
+            .assert()
+            .code(20);
+    }
+    assert_eq!(server.hits("/releases"), 2);
+}
+
+/// A releases.json dated in the future, as after clock skew, is refetched with a warning
+/// rather than trusted for ever.
+#[cfg(unix)]
+#[test]
+fn test_future_mtime_refetches() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    File::options()
+        .write(true)
+        .open(buckle_dir(cache.path()).join("releases.json"))
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(24 * 60 * 60))
+        .unwrap();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("in the future, check the system clock"));
+    assert_eq!(server.hits("/releases"), 1);
+}
+
+/// `buckle refresh` overwrites a fresh releases.json regardless of the TTL, without running
+/// buck2.
+#[test]
+fn test_refresh() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let newer = vec![
+        release("2023-08-01", "2222222222222222222222222222222222222222"),
+        release(TAG, COMMITISH),
+    ];
+    mount_releases(&server, &newer);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("refresh")
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        "Fetched 2 releases, the newest is 2023-08-01\n"
+    );
+    assert_eq!(server.hits("/releases"), 1);
+    let cached = std::fs::read_to_string(buckle_dir(cache.path()).join("releases.json")).unwrap();
+    assert_eq!(cached, serde_json::to_string(&newer).unwrap());
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("refresh")
+        .env("BUCKLE_OFFLINE", "1")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("buckle refresh needs the network"));
+    assert_eq!(server.hits("/releases"), 1);
+}
+
+/// A failed refresh is an error rather than falling back to the cached list.
+#[test]
+fn test_refresh_failure() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    server.mount("/releases", Response::status(500));
+
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .arg("refresh")
+        .assert()
+        .code(20);
+}
+
+/// Resolving `latest` only needs the newest page, so older ones are never fetched.
+#[cfg(unix)]
+#[test]
+fn test_latest_fetches_one_page() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    mount_release_page(
+        &server,
+        "/releases",
+        &[release(TAG, COMMITISH)],
+        "/releases?page=2",
+    );
+    server.mount("/releases?page=2", Response::ok("[]"));
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("USE_BUCK2_VERSION", "latest")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert_eq!(server.hits("/releases"), 1);
+    assert_eq!(server.hits("/releases?page=2"), 0);
+}
+
+/// A tag older than the newest page is found by paging back, honouring
+/// `BUCKLE_RELEASES_PER_PAGE`, and the older pages are cached so the next run doesn't page.
+#[cfg(unix)]
+#[test]
+fn test_old_tag_pages_back() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    let newer = release("2023-08-01", "1111111111111111111111111111111111111111");
+    mount_release_page(
+        &server,
+        "/releases?per_page=1",
+        &[newer],
+        "/releases?per_page=1&page=2",
+    );
+    mount_release_page(
+        &server,
+        "/releases?per_page=1&page=2",
+        &[release(TAG, COMMITISH)],
+        "/releases?per_page=1&page=3",
+    );
+
+    for _ in 0..2 {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("BUCKLE_RELEASES_PER_PAGE", "1")
+            .assert()
+            .success();
+        assert!(stdout(&assert).contains("buck2 stub"));
+    }
+    assert_eq!(server.hits("/releases?per_page=1"), 1);
+    assert_eq!(server.hits("/releases?per_page=1&page=2"), 1);
+    assert_eq!(server.hits("/releases?per_page=1&page=3"), 0);
+    assert_eq!(server.hits("/releases"), 0);
+}
+
+/// A fresh but corrupt releases.json, as after a disk error, is fetched again rather than
+/// failing the run.
+#[cfg(unix)]
+#[test]
+fn test_corrupt_releases_json_is_refetched() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let releases_json = buckle_dir(cache.path()).join("releases.json");
+    std::fs::write(&releases_json, "[{\"tag_name\": \"2023-07").unwrap();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_DEBUG", "1")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    assert!(stderr(&assert).contains("could not be parsed"));
+    assert_eq!(server.hits("/releases"), 1);
+    let cached: Vec<serde_json::Value> =
+        serde_json::from_str(&std::fs::read_to_string(&releases_json).unwrap()).unwrap();
+    assert!(!cached.is_empty());
+}
+
+/// The releases list is refetched conditionally on the `ETag` it was served with, and a 304
+/// reuses the cached list, which then counts as fresh again.
+#[cfg(unix)]
+#[test]
+fn test_unchanged_releases_list_is_reused() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    let releases = serde_json::to_string(&[release(TAG, COMMITISH)]).unwrap();
+    server.mount(
+        "/releases",
+        Response::ok(releases.clone()).with_header("ETag", "\"v1\""),
+    );
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(server.requests()[0].header("if-none-match").is_none());
+
+    server.mount("/releases", Response::status(304));
+    age_releases(cache.path(), Duration::from_secs(5 * 60 * 60));
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1].header("if-none-match"), Some("\"v1\""));
+    let releases_json = buckle_dir(cache.path()).join("releases.json");
+    assert_eq!(std::fs::read_to_string(&releases_json).unwrap(), releases);
+    let age = std::fs::metadata(&releases_json)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .elapsed()
+        .unwrap_or_default();
+    assert!(
+        age < Duration::from_secs(60),
+        "releases.json is {age:?} old"
+    );
+
+    // Fresh again, so the next run doesn't ask at all.
+    buckle_with_server(cache.path(), cwd.path(), &server)
+        .assert()
+        .success();
+    assert_eq!(server.requests().len(), 2);
+}
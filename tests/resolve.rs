@@ -0,0 +1,273 @@
+mod common;
+
+use common::*;
+use serde_json::Value;
+use tempfile::TempDir;
+
+const NIGHTLY_TAG: &str = "2023-08-01";
+const NIGHTLY_COMMITISH: &str = "2222222222222222222222222222222222222222";
+
+fn prerelease(tag: &str, commitish: &str) -> Value {
+    let mut release = release(tag, commitish);
+    release["prerelease"] = true.into();
+    release
+}
+
+/// A cache with a newer prerelease and an older stable release, both installed.
+#[cfg(unix)]
+fn channel_cache() -> TempDir {
+    let cache = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[
+            prerelease("latest", "3333333333333333333333333333333333333333"),
+            prerelease(NIGHTLY_TAG, NIGHTLY_COMMITISH),
+            release(TAG, COMMITISH),
+        ],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), NIGHTLY_COMMITISH, PRELUDE_HASH.as_bytes());
+    cache
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stable_alias() {
+    let cache = channel_cache();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "stable")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains(&format!("stable resolved to {TAG}")));
+    assert!(stdout(&assert).contains(COMMITISH));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_nightly_alias() {
+    for alias in ["nightly", "prerelease"] {
+        let cache = channel_cache();
+        let cwd = TempDir::new().unwrap();
+        let assert = buckle(cache.path(), cwd.path())
+            .env("USE_BUCK2_VERSION", alias)
+            .assert()
+            .success();
+        assert!(stderr(&assert).contains(&format!("{alias} resolved to {NIGHTLY_TAG}")));
+        assert!(stdout(&assert).contains(NIGHTLY_COMMITISH));
+    }
+}
+
+#[test]
+fn test_nightly_without_prereleases() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "nightly")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("There are no prereleases"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_literal_tag() {
+    let cache = channel_cache();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", NIGHTLY_TAG)
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("resolved to"));
+    assert!(stdout(&assert).contains(NIGHTLY_COMMITISH));
+}
+
+fn published(tag: &str, commitish: &str, published_at: &str) -> Value {
+    let mut release = release(tag, commitish);
+    release["published_at"] = published_at.into();
+    release
+}
+
+/// A cache pinned to [`TAG`], published on `pinned_at`, with a newer release on 2023-12-01.
+#[cfg(unix)]
+fn stale_cache(pinned_at: &str) -> TempDir {
+    let cache = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[
+            published(
+                "2023-12-01",
+                "4444444444444444444444444444444444444444",
+                "2023-12-01T09:00:00Z",
+            ),
+            published(TAG, COMMITISH, pinned_at),
+        ],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    cache
+}
+
+#[cfg(unix)]
+#[test]
+fn test_stale_pin_warns() {
+    let cache = stale_cache("2023-07-15T09:00:00Z");
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path()).assert().success();
+    let warning = format!("buck2 {TAG} is 139 days and 1 releases behind 2023-12-01");
+    assert!(stderr(&assert).contains(&warning));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_NO_STALE_WARN", "1")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("behind"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_recent_pin_does_not_warn() {
+    let cache = stale_cache("2023-11-20T09:00:00Z");
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path()).assert().success();
+    assert!(!stderr(&assert).contains("behind"));
+
+    // Unless the threshold is lower.
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_STALE_WARN_DAYS", "7")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("behind"));
+}
+
+const NEWEST_TAG: &str = "2023-10-01";
+const NEWEST_COMMITISH: &str = "5555555555555555555555555555555555555555";
+
+/// Releases listed out of date order, with a newer draft and a prerelease only dated by
+/// `created_at`. Channels resolve by date rather than by position in the list.
+#[cfg(unix)]
+fn shuffled_cache() -> TempDir {
+    let cache = TempDir::new().unwrap();
+    let mut draft = release("2024-01-01", "6666666666666666666666666666666666666666");
+    draft["draft"] = true.into();
+    draft["created_at"] = "2024-01-01T09:00:00Z".into();
+    let mut undated = prerelease(NIGHTLY_TAG, NIGHTLY_COMMITISH);
+    undated["created_at"] = "2023-10-02T09:00:00Z".into();
+    seed_releases(
+        cache.path(),
+        &[
+            published(TAG, COMMITISH, "2023-07-15T09:00:00Z"),
+            draft,
+            published(
+                "2023-09-01",
+                "7777777777777777777777777777777777777777",
+                "2023-09-01T09:00:00Z",
+            ),
+            published(
+                "2023-09-15",
+                "8888888888888888888888888888888888888888",
+                "2023-09-15T09:00:00Z",
+            ),
+            undated,
+            published(NEWEST_TAG, NEWEST_COMMITISH, "2023-10-01T09:00:00Z"),
+            published(
+                "2023-08-01",
+                "9999999999999999999999999999999999999999",
+                "2023-08-01T09:00:00Z",
+            ),
+        ],
+    );
+    seed_version(cache.path(), NEWEST_COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), NIGHTLY_COMMITISH, PRELUDE_HASH.as_bytes());
+    cache
+}
+
+#[cfg(unix)]
+#[test]
+fn test_channels_resolve_by_date() {
+    let cache = shuffled_cache();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "stable")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains(&format!("stable resolved to {NEWEST_TAG}")));
+    assert!(stdout(&assert).contains(NEWEST_COMMITISH));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "nightly")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains(&format!("nightly resolved to {NIGHTLY_TAG}")));
+    assert!(stdout(&assert).contains(NIGHTLY_COMMITISH));
+}
+
+fn draft(tag: &str, commitish: &str) -> Value {
+    let mut release = release(tag, commitish);
+    release["draft"] = true.into();
+    release
+}
+
+/// A draft `latest` is passed over for the newest published release, while pinning a draft
+/// warns before using it.
+#[cfg(unix)]
+#[test]
+fn test_drafts_are_skipped() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[
+            draft("latest", "3333333333333333333333333333333333333333"),
+            published(NIGHTLY_TAG, NIGHTLY_COMMITISH, "2023-08-01T09:00:00Z"),
+            published(TAG, COMMITISH, "2023-07-15T09:00:00Z"),
+            draft("2023-09-01", NEWEST_COMMITISH),
+        ],
+    );
+    seed_version(cache.path(), NIGHTLY_COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), NEWEST_COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "latest")
+        .assert()
+        .success();
+    let stderr_latest = stderr(&assert);
+    assert!(
+        stderr_latest.contains(&format!("latest resolved to {NIGHTLY_TAG}")),
+        "found {stderr_latest}"
+    );
+    assert!(!stderr_latest.contains("draft"), "found {stderr_latest}");
+    assert!(stdout(&assert).contains(NIGHTLY_COMMITISH));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "2023-09-01")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("buck2 2023-09-01 is a draft release"));
+    assert!(stdout(&assert).contains(NEWEST_COMMITISH));
+}
+
+/// Releases resolve by tag even without a display name, and by name when no tag matches.
+#[cfg(unix)]
+#[test]
+fn test_resolve_by_tag_or_name() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let mut unnamed = release(TAG, COMMITISH);
+    unnamed["name"] = Value::Null;
+    let mut named = release("v2023.08.01", NIGHTLY_COMMITISH);
+    named["name"] = "August".into();
+    seed_releases(cache.path(), &[unnamed, named]);
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), NIGHTLY_COMMITISH, PRELUDE_HASH.as_bytes());
+
+    let assert = buckle(cache.path(), cwd.path()).assert().success();
+    assert!(stdout(&assert).contains(COMMITISH));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("USE_BUCK2_VERSION", "August")
+        .assert()
+        .success();
+    assert!(stderr(&assert).contains("August resolved to v2023.08.01"));
+    assert!(stdout(&assert).contains(NIGHTLY_COMMITISH));
+}
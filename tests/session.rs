@@ -0,0 +1,71 @@
+mod common;
+
+use common::*;
+use serde_json::Value;
+use tempfile::TempDir;
+
+const FIRST_LATEST: &str = "5555555555555555555555555555555555555555";
+const SECOND_LATEST: &str = "6666666666666666666666666666666666666666";
+
+fn latest(commitish: &str) -> Value {
+    let mut release = release("latest", commitish);
+    release["prerelease"] = true.into();
+    release
+}
+
+/// Point the mock's `latest` at `commitish`, and make buckle fetch the list again.
+fn move_latest(cache: &TempDir, server: &MockServer, commitish: &str) {
+    mount_releases(server, &[latest(commitish), release(TAG, COMMITISH)]);
+    std::fs::remove_file(buckle_dir(cache.path()).join("releases.json")).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_latest_is_stable_within_a_session() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[latest(FIRST_LATEST), release(TAG, COMMITISH)]);
+    mount_release(&server, "latest", &stub_buck2(), PRELUDE_HASH);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    let run = |version: &str| {
+        let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+            .env("USE_BUCK2_VERSION", version)
+            .assert()
+            .success();
+        stdout(&assert)
+    };
+
+    assert!(run("latest").contains(FIRST_LATEST));
+    // A new release mid-session does not change what latest means.
+    move_latest(&cache, &server, SECOND_LATEST);
+    assert!(run("latest").contains(FIRST_LATEST));
+
+    // Switching to an explicit pin ends the session.
+    assert!(run(TAG).contains(COMMITISH));
+    assert!(run("latest").contains(SECOND_LATEST));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_session_expires_with_releases_ttl() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[latest(FIRST_LATEST)]);
+    mount_release(&server, "latest", &stub_buck2(), PRELUDE_HASH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("USE_BUCK2_VERSION", "latest")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(FIRST_LATEST));
+
+    mount_releases(&server, &[latest(SECOND_LATEST)]);
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("USE_BUCK2_VERSION", "latest")
+        .env("BUCKLE_RELEASES_TTL_SECS", "0")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(SECOND_LATEST));
+}
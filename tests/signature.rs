@@ -0,0 +1,83 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+fn signed_release_server(suffix: &str, signature: &str) -> MockServer {
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+    server.mount(
+        &format!("/download/{TAG}/buck2-{}{suffix}", host_triple()),
+        Response::ok(fs::read(fixture(signature)).unwrap()),
+    );
+    server
+}
+
+#[cfg(unix)]
+#[test]
+fn test_good_minisign_signature() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = signed_release_server(".minisig", "buck2.minisig");
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_VERIFY_KEY", fixture("minisign.pub"))
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
+
+/// A signature from an untrusted key fails the download and nothing is installed.
+#[test]
+fn test_bad_minisign_signature() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = signed_release_server(".minisig", "buck2.minisig");
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_VERIFY_KEY", fixture("minisign-other.pub"))
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("Refusing to install buck2"));
+    let buck2 = version_dir(cache.path(), COMMITISH).join("buck2");
+    assert!(!buck2.exists());
+}
+
+#[test]
+fn test_missing_signature() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = MockServer::start();
+    mount_releases(&server, &[release(TAG, COMMITISH)]);
+    mount_release(&server, TAG, &stub_buck2(), PRELUDE_HASH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_VERIFY_KEY", fixture("minisign.pub"))
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("no signature could be fetched"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_good_gpg_signature() {
+    if std::process::Command::new("gpg")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        eprintln!("gpg is not installed, skipping");
+        return;
+    }
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = signed_release_server(".sig", "buck2.sig");
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_VERIFY_KEY", fixture("gpg.pub"))
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2 stub"));
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+const NEWEST_TAG: &str = "2023-12-01";
+
+/// A project pinned to [`TAG`] and a cache that knows about a newer stable release and an
+/// even newer prerelease.
+fn setup(buckversion: &str) -> (TempDir, TempDir) {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    let mut prerelease = release("2023-12-15", "5555555555555555555555555555555555555555");
+    prerelease["prerelease"] = true.into();
+    seed_releases(
+        cache.path(),
+        &[
+            prerelease,
+            release(NEWEST_TAG, "4444444444444444444444444444444444444444"),
+            release(TAG, COMMITISH),
+        ],
+    );
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(project.path().join(".buckversion"), buckversion).unwrap();
+    (cache, project)
+}
+
+fn buckversion(project: &TempDir) -> String {
+    fs::read_to_string(project.path().join(".buckversion")).unwrap()
+}
+
+#[test]
+fn test_upgrade_to_newest_stable() {
+    let (cache, project) = setup(&format!("# pinned\n{TAG}\n"));
+    let assert = buckle(cache.path(), project.path())
+        .env_remove("USE_BUCK2_VERSION")
+        .arg("upgrade")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(&format!("{TAG} -> {NEWEST_TAG}")));
+    assert_eq!(buckversion(&project), format!("# pinned\n{NEWEST_TAG}\n"));
+}
+
+#[test]
+fn test_upgrade_dry_run() {
+    let (cache, project) = setup(&format!("{TAG}\n"));
+    let assert = buckle(cache.path(), project.path())
+        .args(["upgrade", "--dry-run"])
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(&format!("{TAG} -> {NEWEST_TAG}")));
+    assert_eq!(buckversion(&project), format!("{TAG}\n"));
+}
+
+#[test]
+fn test_upgrade_to_specific_version() {
+    let (cache, project) = setup(&format!("{NEWEST_TAG}\n"));
+    buckle(cache.path(), project.path())
+        .args(["upgrade", "--to", TAG])
+        .assert()
+        .success();
+    assert_eq!(buckversion(&project), format!("{TAG}\n"));
+
+    // An unknown tag is looked for in older releases, which can't be had here.
+    let assert = buckle(cache.path(), project.path())
+        .env("BUCKLE_RELEASES_URL", "http://127.0.0.1:9/releases")
+        .args(["upgrade", "--to", "1999-01-01"])
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("1999-01-01 is not a buck2 release"));
+    assert_eq!(buckversion(&project), format!("{TAG}\n"));
+}
+
+/// A tag older than the newest page of releases is found by paging back for it.
+#[cfg(unix)]
+#[test]
+fn test_upgrade_to_old_version_pages_back() {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(
+        project.path().join(".buckversion"),
+        format!("{NEWEST_TAG}\n"),
+    )
+    .unwrap();
+    let server = mock_github();
+    mount_release_page(
+        &server,
+        "/releases",
+        &[release(
+            NEWEST_TAG,
+            "4444444444444444444444444444444444444444",
+        )],
+        "/releases?page=2",
+    );
+    mount_release_page(
+        &server,
+        "/releases?page=2",
+        &[release(TAG, COMMITISH)],
+        "/releases?page=3",
+    );
+
+    let assert = buckle_with_server(cache.path(), project.path(), &server)
+        .args(["upgrade", "--to", TAG])
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains(&format!("{NEWEST_TAG} -> {TAG}")));
+    assert_eq!(buckversion(&project), format!("{TAG}\n"));
+    assert_eq!(server.hits("/releases?page=2"), 1);
+    assert_eq!(server.hits("/releases?page=3"), 0);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+/// `buckle url` prints the download URLs for the project's version from the cached releases
+/// list, fetching nothing.
+#[test]
+fn test_url_from_cached_releases() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_OFFLINE", "1")
+        .env("BUCKLE_BASE_URL", "https://mirror.example.com/buck2/")
+        .arg("url")
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        format!(
+            "https://mirror.example.com/buck2/{TAG}/buck2-{}.zst\n\
+            https://mirror.example.com/buck2/{TAG}/prelude_hash\n",
+            host_triple()
+        )
+    );
+    assert!(!version_dir(cache.path(), COMMITISH).exists());
+}
+
+/// A version given on the command line is resolved, and the default base URL follows
+/// `BUCKLE_REPO`.
+#[test]
+fn test_url_for_version() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    seed_releases(cache.path(), &[release(TAG, COMMITISH)]);
+
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_OFFLINE", "1")
+        .env("BUCKLE_REPO", "example/buck2-fork")
+        .args(["url", "latest"])
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert_eq!(
+        stdout.lines().next(),
+        Some(
+            format!(
+                "https://github.com/example/buck2-fork/releases/download/{TAG}/buck2-{}.zst",
+                host_triple()
+            )
+            .as_str()
+        ),
+        "found {stdout}"
+    );
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use common::*;
+use std::fs;
+use tempfile::TempDir;
+
+const OTHER_TAG: &str = "2023-08-01";
+const OTHER_COMMITISH: &str = "1111111111111111111111111111111111111111";
+
+/// A project pinned to [`TAG`] with it and [`OTHER_TAG`] both cached. The releases list is
+/// stale and can't be refetched, so anything that needs the network fails.
+#[cfg(unix)]
+fn cached_project() -> (TempDir, TempDir) {
+    let cache = TempDir::new().unwrap();
+    let project = TempDir::new().unwrap();
+    seed_releases(
+        cache.path(),
+        &[release(OTHER_TAG, OTHER_COMMITISH), release(TAG, COMMITISH)],
+    );
+    seed_version(cache.path(), COMMITISH, PRELUDE_HASH.as_bytes());
+    seed_version(cache.path(), OTHER_COMMITISH, PRELUDE_HASH.as_bytes());
+    fs::write(project.path().join(".buckconfig"), "").unwrap();
+    fs::write(project.path().join(".buckversion"), format!("{TAG}\n")).unwrap();
+    (cache, project)
+}
+
+#[cfg(unix)]
+fn offline_buckle(cache: &TempDir, project: &TempDir) -> assert_cmd::Command {
+    let mut cmd = buckle(cache.path(), project.path());
+    cmd.env_remove("USE_BUCK2_VERSION")
+        .env("BUCKLE_RELEASES_TTL_SECS", "0")
+        .env("BUCKLE_RELEASES_URL", "http://127.0.0.1:9/releases");
+    cmd
+}
+
+/// `buckle use` runs the cached version it is given rather than the pinned one.
+#[cfg(unix)]
+#[test]
+fn test_use_runs_cached_version() {
+    let (cache, project) = cached_project();
+
+    let assert = offline_buckle(&cache, &project)
+        .args(["use", OTHER_TAG, "--version"])
+        .assert()
+        .success();
+    let stdout = stdout(&assert);
+    assert!(stdout.contains(OTHER_COMMITISH), "found {stdout}");
+    assert!(stdout.contains("arg: --version\n"), "found {stdout}");
+    assert!(!stdout.contains("arg: use"), "found {stdout}");
+    assert_eq!(
+        fs::read_to_string(project.path().join(".buckversion")).unwrap(),
+        format!("{TAG}\n")
+    );
+}
+
+/// A version that isn't cached is not downloaded, and the error says how to get it.
+#[cfg(unix)]
+#[test]
+fn test_use_uncached_version() {
+    let (cache, project) = cached_project();
+    fs::remove_dir_all(version_dir(cache.path(), OTHER_COMMITISH)).unwrap();
+
+    let assert = offline_buckle(&cache, &project)
+        .args(["use", OTHER_TAG, "--version"])
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!("buck2 {OTHER_TAG} is not in the cache")),
+        "found {stderr}"
+    );
+    assert!(
+        stderr.contains(&format!("USE_BUCK2_VERSION={OTHER_TAG} buckle --version")),
+        "found {stderr}"
+    );
+}
+
+#[test]
+fn test_use_without_version() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("use")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("buckle use requires a version"));
+}
+
+/// A cached version is still checked against the manifest before it runs.
+#[cfg(unix)]
+#[test]
+fn test_use_checks_manifest() {
+    let (cache, project) = cached_project();
+    let manifest = project.path().join("manifest.json");
+    fs::write(
+        &manifest,
+        serde_json::json!({ TAG: { "sha256": "0".repeat(64), "prelude_hash": PRELUDE_HASH } })
+            .to_string(),
+    )
+    .unwrap();
+
+    let assert = offline_buckle(&cache, &project)
+        .env("BUCKLE_MANIFEST_URL", &manifest)
+        .args(["use", OTHER_TAG, "--version"])
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "Refusing to run buck2 {OTHER_TAG}: it is not in the manifest"
+        )),
+        "found {stderr}"
+    );
+    assert!(!stdout(&assert).contains("buck2 stub"));
+}
+
+/// A cached version the allowlist refuses does not run.
+#[cfg(unix)]
+#[test]
+fn test_use_checks_allowlist() {
+    let (cache, project) = cached_project();
+
+    let assert = offline_buckle(&cache, &project)
+        .env("BUCKLE_ALLOWED_VERSIONS", TAG)
+        .args(["use", OTHER_TAG, "--version"])
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains(&format!(
+            "buck2 {OTHER_TAG} is not allowed by BUCKLE_ALLOWED_VERSIONS"
+        )),
+        "found {stderr}"
+    );
+    assert!(!stdout(&assert).contains("buck2 stub"));
+}
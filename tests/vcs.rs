@@ -0,0 +1,51 @@
+//! The prelude check's decision, driven by a fake version control system rather than real
+//! submodules.
+
+use buckle::vcs::{check_submodule, SubmoduleCheck, VcsPrelude};
+use std::collections::HashMap;
+
+const EXPECTED: &str = "0123456789abcdef0123456789abcdef01234567";
+
+/// Submodules checked out at fixed commits.
+struct FakeVcs(HashMap<&'static str, &'static str>);
+
+impl VcsPrelude for FakeVcs {
+    fn submodule_hash(&self, rel_path: &str) -> Option<String> {
+        self.0.get(rel_path).map(|hash| hash.to_string())
+    }
+}
+
+fn fake() -> FakeVcs {
+    FakeVcs(HashMap::from([
+        ("prelude", EXPECTED),
+        (
+            "third-party/prelude",
+            "fedcba9876543210fedcba9876543210fedcba98",
+        ),
+    ]))
+}
+
+#[test]
+fn test_matching_submodule() {
+    assert_eq!(
+        check_submodule(&fake(), "prelude", EXPECTED),
+        SubmoduleCheck::Matches
+    );
+}
+
+#[test]
+fn test_mismatched_submodule() {
+    assert_eq!(
+        check_submodule(&fake(), "third-party/prelude", EXPECTED),
+        SubmoduleCheck::Mismatch("fedcba9876543210fedcba9876543210fedcba98".to_string())
+    );
+}
+
+/// A cell that isn't a submodule, or isn't checked out, has nothing to compare.
+#[test]
+fn test_unknown_submodule_is_unchecked() {
+    assert_eq!(
+        check_submodule(&fake(), "vendor/prelude", EXPECTED),
+        SubmoduleCheck::Unchecked
+    );
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+fn described_cache() -> TempDir {
+    let cache = TempDir::new().unwrap();
+    let mut described = release(TAG, COMMITISH);
+    described["prerelease"] = true.into();
+    described["published_at"] = "2023-07-15T09:00:00Z".into();
+    described["body"] = "## What's new\n\n* Faster builds\n".into();
+    described["assets"] = serde_json::json!([
+        { "name": "buck2-x86_64-unknown-linux-musl.zst" },
+        { "name": "prelude_hash" },
+    ]);
+    seed_releases(cache.path(), &[described]);
+    cache
+}
+
+/// `buckle version-info` describes a release from the cached list without running buck2.
+#[test]
+fn test_version_info() {
+    let cache = described_cache();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_OFFLINE", "1")
+        .args(["version-info", TAG])
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        format!(
+            "tag: {TAG}\n\
+            published: 2023-07-15T09:00:00Z\n\
+            prerelease: yes\n\
+            assets: buck2-x86_64-unknown-linux-musl.zst, prelude_hash\n\
+            \n\
+            ## What's new\n\
+            \n\
+            * Faster builds\n"
+        )
+    );
+}
+
+/// `--json` prints the release itself, and aliases resolve as usual.
+#[test]
+fn test_version_info_json() {
+    let cache = described_cache();
+    let cwd = TempDir::new().unwrap();
+    let assert = buckle(cache.path(), cwd.path())
+        .env("BUCKLE_OFFLINE", "1")
+        .args(["version-info", "--json", "latest"])
+        .assert()
+        .success();
+    let release: serde_json::Value = serde_json::from_str(&stdout(&assert)).unwrap();
+    assert_eq!(release["tag_name"], TAG);
+    assert_eq!(release["target_commitish"], COMMITISH);
+    assert_eq!(release["assets"][1]["name"], "prelude_hash");
+    assert!(stderr(&assert).contains(&format!("latest resolved to {TAG}")));
+
+    let assert = buckle(cache.path(), cwd.path())
+        .arg("version-info")
+        .assert()
+        .failure();
+    assert!(stderr(&assert).contains("Usage: buckle version-info [--json] <version>"));
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::*;
+use tempfile::TempDir;
+
+const OTHER_TRIPLE: &str = "aarch64-apple-darwin";
+
+/// `buckle warm` fetches every platform asked for without running buck2, and reports what
+/// was already cached on the next run.
+#[test]
+fn test_warm_two_triples() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    mount_release_for(&server, TAG, OTHER_TRIPLE, b"other buck2", PRELUDE_HASH);
+    let commitish_dir = buckle_dir(cache.path()).join(COMMITISH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args(["warm", "--arch", host_triple(), "--arch", OTHER_TRIPLE])
+        .assert()
+        .success();
+    let host_dir = commitish_dir.join(host_triple());
+    let other_dir = commitish_dir.join(OTHER_TRIPLE);
+    assert_eq!(
+        stdout(&assert),
+        format!(
+            "{}: fetched buck2 {TAG} into {}\n{OTHER_TRIPLE}: fetched buck2 {TAG} into {}\n",
+            host_triple(),
+            host_dir.display(),
+            other_dir.display()
+        )
+    );
+    assert_eq!(std::fs::read(host_dir.join("buck2")).unwrap(), stub_buck2());
+    assert_eq!(
+        std::fs::read(other_dir.join("buck2")).unwrap(),
+        b"other buck2"
+    );
+    assert!(other_dir.join("prelude_hash").exists());
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args(["warm", &format!("--arch={OTHER_TRIPLE}")])
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&assert),
+        format!(
+            "{OTHER_TRIPLE}: buck2 {TAG} is already cached in {}\n",
+            other_dir.display()
+        )
+    );
+    assert_eq!(
+        server.hits(&format!("/download/{TAG}/buck2-{OTHER_TRIPLE}.zst")),
+        1
+    );
+}
+
+/// A platform without a binary is reported, and the others are still fetched.
+#[test]
+fn test_warm_missing_triple() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args([
+            "warm",
+            "--arch",
+            "riscv64-unknown-linux-gnu",
+            "--arch",
+            host_triple(),
+        ])
+        .assert()
+        .failure();
+    let stderr_warm = stderr(&assert);
+    assert!(
+        stderr_warm.contains(&format!(
+            "could not warm buck2 {TAG} for riscv64-unknown-linux-gnu"
+        )),
+        "found {stderr_warm}"
+    );
+    assert!(stderr_warm.contains("Could not warm the cache for 1 of 2 platforms"));
+    assert!(stdout(&assert).contains(&format!("{}: fetched buck2 {TAG}", host_triple())));
+    assert!(version_dir(cache.path(), COMMITISH).join("buck2").exists());
+}
+
+/// A triple that could escape the cache is refused before anything is written for it.
+#[test]
+fn test_warm_rejects_unsafe_triple() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .args(["warm", "--arch", "../../escaped"])
+        .assert()
+        .failure();
+    let stderr = stderr(&assert);
+    assert!(
+        stderr.contains("\"../../escaped\" is not a valid target triple"),
+        "found {stderr}"
+    );
+    assert!(!cache.path().join("escaped").exists());
+    assert!(!buckle_dir(cache.path()).join("escaped").exists());
+}
+
+/// Warming several platforms into a capped cache keeps all of them, rather than each install
+/// evicting the one before.
+#[test]
+fn test_warm_keeps_every_triple_under_size_cap() {
+    let cache = TempDir::new().unwrap();
+    let cwd = TempDir::new().unwrap();
+    let server = mock_github();
+    mount_release_for(&server, TAG, OTHER_TRIPLE, b"other buck2", PRELUDE_HASH);
+
+    let assert = buckle_with_server(cache.path(), cwd.path(), &server)
+        .env("BUCKLE_CACHE_MAX_BYTES", "1")
+        .args(["warm", "--arch", host_triple(), "--arch", OTHER_TRIPLE])
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("evicting"));
+    let commitish_dir = buckle_dir(cache.path()).join(COMMITISH);
+    assert!(commitish_dir.join(host_triple()).join("buck2").exists());
+    assert!(commitish_dir.join(OTHER_TRIPLE).join("buck2").exists());
+}